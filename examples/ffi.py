@@ -0,0 +1,54 @@
+# Drives the emulator through the C API with ctypes, printing the screen after a few frames:
+#
+#   cargo build && python3 examples/ffi.py target/debug/libchip_8.so game.ch8 120
+
+import ctypes
+import sys
+
+WIDTH, HEIGHT = 64, 32
+
+if len(sys.argv) < 3:
+    sys.exit("Usage: ffi.py path/to/libchip_8.so game.ch8 [frames]")
+
+lib = ctypes.CDLL(sys.argv[1])
+lib.chip8_new.restype = ctypes.c_void_p
+lib.chip8_load_rom.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_size_t]
+lib.chip8_load_rom.restype = ctypes.c_bool
+lib.chip8_run_frame.argtypes = [ctypes.c_void_p]
+lib.chip8_run_frame.restype = ctypes.c_bool
+lib.chip8_get_framebuffer.argtypes = [ctypes.c_void_p]
+lib.chip8_get_framebuffer.restype = ctypes.POINTER(ctypes.c_uint8)
+lib.chip8_set_key.argtypes = [ctypes.c_void_p, ctypes.c_uint8, ctypes.c_bool]
+lib.chip8_sound_active.argtypes = [ctypes.c_void_p]
+lib.chip8_sound_active.restype = ctypes.c_bool
+lib.chip8_save_state.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_size_t]
+lib.chip8_save_state.restype = ctypes.c_size_t
+lib.chip8_load_state.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_size_t]
+lib.chip8_load_state.restype = ctypes.c_bool
+lib.chip8_free.argtypes = [ctypes.c_void_p]
+
+with open(sys.argv[2], "rb") as file:
+    rom = file.read()
+frames = int(sys.argv[3]) if len(sys.argv) > 3 else 60
+
+chip8 = lib.chip8_new()
+if not lib.chip8_load_rom(chip8, rom, len(rom)):
+    sys.exit("Failed to load the ROM")
+
+beeped = False
+for frame in range(frames):
+    if not lib.chip8_run_frame(chip8):
+        print(f"Stopped by an error at frame {frame}")
+        break
+    beeped = beeped or lib.chip8_sound_active(chip8)
+
+state = ctypes.create_string_buffer(lib.chip8_save_state(chip8, None, 0))
+lib.chip8_save_state(chip8, state, len(state))
+assert lib.chip8_load_state(chip8, state, len(state))
+
+pixels = lib.chip8_get_framebuffer(chip8)
+for y in range(HEIGHT):
+    print("".join("#" if pixels[y * WIDTH + x] else " " for x in range(WIDTH)).rstrip())
+print(f"Beeper {'heard' if beeped else 'silent'}, save state of {len(state)} bytes")
+
+lib.chip8_free(chip8)
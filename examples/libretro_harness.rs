@@ -0,0 +1,157 @@
+// Loads the libretro core the way a frontend would and runs a ROM for a few frames, checking
+// video, audio and save states along the way:
+//
+//   cargo build && cargo run --example libretro_harness -- target/debug/libchip_8.so game.ch8 120
+
+use chip_8::libretro::{RetroGameInfo, RetroSystemAvInfo, RetroSystemInfo};
+
+use libloading::{Library, Symbol};
+
+use std::ffi::{c_uint, c_void, CStr};
+use std::process;
+use std::ptr;
+use std::sync::Mutex;
+
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+
+static FRAME: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+static SAMPLES: Mutex<usize> = Mutex::new(0);
+static BEEPING: Mutex<bool> = Mutex::new(false);
+
+unsafe extern "C" fn environment(cmd: c_uint, _data: *mut c_void) -> bool {
+    // Only the pixel format is required, options keep their defaults
+    cmd == RETRO_ENVIRONMENT_SET_PIXEL_FORMAT
+}
+
+unsafe extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    let mut frame = FRAME.lock().unwrap();
+    frame.clear();
+    for y in 0..height as usize {
+        let row = (data as *const u8).add(y * pitch) as *const u32;
+        frame.extend_from_slice(std::slice::from_raw_parts(row, width as usize));
+    }
+}
+
+unsafe extern "C" fn audio_sample(_left: i16, _right: i16) {}
+
+unsafe extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+    *SAMPLES.lock().unwrap() += frames;
+    if std::slice::from_raw_parts(data, frames * 2).iter().any(|sample| *sample != 0) {
+        *BEEPING.lock().unwrap() = true;
+    }
+    frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+unsafe extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, _id: c_uint) -> i16 {
+    0
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        fail("Usage: libretro_harness path/to/libchip_8.so game.ch8 [frames]");
+    }
+    let frames: usize = args.get(3).map(|frames| frames.parse().unwrap_or_else(|_| fail("frames must be a number"))).unwrap_or(60);
+    let rom = std::fs::read(&args[2]).unwrap_or_else(|error| fail(&format!("Failed to read {}: {}", args[2], error)));
+
+    unsafe {
+
+        let core = Library::new(&args[1]).unwrap_or_else(|error| fail(&format!("Failed to load {}: {}", args[1], error)));
+
+        macro_rules! symbol {
+            ($name:ident: $type:ty) => {
+                let $name: Symbol<$type> = core.get(concat!(stringify!($name), "\0").as_bytes()).unwrap_or_else(|error| fail(&format!("Missing {}: {}", stringify!($name), error)));
+            };
+        }
+
+        symbol!(retro_api_version: unsafe extern "C" fn() -> c_uint);
+        symbol!(retro_set_environment: unsafe extern "C" fn(unsafe extern "C" fn(c_uint, *mut c_void) -> bool));
+        symbol!(retro_set_video_refresh: unsafe extern "C" fn(unsafe extern "C" fn(*const c_void, c_uint, c_uint, usize)));
+        symbol!(retro_set_audio_sample: unsafe extern "C" fn(unsafe extern "C" fn(i16, i16)));
+        symbol!(retro_set_audio_sample_batch: unsafe extern "C" fn(unsafe extern "C" fn(*const i16, usize) -> usize));
+        symbol!(retro_set_input_poll: unsafe extern "C" fn(unsafe extern "C" fn()));
+        symbol!(retro_set_input_state: unsafe extern "C" fn(unsafe extern "C" fn(c_uint, c_uint, c_uint, c_uint) -> i16));
+        symbol!(retro_init: unsafe extern "C" fn());
+        symbol!(retro_deinit: unsafe extern "C" fn());
+        symbol!(retro_get_system_info: unsafe extern "C" fn(*mut RetroSystemInfo));
+        symbol!(retro_get_system_av_info: unsafe extern "C" fn(*mut RetroSystemAvInfo));
+        symbol!(retro_load_game: unsafe extern "C" fn(*const RetroGameInfo) -> bool);
+        symbol!(retro_unload_game: unsafe extern "C" fn());
+        symbol!(retro_run: unsafe extern "C" fn());
+        symbol!(retro_serialize_size: unsafe extern "C" fn() -> usize);
+        symbol!(retro_serialize: unsafe extern "C" fn(*mut c_void, usize) -> bool);
+        symbol!(retro_unserialize: unsafe extern "C" fn(*const c_void, usize) -> bool);
+
+        let mut info: RetroSystemInfo = std::mem::zeroed();
+        retro_get_system_info(&mut info);
+        println!("API {}, {} {}", retro_api_version(), CStr::from_ptr(info.library_name).to_string_lossy(), CStr::from_ptr(info.library_version).to_string_lossy());
+
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample(audio_sample);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+
+        let game = RetroGameInfo { path: ptr::null(), data: rom.as_ptr() as *const c_void, size: rom.len(), meta: ptr::null() };
+        if !retro_load_game(&game) {
+            fail("retro_load_game failed");
+        }
+
+        let mut av_info: RetroSystemAvInfo = std::mem::zeroed();
+        retro_get_system_av_info(&mut av_info);
+        let (width, height) = (av_info.geometry.base_width as usize, av_info.geometry.base_height as usize);
+
+        for _ in 0..frames {
+            retro_run();
+        }
+
+        let expected_samples = frames * av_info.timing.sample_rate as usize / av_info.timing.fps as usize;
+        let samples = *SAMPLES.lock().unwrap();
+        if samples != expected_samples {
+            fail(&format!("Got {} audio frames, expected {}", samples, expected_samples));
+        }
+
+        // Running from a save state twice has to give the same picture, unless the ROM uses random numbers
+        let mut state = vec![0u8; retro_serialize_size()];
+        if !retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) {
+            fail("retro_serialize failed");
+        }
+        let mut after_state = Vec::new();
+        for _ in 0..2 {
+            if !retro_unserialize(state.as_ptr() as *const c_void, state.len()) {
+                fail("retro_unserialize failed");
+            }
+            for _ in 0..10 {
+                retro_run();
+            }
+            after_state.push(FRAME.lock().unwrap().clone());
+        }
+
+        let frame = FRAME.lock().unwrap().clone();
+        if frame.len() != width * height {
+            fail(&format!("Got a {} pixel frame, expected {}x{}", frame.len(), width, height));
+        }
+        // The default palette has black for unlit pixels
+        for row in frame.chunks(width) {
+            println!("{}", row.iter().map(|pixel| if *pixel == 0 { ' ' } else { '#' }).collect::<String>());
+        }
+
+        println!("{} frames, {} audio frames, beeper {}", frames + 20, samples, if *BEEPING.lock().unwrap() { "heard" } else { "silent" });
+        println!("Save state of {} bytes {}", state.len(), if after_state[0] == after_state[1] { "replays identically" } else { "replays differently (random numbers?)" });
+
+        retro_unload_game();
+        retro_deinit();
+
+    }
+
+}
@@ -0,0 +1,58 @@
+// Plays a ROM with random key presses in many environments at once, printing the speed and the
+// average score:
+//
+//   cargo run --release --example random_agent -- game.ch8 "bcd(0x2F0)" "[0x2F5] == 0" 64 10000
+
+use chip_8::gym::{EnvOptions, VecEnv};
+
+use rand::Rng;
+
+use std::time::Instant;
+
+fn main() {
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: random_agent game.ch8 reward [done] [environments] [steps]");
+        std::process::exit(1);
+    }
+
+    let rom = std::fs::read(&args[1]).unwrap_or_else(|error| panic!("Failed to read {}: {}", args[1], error));
+
+    let mut options = EnvOptions::new();
+    options.reward = args[2].clone();
+    options.done = args.get(3).filter(|done| !done.is_empty()).cloned();
+    options.max_frames = Some(60 * 60);
+    options.seed = Some(0);
+    let count: usize = args.get(4).map_or(64, |count| count.parse().expect("environments must be a number"));
+    let steps: usize = args.get(5).map_or(1000, |steps| steps.parse().expect("steps must be a number"));
+
+    let mut envs = VecEnv::new(&rom, &options, count).unwrap_or_else(|error| panic!("{}", error));
+    envs.reset();
+
+    let mut rng = rand::thread_rng();
+    let mut returns = vec![0.0; count];
+    let mut finished: Vec<f64> = Vec::new();
+    let start = Instant::now();
+
+    for _ in 0..steps {
+        let actions: Vec<u16> = (0..count).map(|_| 1 << rng.gen_range(0..16)).collect();
+        for (index, (_, reward, done)) in envs.step(&actions).unwrap_or_else(|error| panic!("{}", error)).into_iter().enumerate() {
+            returns[index] += reward;
+            if done {
+                finished.push(returns[index]);
+                returns[index] = 0.0;
+            }
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let frames = (steps * count * options.frame_skip) as f64;
+    println!("{:.0} steps/s, {:.0} frames/s over {} environments", (steps * count) as f64 / elapsed, frames / elapsed, count);
+    if finished.is_empty() {
+        println!("No episode finished");
+    } else {
+        println!("{} episodes, average return {:.2}", finished.len(), finished.iter().sum::<f64>() / finished.len() as f64);
+    }
+
+}
@@ -0,0 +1,107 @@
+/* Generated from src/ffi.rs, don't edit. CHIP_8_UPDATE_HEADER=1 cargo test header regenerates it */
+
+#ifndef CHIP_8_H
+#define CHIP_8_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Framebuffer width in pixels.
+ */
+#define CHIP8_DISPLAY_WIDTH 64
+
+/**
+ * Framebuffer height in pixels.
+ */
+#define CHIP8_DISPLAY_HEIGHT 32
+
+/**
+ * A machine, created by `chip8_new()` and destroyed by `chip8_free()`.
+ */
+typedef struct Chip8 Chip8;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * A machine in its power-on state with no ROM loaded.
+ */
+struct Chip8 *chip8_new(void);
+
+/**
+ * Resets the machine and loads `len` bytes of program at 0x200. False if the ROM is empty or
+ * doesn't fit in memory.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()` and `data` point to `len` readable bytes.
+ */
+bool chip8_load_rom(struct Chip8 *chip8, const uint8_t *data, size_t len);
+
+/**
+ * Runs one 60Hz frame. False if the program hit an error (unknown opcode, stack overflow, ...),
+ * which is printed to stderr and stops the machine.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()`.
+ */
+bool chip8_run_frame(struct Chip8 *chip8);
+
+/**
+ * `CHIP8_DISPLAY_WIDTH * CHIP8_DISPLAY_HEIGHT` bytes, row by row, 0 for unlit pixels and 255
+ * for lit ones. Valid until the next call on the machine.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()`.
+ */
+const uint8_t *chip8_get_framebuffer(const struct Chip8 *chip8);
+
+/**
+ * Presses or releases key 0x0 to 0xF, other keys are ignored.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()`.
+ */
+void chip8_set_key(struct Chip8 *chip8, uint8_t key, bool pressed);
+
+/**
+ * True while the beeper should be on.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()`.
+ */
+bool chip8_sound_active(const struct Chip8 *chip8);
+
+/**
+ * Writes a save state to `out` if `len` is large enough. Returns the size of a save state, so
+ * `chip8_save_state(chip8, NULL, 0)` tells how much to allocate.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()` and `out` point to `len` writable bytes (or be null).
+ */
+size_t chip8_save_state(const struct Chip8 *chip8, uint8_t *out, size_t len);
+
+/**
+ * Restores a state written by `chip8_save_state()`. False, leaving the machine untouched, if it
+ * isn't one.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()` and `data` point to `len` readable bytes.
+ */
+bool chip8_load_state(struct Chip8 *chip8, const uint8_t *data, size_t len);
+
+/**
+ * Destroys a machine.
+ *
+ * # Safety
+ * `chip8` must come from `chip8_new()` and not be used afterwards.
+ */
+void chip8_free(struct Chip8 *chip8);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CHIP_8_H */
@@ -0,0 +1,284 @@
+use chip_8::emulator;
+
+#[derive(PartialEq)]
+pub enum Watch {
+    // Reset the machine and load the new ROM
+    Reset,
+    // Write changed bytes into memory, keeping registers and the rest of memory as they are
+    Patch,
+}
+
+#[derive(PartialEq)]
+pub enum Frontend {
+    // Window with OpenGL rendering, sound and the debugging / ROM browsing tools
+    Sdl,
+    // Frames written to image files, no window, input or sound
+    Image(String),
+    // Drawn in the terminal with half blocks or braille, keys read from stdin
+    Terminal,
+    Braille,
+    // Nothing drawn and no input, e.g. to render the beeper with --wav on a machine without a display
+    Headless,
+}
+
+pub struct Args {
+    // None with --dap, the client names the rom in its launch request
+    pub rom: Option<String>,
+    pub options: emulator::Options,
+    pub trace: Option<emulator::TraceOptions>,
+    pub frame_limit: Option<u64>,
+    pub memory_viewer: bool,
+    pub cheat_path: Option<String>,
+    pub gdb_port: Option<u16>,
+    pub dap: bool,
+    // Compile the .8o given as rom to this path and exit
+    pub compile_output: Option<String>,
+    pub symbol_path: Option<String>,
+    pub watch: Option<Watch>,
+    // Listed by the ROM browser, defaults to the ROM's directory
+    pub rom_dir: Option<String>,
+    pub frontend: Frontend,
+    // Emulate in the main loop instead of on a thread of its own
+    pub single_thread: bool,
+    // Run every combination of ROM (rom may be a directory), seed and quirk set without a window
+    pub batch: bool,
+    pub seeds: Vec<u64>,
+    pub quirk_sets: Vec<String>,
+    pub threads: Option<usize>,
+    pub report: Option<String>,
+}
+
+fn value<'a>(args: &'a [String], index: &mut usize, flag: &str, expected: &str) -> &'a str {
+    *index += 1;
+    match args.get(*index) {
+        Some(value) => value.as_str(),
+        None => panic!("{} expects {}", flag, expected),
+    }
+}
+
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (start, end) = text.split_once('-')?;
+    let start = u16::from_str_radix(start, 16).ok()?;
+    let end = u16::from_str_radix(end, 16).ok()?;
+    Some((start, end))
+}
+
+// "7", "1,2,3" or "0-15"
+fn parse_seeds(text: &str) -> Option<Vec<u64>> {
+    if let Some((start, end)) = text.split_once('-') {
+        let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+        return Some((start..=end).collect()).filter(|seeds: &Vec<u64>| !seeds.is_empty());
+    }
+    text.split(',').map(|seed| seed.parse().ok()).collect()
+}
+
+// "000000,FF0000", off then on
+fn parse_palette(text: &str) -> Option<[[u8; 3]; 2]> {
+    let (off, on) = text.split_once(',')?;
+    let colour = |text: &str| -> Option<[u8; 3]> {
+        let text = text.trim_start_matches('#');
+        if text.len() != 6 {
+            return None;
+        }
+        let value = u32::from_str_radix(text, 16).ok()?;
+        Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+    };
+    Some([colour(off)?, colour(on)?])
+}
+
+pub fn parse(args: &[String]) -> Args {
+
+    let mut options = emulator::Options::new();
+    let mut rom: Option<String> = None;
+    let mut frame_limit: Option<u64> = None;
+    let mut memory_viewer = false;
+    let mut cheat_path: Option<String> = None;
+    let mut gdb_port: Option<u16> = None;
+    let mut dap = false;
+    let mut compile_output: Option<String> = None;
+    let mut symbol_path: Option<String> = None;
+    let mut watch: Option<Watch> = None;
+    let mut rom_dir: Option<String> = None;
+    let mut frontend = Frontend::Sdl;
+    let mut single_thread = false;
+    let mut batch = false;
+    let mut seeds: Vec<u64> = Vec::new();
+    let mut quirk_sets: Vec<String> = Vec::new();
+    let mut threads: Option<usize> = None;
+    let mut report: Option<String> = None;
+
+    let mut trace_path: Option<String> = None;
+    let mut trace_range: Option<(u16, u16)> = None;
+    let mut trace_classes: Vec<emulator::Class> = Vec::new();
+    let mut trace_ring: Option<usize> = None;
+
+    let mut profile_annotated: Option<String> = None;
+    let mut profile_folded: Option<String> = None;
+
+    let mut index = 1;
+    while index < args.len() {
+        let flag = args[index].as_str();
+        match flag {
+            "--audio" => {
+                options.audio = match value(args, &mut index, flag, "one of: sdl, null") {
+                    "sdl" => emulator::AudioBackend::Sdl,
+                    "null" => emulator::AudioBackend::Null,
+                    _ => panic!("--audio expects one of: sdl, null"),
+                };
+            }
+            "--wav" => {
+                let path = value(args, &mut index, flag, "an output path");
+                options.audio = emulator::AudioBackend::Wav(path.to_string());
+            }
+            "--frames" => {
+                match value(args, &mut index, flag, "a number of frames").parse() {
+                    Ok(frames) => frame_limit = Some(frames),
+                    Err(_) => panic!("--frames expects a number of frames"),
+                }
+            }
+            "--frontend" => {
+                frontend = match value(args, &mut index, flag, "one of: sdl, terminal, braille, none") {
+                    "sdl" => Frontend::Sdl,
+                    "none" => Frontend::Headless,
+                    "terminal" if cfg!(unix) => Frontend::Terminal,
+                    "braille" if cfg!(unix) => Frontend::Braille,
+                    "terminal" | "braille" => panic!("The terminal frontend is only available on Unix"),
+                    _ => panic!("--frontend expects one of: sdl, terminal, braille, none"),
+                };
+            }
+            "--image" => frontend = Frontend::Image(value(args, &mut index, flag, "an output path").to_string()),
+            "--memory-viewer" => memory_viewer = true,
+            "--background" => options.background = true,
+            "--single-thread" => single_thread = true,
+            "--batch" => batch = true,
+            "--seed" => {
+                match parse_seeds(value(args, &mut index, flag, "a seed, list or range")) {
+                    Some(parsed) => seeds = parsed,
+                    None => panic!("--seed expects a number, a list like 1,2,3 or a range like 0-15"),
+                }
+            }
+            "--quirks" => {
+                for name in value(args, &mut index, flag, "a list of quirk sets").split(',') {
+                    match emulator::Quirks::preset(name) {
+                        Some(_) => quirk_sets.push(name.to_string()),
+                        None => panic!("Unknown quirk set '{}', expected vip, schip or xochip", name),
+                    }
+                }
+            }
+            "--threads" => {
+                match value(args, &mut index, flag, "a number of threads").parse() {
+                    Ok(count) if count > 0 => threads = Some(count),
+                    _ => panic!("--threads expects a number of threads"),
+                }
+            }
+            "--report" => report = Some(value(args, &mut index, flag, "an output path").to_string()),
+            "--software" => options.software_renderer = true,
+            "--shader" => options.shader = Some(value(args, &mut index, flag, "a fragment shader path").to_string()),
+            "--palette" => {
+                match parse_palette(value(args, &mut index, flag, "two hex colours")) {
+                    Some(palette) => options.palette = palette,
+                    None => panic!("--palette expects two hex colours like 000000,FF0000"),
+                }
+            }
+            "--cheats" => cheat_path = Some(value(args, &mut index, flag, "a cheat file path").to_string()),
+            "--trace" => trace_path = Some(value(args, &mut index, flag, "an output path").to_string()),
+            "--trace-range" => {
+                match parse_range(value(args, &mut index, flag, "a hex address range")) {
+                    Some(range) => trace_range = Some(range),
+                    None => panic!("--trace-range expects a hex address range like 200-2FF"),
+                }
+            }
+            "--trace-class" => {
+                for name in value(args, &mut index, flag, "a list of instruction classes").split(',') {
+                    match emulator::Class::parse(name) {
+                        Some(class) => trace_classes.push(class),
+                        None => panic!("Unknown instruction class '{}', expected sys, flow, load, alu, memory, draw, input, timer or random", name),
+                    }
+                }
+            }
+            "--trace-ring" => {
+                match value(args, &mut index, flag, "a number of instructions").parse() {
+                    Ok(size) if size > 0 => trace_ring = Some(size),
+                    _ => panic!("--trace-ring expects a number of instructions, at least 1"),
+                }
+            }
+            "--profile" => profile_annotated = Some(value(args, &mut index, flag, "an output path").to_string()),
+            "--profile-folded" => profile_folded = Some(value(args, &mut index, flag, "an output path").to_string()),
+            "--gdb" => {
+                match value(args, &mut index, flag, "a port number").parse() {
+                    Ok(port) => gdb_port = Some(port),
+                    Err(_) => panic!("--gdb expects a port number"),
+                }
+            }
+            "--dap" => dap = true,
+            "--watch" => watch = Some(Watch::Reset),
+            "--watch-patch" => watch = Some(Watch::Patch),
+            "--rom-dir" => rom_dir = Some(value(args, &mut index, flag, "a directory").to_string()),
+            "--symbols" => symbol_path = Some(value(args, &mut index, flag, "a symbol file path").to_string()),
+            "--compile" => compile_output = Some(value(args, &mut index, flag, "an output path").to_string()),
+            arg => rom = Some(arg.to_string()),
+        }
+        index += 1;
+    }
+
+    let trace = trace_path.map(|path| emulator::TraceOptions {
+        path,
+        range: trace_range,
+        classes: trace_classes,
+        ring: trace_ring,
+    });
+
+    if profile_annotated.is_some() || profile_folded.is_some() {
+        options.profile = Some(emulator::ProfileOptions {
+            annotated: profile_annotated,
+            folded: profile_folded,
+        });
+    }
+
+    if !batch {
+        if seeds.len() > 1 || quirk_sets.len() > 1 {
+            panic!("Several seeds or quirk sets need --batch");
+        }
+        options.seed = seeds.first().copied();
+        if let Some(name) = quirk_sets.first() {
+            options.quirks = emulator::Quirks::preset(name).unwrap();
+        }
+    } else if dap || gdb_port.is_some() || memory_viewer || watch.is_some() || frontend != Frontend::Sdl {
+        panic!("--batch runs without a frontend or debugger");
+    }
+
+    if rom.is_none() && !dap {
+        panic!("Must provide rom as command line argument");
+    }
+
+    if dap && gdb_port.is_some() {
+        panic!("--gdb and --dap can't be used together");
+    }
+
+    if frontend != Frontend::Sdl && (dap || gdb_port.is_some() || memory_viewer || watch.is_some()) {
+        panic!("--gdb, --dap, --memory-viewer and --watch need the SDL frontend");
+    }
+
+    Args {
+        rom,
+        options,
+        trace,
+        frame_limit,
+        memory_viewer,
+        cheat_path,
+        gdb_port,
+        dap,
+        compile_output,
+        symbol_path,
+        watch,
+        rom_dir,
+        frontend,
+        single_thread,
+        batch,
+        seeds,
+        quirk_sets,
+        threads,
+        report,
+    }
+
+}
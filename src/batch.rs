@@ -0,0 +1,180 @@
+//! Runs many independent machines at once on a thread pool, for compatibility sweeps over ROMs,
+//! seeds and quirk sets. Each job gets its own `Cpu`, a job that fails or panics only affects
+//! its own outcome, and so does a ROM that couldn't be read.
+
+use crate::emulator::{Cpu, Options, Quirks};
+
+use rayon::prelude::*;
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+pub struct Job {
+    /// Shown in summaries and reports, usually the ROM's file name.
+    pub name: String,
+    /// The program, or why it couldn't be read, which fails the run.
+    pub rom: Result<Vec<u8>, String>,
+    pub seed: Option<u64>,
+    /// Name of the quirk set, for grouping results.
+    pub quirk_set: String,
+    pub quirks: Quirks,
+    pub cycles_per_frame: usize,
+    /// Frames to run, with no keys pressed.
+    pub frames: u64,
+}
+
+pub struct Outcome {
+    /// The program's error (or the emulator's panic), None if it ran every frame.
+    pub error: Option<String>,
+    pub frames: u64,
+    /// FNV-1a hash of the final screen, equal hashes mean equal screens.
+    pub screen_hash: u64,
+    pub lit_pixels: usize,
+    /// Frames the beeper was on.
+    pub sound_frames: u64,
+    pub duration: Duration,
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF29CE484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001B3))
+}
+
+fn run_job(job: &Job) -> Outcome {
+
+    let start = Instant::now();
+
+    let mut options = Options::new();
+    options.quirks = job.quirks;
+    options.cycles_per_frame = job.cycles_per_frame;
+    options.seed = job.seed;
+    let mut cpu = Cpu::new(&options);
+
+    let mut frames = 0;
+    let mut sound_frames = 0;
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), String> {
+        cpu.load_rom(job.rom.as_deref().map_err(String::clone)?)?;
+        while frames < job.frames {
+            cpu.run_frame().map_err(|error| error.to_string())?;
+            frames += 1;
+            if cpu.sound_active() {
+                sound_frames += 1;
+            }
+        }
+        Ok(())
+    }));
+
+    let error = match result {
+        Ok(result) => result.err(),
+        Err(payload) => {
+            let message = payload.downcast_ref::<String>().cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|message| message.to_string()))
+                .unwrap_or_default();
+            Some(format!("Emulator panicked: {}", message))
+        }
+    };
+
+    Outcome {
+        error,
+        frames,
+        screen_hash: fnv1a(cpu.display()),
+        lit_pixels: cpu.display().iter().filter(|pixel| **pixel != 0).count(),
+        sound_frames,
+        duration: start.elapsed(),
+    }
+
+}
+
+/// Runs every job, on `threads` threads or one per core. Outcomes are in the same order as the
+/// jobs. Panics end up in the outcomes, so the panic hook is silenced (for the whole process)
+/// until it returns.
+pub fn run(jobs: &[Job], threads: Option<usize>) -> Result<Vec<Outcome>, String> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .build()
+        .map_err(|error| format!("Failed to start threads: {}", error))?;
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let outcomes = pool.install(|| jobs.par_iter().map(run_job).collect());
+    panic::set_hook(hook);
+    Ok(outcomes)
+}
+
+/// Totals per quirk set, then per ROM the runs that failed and whether the final screen depends
+/// on the seed or the quirk set.
+pub fn summary(jobs: &[Job], outcomes: &[Outcome]) -> String {
+
+    let mut out = String::new();
+
+    let failed = outcomes.iter().filter(|outcome| outcome.error.is_some()).count();
+    out += &format!("{} runs, {} ok, {} failed\n", outcomes.len(), outcomes.len() - failed, failed);
+
+    let mut quirk_sets: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for (job, outcome) in jobs.iter().zip(outcomes) {
+        let totals = quirk_sets.entry(&job.quirk_set).or_default();
+        if outcome.error.is_some() { totals.1 += 1 } else { totals.0 += 1 }
+    }
+    for (name, (ok, failed)) in &quirk_sets {
+        out += &format!("  {:8} {} ok, {} failed\n", name, ok, failed);
+    }
+
+    let mut roms: BTreeMap<&str, Vec<(&Job, &Outcome)>> = BTreeMap::new();
+    for (job, outcome) in jobs.iter().zip(outcomes) {
+        roms.entry(&job.name).or_default().push((job, outcome));
+    }
+
+    for (name, runs) in &roms {
+
+        let mut notes: Vec<String> = Vec::new();
+
+        for (job, outcome) in runs {
+            if let Some(error) = &outcome.error {
+                let seed = job.seed.map(|seed| format!(" seed {}", seed)).unwrap_or_default();
+                notes.push(format!("{}{} failed at frame {}: {}", job.quirk_set, seed, outcome.frames, error));
+            }
+        }
+
+        // Screens that only differ by seed mean the ROM uses random numbers, by quirk set that it's sensitive to them
+        let distinct = |key: &dyn Fn(&Job) -> String| -> bool {
+            let mut screens: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+            for (job, outcome) in runs {
+                screens.entry(key(job)).or_default().push(outcome.screen_hash);
+            }
+            screens.values().any(|hashes| hashes.iter().any(|hash| *hash != hashes[0]))
+        };
+        if distinct(&|job| job.quirk_set.clone()) {
+            notes.push(String::from("final screen depends on the seed"));
+        }
+        if distinct(&|job| format!("{:?}", job.seed)) {
+            notes.push(String::from("final screen depends on the quirk set"));
+        }
+
+        if !notes.is_empty() {
+            out += &format!("{}:\n", name);
+            for note in notes {
+                out += &format!("  {}\n", note);
+            }
+        }
+
+    }
+
+    out
+
+}
+
+/// Every outcome with its job, as a JSON array.
+pub fn report(jobs: &[Job], outcomes: &[Outcome]) -> Value {
+    Value::Array(jobs.iter().zip(outcomes).map(|(job, outcome)| json!({
+        "rom": job.name,
+        "seed": job.seed,
+        "quirks": job.quirk_set,
+        "error": outcome.error,
+        "frames": outcome.frames,
+        "screen_hash": format!("{:016x}", outcome.screen_hash),
+        "lit_pixels": outcome.lit_pixels,
+        "sound_frames": outcome.sound_frames,
+        "milliseconds": outcome.duration.as_secs_f64() * 1000.0,
+    })).collect())
+}
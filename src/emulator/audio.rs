@@ -1,59 +1,111 @@
-extern crate sdl2;
+use super::square_wave::SquareWave;
 
-use sdl2::audio::AudioSpecDesired;
-use sdl2::audio::AudioDevice;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::fs::File;
 
-pub struct Audio {
-    pub device: AudioDevice<SquareWave>,
+// The audio gate: the beeper is on between resume() and pause()
+pub trait AudioSink {
+    fn resume(&mut self);
+    fn pause(&mut self);
+    // Called once per 60Hz frame, for sinks that generate samples themselves
+    fn end_frame(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub enum AudioBackend {
+    Sdl,
+    Null,
+    Wav(String),
+}
+
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn resume(&mut self) {}
+    fn pause(&mut self) {}
 }
 
-impl Audio {
-    
-    pub fn new(sdl_context: &sdl2::Sdl, frequency: i32, sample_rate: u16) -> Audio {
-        
-        let audio_subsystem = sdl_context.audio().unwrap();
+// Renders the beeper into a 16-bit mono PCM file. The header is rewritten after
+// every frame so the file stays valid even if the process exits abruptly.
+pub struct WavAudio {
+    file: File,
+    wave: SquareWave,
+    playing: bool,
+    samples_per_frame: usize,
+    data_len: u32,
+}
+
+impl WavAudio {
+
+    pub fn new(path: &str, frequency: i32) -> io::Result<WavAudio> {
 
-        let want = AudioSpecDesired {
-            freq: Some(frequency),
-            channels: Some(1),
-            samples: Some(sample_rate),
+        let mut audio = WavAudio {
+            file: File::create(path)?,
+            wave: SquareWave::new(frequency),
+            playing: false,
+            samples_per_frame: (frequency / 60) as usize,
+            data_len: 0,
         };
-        
-        let device = audio_subsystem.open_playback(None, &want, |spec| {
-            SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.1,
-            }
-        }).unwrap();
-        
-        Audio {
-            device,
-        }
+
+        audio.write_header(frequency as u32)?;
+        Ok(audio)
 
     }
 
-}
+    fn write_header(&mut self, frequency: u32) -> io::Result<()> {
+        let mut header: Vec<u8> = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+        header.extend_from_slice(&frequency.to_le_bytes());
+        header.extend_from_slice(&(frequency * 2).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        self.file.write_all(&header)
+    }
 
-pub struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
 }
 
-impl sdl2::audio::AudioCallback for SquareWave {
-    
-    type Channel = f32;
-
-    fn callback(&mut self, out: &mut [Self::Channel]) {
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+impl AudioSink for WavAudio {
+
+    fn resume(&mut self) {
+        self.playing = true;
+    }
+
+    fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    fn end_frame(&mut self) -> io::Result<()> {
+
+        let mut samples: Vec<f32> = vec![0.0; self.samples_per_frame];
+        if self.playing {
+            self.wave.fill(&mut samples);
+        }
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            bytes.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_le_bytes());
         }
+
+        self.data_len += bytes.len() as u32;
+
+        self.file.write_all(&bytes)?;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+
     }
 
 }
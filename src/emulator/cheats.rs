@@ -0,0 +1,230 @@
+use super::cpu::Cpu;
+use super::memory;
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::Path;
+
+// One code per line in the cheat file, '#' starts a comment:
+//   AAA:VV      write VV to address AAA every frame
+//   AAA:VV?CC   write VV to AAA only while it currently holds CC
+//   VX:VV       freeze register VX at VV
+pub enum Cheat {
+    Write { address: u16, value: u8 },
+    ConditionalWrite { address: u16, value: u8, compare: u8 },
+    FreezeRegister { register: usize, value: u8 },
+}
+
+impl Cheat {
+
+    pub fn parse(code: &str) -> Result<Cheat, String> {
+
+        let (target, value) = match code.split_once(':') {
+            Some(parts) => parts,
+            None => return Err(format!("expected 'address:value', got '{}'", code)),
+        };
+
+        let (value, compare) = match value.split_once('?') {
+            Some((value, compare)) => (value, Some(parse_byte(compare)?)),
+            None => (value, None),
+        };
+        let value = parse_byte(value)?;
+
+        let target = target.trim();
+        if target.len() == 2 && (target.starts_with('V') || target.starts_with('v')) {
+            if compare.is_some() {
+                return Err(format!("register freeze can't be conditional: '{}'", code));
+            }
+            return match usize::from_str_radix(&target[1..], 16) {
+                Ok(register) => Ok(Cheat::FreezeRegister { register, value }),
+                Err(_) => Err(format!("invalid register '{}'", target)),
+            };
+        }
+
+        let address = match u16::from_str_radix(target, 16) {
+            Ok(address) if (address as usize) < memory::SIZE => address,
+            _ => return Err(format!("invalid address '{}'", target)),
+        };
+
+        Ok(match compare {
+            Some(compare) => Cheat::ConditionalWrite { address, value, compare },
+            None => Cheat::Write { address, value },
+        })
+
+    }
+
+    pub fn apply(&self, cpu: &mut Cpu) {
+        match *self {
+            // Poked so the memory viewer doesn't show cheat addresses as written by the program
+            Cheat::Write { address, value } => cpu.memory_mut().poke(address, value),
+            Cheat::ConditionalWrite { address, value, compare } => {
+                if cpu.memory().read_u8(address) == compare {
+                    cpu.memory_mut().poke(address, value);
+                }
+            }
+            Cheat::FreezeRegister { register, value } => cpu.set_register(register, value),
+        }
+    }
+
+}
+
+impl fmt::Display for Cheat {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cheat::Write { address, value } => write!(f, "{:03X}:{:02X}", address, value),
+            Cheat::ConditionalWrite { address, value, compare } => write!(f, "{:03X}:{:02X}?{:02X}", address, value, compare),
+            Cheat::FreezeRegister { register, value } => write!(f, "V{:X}:{:02X}", register, value),
+        }
+    }
+
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    u8::from_str_radix(text.trim(), 16).map_err(|_| format!("invalid byte '{}'", text.trim()))
+}
+
+pub struct Cheats {
+    // None when there's no file to add cheats to, e.g. for a ROM read from stdin
+    path: Option<String>,
+    pub codes: Vec<Cheat>,
+}
+
+impl Cheats {
+
+    // The per-ROM cheat file lives next to the ROM, e.g. roms/pong.ch8 -> roms/pong.cht. A ROM
+    // read from stdin ("-") has none.
+    pub fn path_for_rom(rom: &str) -> Option<String> {
+        if rom == "-" {
+            return None;
+        }
+        Some(Path::new(rom).with_extension("cht").to_string_lossy().into_owned())
+    }
+
+    pub fn new() -> Cheats {
+        Cheats {
+            path: None,
+            codes: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Cheats {
+
+        let mut codes: Vec<Cheat> = Vec::new();
+
+        // A missing cheat file just means no cheats yet
+        if let Ok(contents) = fs::read_to_string(path) {
+            for (number, line) in contents.lines().enumerate() {
+                let code = match line.split_once('#') {
+                    Some((code, _)) => code.trim(),
+                    None => line.trim(),
+                };
+                if code.is_empty() {
+                    continue;
+                }
+                match Cheat::parse(code) {
+                    Ok(cheat) => codes.push(cheat),
+                    Err(error) => eprintln!("{}:{}: ignoring cheat: {}", path, number + 1, error),
+                }
+            }
+        }
+
+        Cheats {
+            path: Some(path.to_string()),
+            codes,
+        }
+
+    }
+
+    pub fn apply(&self, cpu: &mut Cpu) {
+        for cheat in &self.codes {
+            cheat.apply(cpu);
+        }
+    }
+
+    // Appends to the file rather than rewriting it so user comments survive
+    pub fn add(&mut self, cheat: Cheat) -> Result<(), String> {
+        let path = self.path.as_deref().ok_or("No cheat file, use --cheats path")?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|error| format!("Failed to open {}: {}", path, error))?;
+        writeln!(file, "{}", cheat).map_err(|error| format!("Failed to write {}: {}", path, error))?;
+        self.codes.push(cheat);
+        Ok(())
+    }
+
+}
+
+impl Default for Cheats {
+    fn default() -> Cheats {
+        Cheats::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::emulator::Options;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("chip_8_cheats_test_{}_{}.cht", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn codes_parse_and_print() {
+        for code in ["2A0:05", "2A0:05?03", "VF:FF"] {
+            assert_eq!(Cheat::parse(code).unwrap().to_string(), code);
+        }
+        assert_eq!(Cheat::parse(" 2a0 : 5 ").unwrap().to_string(), "2A0:05");
+        assert_eq!(Cheat::parse("va:1").unwrap().to_string(), "VA:01");
+    }
+
+    #[test]
+    fn bad_codes() {
+        assert_eq!(Cheat::parse("2A0").err().unwrap(), "expected 'address:value', got '2A0'");
+        assert_eq!(Cheat::parse("2A0:100").err().unwrap(), "invalid byte '100'");
+        assert_eq!(Cheat::parse("2A0:05?XY").err().unwrap(), "invalid byte 'XY'");
+        assert_eq!(Cheat::parse("1000:05").err().unwrap(), "invalid address '1000'");
+        assert_eq!(Cheat::parse("VG:05").err().unwrap(), "invalid register 'VG'");
+        assert_eq!(Cheat::parse("V1:05?01").err().unwrap(), "register freeze can't be conditional: 'V1:05?01'");
+    }
+
+    #[test]
+    fn apply() {
+        let mut cpu = Cpu::new(&Options::new());
+        cpu.memory_mut().set_u8(0x301, 0x09);
+        cpu.memory_mut().clear_written();
+        let cheats = Cheats {
+            path: None,
+            codes: ["300:05", "301:07?09", "302:07?09", "V3:42"].iter().map(|code| Cheat::parse(code).unwrap()).collect(),
+        };
+        cheats.apply(&mut cpu);
+        assert_eq!([cpu.memory().read_u8(0x300), cpu.memory().read_u8(0x301), cpu.memory().read_u8(0x302)], [0x05, 0x07, 0x00]);
+        assert_eq!(cpu.register(3), Some(0x42));
+        assert!(!cpu.memory().was_written(0x300));
+    }
+
+    #[test]
+    fn files() {
+        let path = temp_path("files");
+        fs::write(&path, "# Infinite lives\n2F5:03 # lives\n\nnonsense\nV0:01\n").unwrap();
+        let mut cheats = Cheats::load(&path);
+        assert_eq!(cheats.codes.iter().map(Cheat::to_string).collect::<Vec<String>>(), vec!["2F5:03", "V0:01"]);
+
+        cheats.add(Cheat::Write { address: 0x300, value: 0x01 }).unwrap();
+        assert_eq!(cheats.codes.len(), 3);
+        assert!(fs::read_to_string(&path).unwrap().starts_with("# Infinite lives\n"));
+        assert_eq!(Cheats::load(&path).codes.len(), 3);
+        fs::remove_file(&path).unwrap();
+
+        assert!(Cheats::load(&temp_path("missing")).codes.is_empty());
+        assert_eq!(Cheats::new().add(Cheat::Write { address: 0x300, value: 0x01 }).unwrap_err(), "No cheat file, use --cheats path");
+        assert_eq!(Cheats::path_for_rom("roms/pong.ch8").as_deref(), Some("roms/pong.cht"));
+        assert_eq!(Cheats::path_for_rom("-"), None);
+    }
+
+}
@@ -1,13 +1,16 @@
 use super::instruction::Instruction;
 use super::memory::Memory;
 use super::stack::Stack;
-use super::renderer::Renderer;
-
-use std::io::prelude::*;
-use std::fs::File;
+use super::options::{Options, Quirks};
+use super::error::Error;
+use super::memory;
+use super::rom;
+use super::trace::Tracer;
+use super::profiler::Profiler;
+use super::symbols::Symbols;
 
 extern crate rand;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 
 pub struct Pause {
     active: bool,
@@ -17,23 +20,63 @@ pub struct Pause {
     register: usize,
 }
 
+// Display size in pixels, the framebuffer is one byte per pixel (0 or 255), row by row
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+
+// Default instructions executed per 60Hz frame
+pub const CYCLES_PER_FRAME: usize = 20;
+
+// Save states start with this, followed by a version byte
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+const STATE_VERSION: u8 = 2;
+
+/// Length in bytes of every `save_state()`.
+pub const STATE_SIZE: usize = STATE_MAGIC.len() + 1
+    + 2 + 2 + 1 + 1 + 16  // PC, I, DT, ST, V0-VF
+    + 1 + 16 * 2          // SP, stack
+    + 5 + 1               // Fx0A wait, drawn this frame
+    + 2 + memory::SIZE    // ROM length, memory
+    + DISPLAY_WIDTH * DISPLAY_HEIGHT
+    + 0x10 + 0x10;        // keys, last frame's keys
+
+// Offset of the ROM length, read before anything else is restored
+const STATE_ROM_LEN: usize = STATE_MAGIC.len() + 1 + 2 + 2 + 1 + 1 + 16 + 1 + 16 * 2 + 5 + 1;
+
+/// A CHIP-8 machine: memory, registers, timers, framebuffer and keypad. It doesn't draw, play
+/// sound or read input itself, frontends read `display()` / `sound_active()` and call `set_key()`.
+/// It's `Send`, so it can run on a thread of its own.
 pub struct Cpu {
     pc: u16,
     pub dt: u8,
     pub st: u8,
     i: u16,
-    pub drawn_this_frame: bool,
-    rng: rand::rngs::ThreadRng,
+    drawn_this_frame: bool,
+    rng: rand::rngs::StdRng,
     pause: Pause,
     memory: Memory,
     stack: Stack,
-    pub renderer: Renderer,
+    display: [u8; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+    keys: [u8; 0x10],
+    last_keys: [u8; 0x10],
     registers: [u8; 16],
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    pub symbols: Symbols,
+    pub quirks: Quirks,
+    pub cycles_per_frame: usize,
 }
 
+// Keeps it that way, EmulationThread depends on it
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Cpu>();
+};
+
 impl Cpu {
 
-    pub fn new() -> Cpu {
+    /// A machine in its power-on state with no ROM loaded.
+    pub fn new(options: &Options) -> Cpu {
         Cpu {
             pc: 0x200,
             dt: 0,
@@ -41,111 +84,369 @@ impl Cpu {
             i: 0,
             drawn_this_frame: false,
             pause: Pause { active: false, down: false, down_key: 0, released: false, register: 0, },
-            rng: rand::thread_rng(),
+            rng: options.seed.map_or_else(rand::rngs::StdRng::from_entropy, rand::rngs::StdRng::seed_from_u64),
             memory: Memory::new(),
             stack: Stack::new(),
-            renderer: Renderer::new(),
+            display: [0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            keys: [0; 0x10],
+            last_keys: [0; 0x10],
             registers: [0; 16],
+            tracer: None,
+            profiler: options.profile.as_ref().map(Profiler::new),
+            symbols: Symbols::new(),
+            quirks: options.quirks,
+            cycles_per_frame: options.cycles_per_frame,
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.stack.sp()
+    }
+
+    pub fn set_sp(&mut self, sp: u8) {
+        self.stack.set_sp(sp);
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    /// V0 to VF, None past VF.
+    pub fn register(&self, register: usize) -> Option<u8> {
+        self.registers.get(register).copied()
+    }
+
+    /// Sets V0 to VF, other registers are ignored.
+    pub fn set_register(&mut self, register: usize, value: u8) {
+        if let Some(slot) = self.registers.get_mut(register) {
+            *slot = value;
+        }
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// The framebuffer, `DISPLAY_WIDTH * DISPLAY_HEIGHT` bytes of 0 (off) or 255 (on).
+    pub fn display(&self) -> &[u8] {
+        &self.display
+    }
+
+    /// Presses or releases one of the 16 keypad keys (0x0 to 0xF), other keys are ignored.
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        if let Some(slot) = self.keys.get_mut(key) {
+            *slot = pressed as u8;
+        }
+    }
+
+    /// Sets all 16 keys at once, indexed by key.
+    pub fn set_keys(&mut self, keys: &[bool; 0x10]) {
+        for (key, pressed) in keys.iter().enumerate() {
+            self.set_key(key, *pressed);
+        }
+    }
+
+    pub fn key(&self, key: usize) -> bool {
+        self.keys.get(key) == Some(&1)
+    }
+
+    /// True while the sound timer is running, i.e. the beeper should be on.
+    pub fn sound_active(&self) -> bool {
+        self.st > 0
+    }
+
+    /// Counts the delay and sound timers down, once per 60Hz frame.
+    pub fn tick_timers(&mut self) {
+        if self.dt > 0 { self.dt -= 1; }
+        if self.st > 0 { self.st -= 1; }
+    }
+
+    /// Call at the end of every frame, even ones where nothing ran: allows the next sprite draw
+    /// and remembers the keys so Fx0A can tell which were pressed since.
+    pub fn end_frame(&mut self) {
+        self.drawn_this_frame = false;
+        self.last_keys = self.keys;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.end_frame();
+        }
+    }
+
+    /// One 60Hz frame: ticks the timers and runs `cycles_per_frame` instructions, stopping at
+    /// the first error.
+    pub fn run_frame(&mut self) -> Result<(), Error> {
+        self.tick_timers();
+        let result = (0..self.cycles_per_frame).try_for_each(|_| self.step());
+        self.end_frame();
+        result
+    }
+
+    /// Restarts CXNN's random numbers from a seed, so runs can be repeated exactly.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = rand::rngs::StdRng::seed_from_u64(seed);
+    }
+
+    /// Copies a program to 0x200, refusing empty ROMs and ROMs that don't fit in memory.
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), String> {
+        rom::validate(data, self.quirks.platform)?;
+        self.memory.load_rom(data);
+        Ok(())
+    }
+
+    /// Writes only the bytes that differ between the old and new ROM, so registers and memory
+    /// the game changed at runtime survive a reload.
+    pub fn patch_rom(&mut self, old: &[u8], new: &[u8]) -> Result<(), String> {
+        rom::validate(new, self.quirks.platform)?;
+        self.memory.patch_rom(old, new);
+        Ok(())
+    }
+
+    /// Back to the power-on state with the ROM unloaded and every key released.
+    pub fn reset(&mut self) {
+        self.pc = 0x200;
+        self.dt = 0;
+        self.st = 0;
+        self.i = 0;
+        self.drawn_this_frame = false;
+        self.pause = Pause { active: false, down: false, down_key: 0, released: false, register: 0, };
+        self.keys = [0; 0x10];
+        self.last_keys = [0; 0x10];
+        if let Some(profiler) = &mut self.profiler {
+            profiler.clear_calls();
+        }
+        self.memory = Memory::new();
+        self.stack = Stack::new();
+        self.registers = [0; 16];
+        self.display.fill(0);
+    }
+
+    /// Everything needed to resume the machine later, `STATE_SIZE` bytes. Symbols, tracing,
+    /// profiling, quirks and speed aren't part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+
+        let mut state = Vec::with_capacity(STATE_SIZE);
+        state.extend_from_slice(STATE_MAGIC);
+        state.push(STATE_VERSION);
+
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        state.extend_from_slice(&self.i.to_be_bytes());
+        state.push(self.dt);
+        state.push(self.st);
+        state.extend_from_slice(&self.registers);
+
+        state.push(self.stack.sp());
+        for index in 0..16 {
+            let address = self.stack.entries().get(index).copied().unwrap_or(0);
+            state.extend_from_slice(&address.to_be_bytes());
         }
+
+        state.extend_from_slice(&[self.pause.active as u8, self.pause.down as u8, self.pause.down_key, self.pause.released as u8, self.pause.register as u8]);
+        state.push(self.drawn_this_frame as u8);
+
+        state.extend_from_slice(&(self.memory.rom_end() - 0x200).to_be_bytes());
+        state.extend_from_slice(self.memory.data());
+        state.extend_from_slice(&self.display);
+        state.extend_from_slice(&self.keys);
+        state.extend_from_slice(&self.last_keys);
+
+        state
+
     }
 
-    pub fn load_rom(&mut self, path: &str) {
-        let mut file = File::open(path).unwrap();
-        let mut buffer: Vec<u8> = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
-        self.memory.load_rom(&buffer);
+    /// Restores a `save_state()`, leaving the machine untouched if it isn't one.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+
+        if state.len() != STATE_SIZE || &state[..4] != STATE_MAGIC {
+            return Err(String::from("Not a CHIP-8 save state"));
+        }
+        if state[4] != STATE_VERSION {
+            return Err(format!("Unsupported save state version {}", state[4]));
+        }
+        // Checked up front so a bad state leaves the machine as it was
+        let rom_len = u16::from_be_bytes([state[STATE_ROM_LEN], state[STATE_ROM_LEN + 1]]) as usize;
+        if rom_len > memory::SIZE - 0x200 {
+            return Err(format!("Save state ROM length {} doesn't fit in memory", rom_len));
+        }
+
+        let mut bytes = state[5..].iter().copied();
+        let mut take = |count: usize| -> Vec<u8> { bytes.by_ref().take(count).collect() };
+        let word = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+
+        self.pc = word(&take(2));
+        self.i = word(&take(2));
+        self.dt = take(1)[0];
+        self.st = take(1)[0];
+        self.registers.copy_from_slice(&take(16));
+
+        let sp = take(1)[0];
+        let stack = take(32);
+        self.stack = Stack::new();
+        for address in stack.chunks(2).take(sp as usize) {
+            self.stack.push(word(address));
+        }
+
+        let pause = take(5);
+        self.pause = Pause { active: pause[0] != 0, down: pause[1] != 0, down_key: pause[2], released: pause[3] != 0, register: pause[4] as usize & 0xF, };
+        self.drawn_this_frame = take(1)[0] != 0;
+
+        take(2);
+        self.memory = Memory::restore(&take(memory::SIZE), rom_len);
+        self.display.copy_from_slice(&take(DISPLAY_WIDTH * DISPLAY_HEIGHT));
+        self.keys.copy_from_slice(&take(0x10));
+        self.last_keys.copy_from_slice(&take(0x10));
+        if let Some(profiler) = &mut self.profiler {
+            profiler.clear_calls();
+        }
+
+        Ok(())
+
+    }
+
+    /// One line per frame of the call stack, starting from the instruction at `pc`.
+    pub fn backtrace(&self, pc: u16) -> String {
+        let mut backtrace = String::new();
+        for (index, address) in self.stack.frames(pc).into_iter().enumerate() {
+            backtrace += &format!("  {:2}: {:03X} {}\n", index, address, self.symbols.name_for(address).unwrap_or_default());
+        }
+        backtrace
     }
 
-    pub fn decode_next_instruction(&mut self) {
+    /// Executes a single instruction. While waiting for a key (Fx0A) it does nothing.
+    pub fn step(&mut self) -> Result<(), Error> {
         
         if self.pause.active {
             if !self.pause.down {
-                if self.renderer.last_keys != self.renderer.keys {
+                if self.last_keys != self.keys {
                     self.op_ld_vx_k_down();
                 }
-            } else if !self.pause.released {
-                if self.renderer.last_keys != self.renderer.keys {
-                    self.op_ld_vx_k_released();
-                }
+            } else if !self.pause.released && self.last_keys != self.keys {
+                self.op_ld_vx_k_released();
             }
-            return;
+            return Ok(());
+        }
+
+        if self.pc as usize + 1 >= memory::SIZE {
+            return Err(Error::PcOutOfBounds { pc: self.pc });
+        }
+
+        let instruction = Instruction::new(self.memory.read_u16(self.pc));
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(self.pc, &instruction, &self.registers, self.i, self.stack.sp(), &self.symbols);
+        }
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.pc, &instruction);
         }
 
-        let opcode = self.memory.read_u16(self.pc);
         self.pc += 2;
-        self.execute_instruction(&Instruction::new(opcode));
-    
+        self.execute_instruction(&instruction)
+
     }
 
-    pub fn execute_instruction(&mut self, instruction: &Instruction) {
-        //println!("Executing instruction: {:#06X?}", instruction.raw);
-        
-                match instruction.raw & 0xF000 {
+    pub fn execute_instruction(&mut self, instruction: &Instruction) -> Result<(), Error> {
+
+        let unknown = Error::UnknownOpcode { pc: self.pc.wrapping_sub(2), opcode: instruction.raw };
+
+        match instruction.raw & 0xF000 {
             0x0000 => match instruction.raw {
                 0x00E0 => self.op_cls(),
-                0x00EE => self.op_ret(),
-                _ => println!("SYS addr instruction, ignoring"), 
+                0x00EE => self.op_ret()?,
+                _ => eprintln!("SYS addr instruction, ignoring"),
             }
-            0x1000 => self.op_jp_addr(&instruction),
-            0x2000 => self.op_call_addr(&instruction),
-            0x3000 => self.op_se_vx_byte(&instruction),
-            0x4000 => self.op_sne_vx_byte(&instruction),
-            0x5000 => self.op_se_vx_vy(&instruction),
-            0x6000 => self.op_ld_vx_byte(&instruction),
-            0x7000 => self.op_add_vx_byte(&instruction),
+            0x1000 => self.op_jp_addr(instruction),
+            0x2000 => self.op_call_addr(instruction)?,
+            0x3000 => self.op_se_vx_byte(instruction),
+            0x4000 => self.op_sne_vx_byte(instruction),
+            0x5000 => self.op_se_vx_vy(instruction),
+            0x6000 => self.op_ld_vx_byte(instruction),
+            0x7000 => self.op_add_vx_byte(instruction),
             0x8000 => match instruction.raw & 0x000F {
-                0x0000 => self.op_ld_vx_vy(&instruction),
-                0x0001 => self.op_or_vx_vy(&instruction),
-                0x0002 => self.op_and_vx_vy(&instruction), 
-                0x0003 => self.op_xor_vx_vy(&instruction),
-                0x0004 => self.op_add_vx_vy(&instruction),
-                0x0005 => self.op_sub_vx_vy(&instruction),
-                0x0006 => self.op_shr_vx_vy(&instruction),
-                0x0007 => self.op_subn_vx_vy(&instruction),
-                0x000E => self.op_shl_vx_vy(&instruction),
-                _ => panic!("Unhandled instruction: {:#06X?}", instruction.raw),
+                0x0000 => self.op_ld_vx_vy(instruction),
+                0x0001 => self.op_or_vx_vy(instruction),
+                0x0002 => self.op_and_vx_vy(instruction), 
+                0x0003 => self.op_xor_vx_vy(instruction),
+                0x0004 => self.op_add_vx_vy(instruction),
+                0x0005 => self.op_sub_vx_vy(instruction),
+                0x0006 => self.op_shr_vx_vy(instruction),
+                0x0007 => self.op_subn_vx_vy(instruction),
+                0x000E => self.op_shl_vx_vy(instruction),
+                _ => return Err(unknown),
             }
-            0x9000 => self.op_sne_vx_vy(&instruction),
-            0xA000 => self.op_ld_i_addr(&instruction),
-            0xB000 => self.op_jp_v0_addr(&instruction),
-            0xC000 => self.op_rnd_vx_byte(&instruction),
-            0xD000 => self.op_drw_vx_vy_n(&instruction),
+            0x9000 => self.op_sne_vx_vy(instruction),
+            0xA000 => self.op_ld_i_addr(instruction),
+            0xB000 => self.op_jp_v0_addr(instruction),
+            0xC000 => self.op_rnd_vx_byte(instruction),
+            0xD000 => self.op_drw_vx_vy_n(instruction),
             0xE000 => match instruction.raw & 0x00FF {
-                0x9E => self.op_skp_vx(&instruction),
-                0xA1 => self.op_sknp_vx(&instruction),
-                _ => panic!("Unhandled instruction: {:#06X?}", instruction.raw),
+                0x9E => self.op_skp_vx(instruction),
+                0xA1 => self.op_sknp_vx(instruction),
+                _ => return Err(unknown),
             }
             0xF000 => match instruction.raw & 0x00FF {
-                0x07 => self.op_ld_vx_dt(&instruction),
-                0x0A => self.op_ld_vx_k(&instruction),
-                0x15 => self.op_ld_dt_vx(&instruction),
-                0x18 => self.op_ld_st_vx(&instruction),
-                0x1E => self.op_add_i_vx(&instruction),
-                0x29 => self.op_ld_f_vx(&instruction),
-                0x33 => self.op_ld_b_vx(&instruction),
-                0x55 => self.op_ld_i_vx(&instruction),
-                0x65 => self.op_ld_vx_i(&instruction),
-                _ => panic!("Unhandled instruction: {:#06X?}", instruction.raw),
+                0x07 => self.op_ld_vx_dt(instruction),
+                0x0A => self.op_ld_vx_k(instruction),
+                0x15 => self.op_ld_dt_vx(instruction),
+                0x18 => self.op_ld_st_vx(instruction),
+                0x1E => self.op_add_i_vx(instruction),
+                0x29 => self.op_ld_f_vx(instruction),
+                0x33 => self.op_ld_b_vx(instruction),
+                0x55 => self.op_ld_i_vx(instruction),
+                0x65 => self.op_ld_vx_i(instruction),
+                _ => return Err(unknown),
             }
-            _ => panic!("Unhandled instruction: {:#06X?}", instruction.raw),
+            _ => return Err(unknown),
         }
+        Ok(())
     }
     
     fn op_cls(&mut self) {
-        self.renderer.clear_display();
+        self.display.fill(0);
     }
 
-    fn op_ret(&mut self) {
-        self.pc = self.stack.pop();
+    fn op_ret(&mut self) -> Result<(), Error> {
+        match self.stack.pop() {
+            Some(address) => self.pc = address,
+            None => return Err(Error::StackUnderflow { pc: self.pc - 2 }),
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.ret();
+        }
+        Ok(())
     }
 
     fn op_jp_addr(&mut self, instruction : &Instruction) {
         self.pc = instruction.nnn();
     }
 
-    fn op_call_addr(&mut self, instruction: &Instruction) {
-        self.stack.push(self.pc);
+    fn op_call_addr(&mut self, instruction: &Instruction) -> Result<(), Error> {
+        if !self.stack.push(self.pc) {
+            return Err(Error::StackOverflow { pc: self.pc - 2 });
+        }
         self.pc = instruction.nnn();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.call(self.pc);
+        }
+        Ok(())
     }
 
     fn op_se_vx_byte(&mut self, instruction: &Instruction) {
@@ -184,19 +485,25 @@ impl Cpu {
     fn op_or_vx_vy(&mut self, instruction: &Instruction) {
         let result = self.registers[instruction.x()] | self.registers[instruction.y()];
         self.registers[instruction.x()] = result;
-        self.registers[0xF] = 0;
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
     }
 
     fn op_and_vx_vy(&mut self, instruction: &Instruction) {
         let result = self.registers[instruction.x()] & self.registers[instruction.y()];
         self.registers[instruction.x()] = result;
-        self.registers[0xF] = 0;
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
     }
 
     fn op_xor_vx_vy(&mut self, instruction: &Instruction) {
         let result = self.registers[instruction.x()] ^ self.registers[instruction.y()];
         self.registers[instruction.x()] = result;
-        self.registers[0xF] = 0;
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
     }
 
     fn op_add_vx_vy(&mut self, instruction: &Instruction) {
@@ -218,8 +525,8 @@ impl Cpu {
 
     fn op_shr_vx_vy(&mut self, instruction: &Instruction) {
         let register_x = self.registers[instruction.x()];
-        self.registers[instruction.x()] = self.registers[instruction.y()];
-        self.registers[instruction.x()] >>= 1;
+        let source = if self.quirks.shift_vy { self.registers[instruction.y()] } else { register_x };
+        self.registers[instruction.x()] = source >> 1;
         self.registers[0xF] = register_x & 0x1;
     }
 
@@ -232,8 +539,8 @@ impl Cpu {
 
     fn op_shl_vx_vy(&mut self, instruction: &Instruction) {
         let register_x = self.registers[instruction.x()];
-        self.registers[instruction.x()] = self.registers[instruction.y()];
-        self.registers[instruction.x()] <<= 1;
+        let source = if self.quirks.shift_vy { self.registers[instruction.y()] } else { register_x };
+        self.registers[instruction.x()] = source << 1;
         self.registers[0xF] = (register_x & 0x80) >> 7;
     }
     
@@ -248,7 +555,8 @@ impl Cpu {
     }
 
     fn op_jp_v0_addr(&mut self, instruction: &Instruction) {
-        self.pc = instruction.nnn() + self.registers[0x0] as u16;
+        let register = if self.quirks.jump_vx { instruction.x() } else { 0x0 };
+        self.pc = instruction.nnn() + self.registers[register] as u16;
     }
 
     fn op_rnd_vx_byte(&mut self, instruction: &Instruction) {
@@ -257,38 +565,45 @@ impl Cpu {
 
     fn op_drw_vx_vy_n(&mut self, instruction: &Instruction) {
         
-        if self.drawn_this_frame {
+        if self.quirks.display_wait && self.drawn_this_frame {
             self.pc -= 2;
             return;
         }
 
         self.drawn_this_frame = true;
 
-        let coord_x = self.registers[instruction.x()] as u16;
-        let coord_y = self.registers[instruction.y()] as u16;
+        let mut coord_x = self.registers[instruction.x()] as u16;
+        let mut coord_y = self.registers[instruction.y()] as u16;
+        if self.quirks.clip {
+            // The starting position wraps, the sprite itself doesn't
+            coord_x %= 64;
+            coord_y %= 32;
+        }
         let height = instruction.n();
 
         self.registers[0xF] = 0;
         
         for yline in 0..height {
 
-            let pixel = self.memory.read_u8(self.i + yline);
+            let pixel = self.memory.read_u8(self.i.wrapping_add(yline));
 
             for xline in 0..8 {
 
                 if pixel & (0x80 >> xline) != 0 {
 
+                    if self.quirks.clip && (coord_x + xline >= 64 || coord_y + yline >= 32) { continue; }
+
                     let x = (coord_x + xline) % 64;
                     
                     let index = (x + (coord_y + yline) * 64) as usize;
 
                     if index > 2047 { continue; }
 
-                    if self.renderer.display[index] > 0 {
+                    if self.display[index] > 0 {
                          self.registers[0xF] = 1;
                     }
 
-                    self.renderer.display[index] ^= 255;
+                    self.display[index] ^= 255;
 
                 }
 
@@ -299,13 +614,13 @@ impl Cpu {
     }
 
     fn op_skp_vx(&mut self, instruction: &Instruction) {
-        if self.renderer.keys[self.registers[instruction.x()] as usize] == 1 {
+        if self.keys[self.registers[instruction.x()] as usize & 0xF] == 1 {
             self.pc += 2; 
         }
     }
 
     fn op_sknp_vx(&mut self, instruction: &Instruction) {
-        if self.renderer.keys[self.registers[instruction.x()] as usize] == 0 {
+        if self.keys[self.registers[instruction.x()] as usize & 0xF] == 0 {
             self.pc += 2;
         }
     }
@@ -320,8 +635,8 @@ impl Cpu {
     }
 
     fn op_ld_vx_k_down(&mut self) {
-        for i in 0..self.renderer.keys.len() {
-            if (self.renderer.last_keys[i] == 0) && (self.renderer.keys[i] == 1) {
+        for i in 0..self.keys.len() {
+            if (self.last_keys[i] == 0) && (self.keys[i] == 1) {
                 self.registers[self.pause.register] = i as u8;
                 self.pause.down_key = i as u8;
                 self.pause.down = true;
@@ -331,7 +646,7 @@ impl Cpu {
     }
 
     fn op_ld_vx_k_released(&mut self) {
-        if self.renderer.keys[self.pause.down_key as usize] == 0 {
+        if self.keys[self.pause.down_key as usize] == 0 {
             self.pause = Pause { active: false, down: false, down_key: 0, released: false, register: 0, }
         }
     }
@@ -345,37 +660,278 @@ impl Cpu {
     } 
 
     fn op_add_i_vx(&mut self, instruction: &Instruction) {
-        self.i += self.registers[instruction.x()] as u16;
+        // I is 12 bits wide
+        self.i = self.i.wrapping_add(self.registers[instruction.x()] as u16) & 0xFFF;
     }
 
     fn op_ld_f_vx(&mut self, instruction: &Instruction) {
-        self.i = (0x50 + self.registers[instruction.x()] * 5) as u16;
+        // Only the low nibble picks a digit
+        self.i = 0x50 + (self.registers[instruction.x()] & 0xF) as u16 * 5;
     }
 
     fn op_ld_b_vx(&mut self, instruction: &Instruction) {
         let mut register: u8 = self.registers[instruction.x()];
         let ones: u8 = register % 10;
-        register = register / 10;
+        register /= 10;
         let tens: u8 = register % 10;
-        register = register / 10;
+        register /= 10;
         let hundreds: u8 = register % 10;
         self.memory.set_u8(self.i, hundreds);
-        self.memory.set_u8(self.i + 1, tens);
-        self.memory.set_u8(self.i + 2, ones);
+        self.memory.set_u8(self.i.wrapping_add(1), tens);
+        self.memory.set_u8(self.i.wrapping_add(2), ones);
     }
     
     fn op_ld_i_vx(&mut self, instruction: &Instruction) {
         for register in 0..(instruction.x() + 1) {
-            self.memory.set_u8(self.i + register as u16, self.registers[register]);
+            self.memory.set_u8(self.i.wrapping_add(register as u16), self.registers[register]);
+        }
+        if self.quirks.memory_increment {
+            self.i = self.i.wrapping_add(instruction.x() as u16 + 1) & 0xFFF;
         }
-        self.i = self.i + instruction.x() as u16 + 1;
     }
 
     fn op_ld_vx_i(&mut self, instruction: &Instruction) {
         for register in 0..(instruction.x() + 1) {
-            self.registers[register] = self.memory.read_u8(self.i + register as u16);
+            self.registers[register] = self.memory.read_u8(self.i.wrapping_add(register as u16));
+        }
+        if self.quirks.memory_increment {
+            self.i = self.i.wrapping_add(instruction.x() as u16 + 1) & 0xFFF;
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn cpu(rom: &[u16]) -> Cpu {
+        let mut options = Options::new();
+        options.seed = Some(0);
+        let mut cpu = Cpu::new(&options);
+        let bytes: Vec<u8> = rom.iter().flat_map(|op| op.to_be_bytes()).collect();
+        cpu.load_rom(&bytes).unwrap();
+        cpu
+    }
+
+    fn error(result: Result<(), Error>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn step_runs_one_instruction() {
+        let mut cpu = cpu(&[0x6A05, 0x7A03, 0x3A08, 0x0000, 0x1200]);
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc(), cpu.register(0xA)), (0x202, Some(5)));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc(), cpu.register(0xA)), (0x208, Some(8)));
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc(), 0x200);
+    }
+
+    #[test]
+    fn step_errors() {
+        assert_eq!(error(cpu(&[0xFFFF]).step()), "Unhandled instruction 0xFFFF at 0x200");
+        assert_eq!(error(cpu(&[0x00EE]).step()), "Stack underflow at 0x200");
+
+        let mut cpu = cpu(&[0x1FFF]);
+        cpu.step().unwrap();
+        assert_eq!(error(cpu.step()), "Program counter out of bounds: 0xFFF");
+    }
+
+    #[test]
+    fn step_waits_for_a_key() {
+        let mut cpu = cpu(&[0xF30A, 0x1202]);
+        cpu.step().unwrap();
+        cpu.end_frame();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc(), 0x202);
+        cpu.set_key(0x7, true);
+        cpu.step().unwrap();
+        cpu.end_frame();
+        cpu.set_key(0x7, false);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc(), cpu.register(3)), (0x202, Some(7)));
+    }
+
+    #[test]
+    fn run_frame_ticks_timers_and_stops_at_errors() {
+        let mut looping = cpu(&[0x6010, 0xF015, 0xF018, 0x1206]);
+        looping.run_frame().unwrap();
+        assert_eq!((looping.dt, looping.st, looping.pc()), (0x10, 0x10, 0x206));
+        looping.run_frame().unwrap();
+        assert_eq!((looping.dt, looping.st), (0x0F, 0x0F));
+        assert!(looping.sound_active());
+
+        let mut failing = cpu(&[0x6001, 0xFFFF, 0x6002]);
+        assert_eq!(error(failing.run_frame()), "Unhandled instruction 0xFFFF at 0x202");
+        assert_eq!(failing.register(0), Some(1));
+    }
+
+    #[test]
+    fn run_frame_draws_one_sprite_with_display_wait() {
+        // Digit 0 twice at the same place, the second draw waits for the next frame
+        let mut cpu = cpu(&[0xF029, 0xD005, 0xD005, 0x1206]);
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.display()[0], 255);
+        assert_eq!(cpu.pc(), 0x204);
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.display()[0], 0);
+        assert_eq!(cpu.register(0xF), Some(1));
+    }
+
+    #[test]
+    fn memory_access_wraps_at_the_end_of_memory() {
+        let mut cpu = cpu(&[
+            0xAFFF, 0x60FF, 0xF01E, // I = 0xFFF + 0xFF
+            0xAFFF, 0xF033,         // BCD of 255 across the end of memory
+            0xAFFE, 0xD01F,         // 15 rows of sprite past the end
+            0xF029,                 // font address of 0xFF
+            0xAFFE, 0xFF55, 0xAFFE, 0xFF65,
+        ]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.i(), 0x0FE);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!([cpu.memory().read_u8(0xFFF), cpu.memory().read_u8(0x000), cpu.memory().read_u8(0x001)], [2, 5, 5]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.i(), 0x50 + 0xF * 5);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.i(), 0x00E);
+        assert_eq!(cpu.register(0), Some(0xFF));
+    }
+
+    #[test]
+    fn out_of_range_keys_and_registers() {
+        let mut cpu = cpu(&[0x1200]);
+        cpu.set_key(0x10, true);
+        cpu.set_register(0x10, 1);
+        assert!(!cpu.key(0x10));
+        assert_eq!(cpu.register(0x10), None);
+    }
+
+    #[test]
+    fn load_rom_copies_to_0x200() {
+        let mut cpu = cpu(&[0x00E0, 0x1202]);
+        assert_eq!(cpu.memory().read_u16(0x202), 0x1202);
+        assert_eq!(cpu.memory().rom_end(), 0x204);
+
+        assert_eq!(cpu.load_rom(&[]).unwrap_err(), "ROM is empty");
+        assert!(cpu.load_rom(&[0; 3585]).is_err());
+        assert_eq!(cpu.memory().rom_end(), 0x204);
+
+        cpu.load_rom(&[0x12; 3584]).unwrap();
+        assert_eq!(cpu.memory().read_u8(0xFFF), 0x12);
+    }
+
+    #[test]
+    fn patch_rom_keeps_runtime_changes() {
+        let old = [0x60, 0x05, 0x12, 0x02];
+        let new = [0x60, 0x06, 0x12, 0x02, 0x00, 0xE0];
+        let mut cpu = cpu(&[0x6005, 0x1202]);
+        cpu.step().unwrap();
+        cpu.memory_mut().set_u8(0x203, 0x00);
+
+        cpu.patch_rom(&old, &new).unwrap();
+        assert_eq!(cpu.memory().read_u16(0x200), 0x6006);
+        // Unchanged in the ROM, so what the program wrote stays
+        assert_eq!(cpu.memory().read_u8(0x203), 0x00);
+        assert_eq!(cpu.memory().read_u16(0x204), 0x00E0);
+        assert_eq!(cpu.memory().rom_end(), 0x206);
+        assert_eq!((cpu.pc(), cpu.register(0)), (0x202, Some(5)));
+
+        assert!(cpu.patch_rom(&new, &[]).is_err());
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut cpu = cpu(&[0x6A05, 0xA300, 0x2208, 0x0000, 0xF029, 0xD005, 0xF30A, 0x00EE]);
+        cpu.set_key(0x3, true);
+        for _ in 0..6 {
+            cpu.step().unwrap();
         }
-        self.i = self.i + instruction.x() as u16 + 1;
+        cpu.st = 7;
+        let state = cpu.save_state();
+        assert_eq!(state.len(), STATE_SIZE);
+
+        let mut restored = super::Cpu::new(&Options::new());
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!((restored.pc(), restored.i(), restored.sp(), restored.st), (0x20E, 0x50, 1, 7));
+        assert_eq!(restored.display(), cpu.display());
+        assert!(restored.key(0x3));
+
+        // Waiting for a key carries over too
+        restored.set_key(0x3, false);
+        restored.set_key(0x9, true);
+        restored.step().unwrap();
+        restored.end_frame();
+        restored.set_key(0x9, false);
+        restored.step().unwrap();
+        restored.step().unwrap();
+        assert_eq!((restored.register(3), restored.pc()), (Some(9), 0x206));
+    }
+
+    #[test]
+    fn load_state_rejects_other_data() {
+        let mut cpu = cpu(&[0x6A05, 0x1202]);
+        cpu.step().unwrap();
+        let before = cpu.save_state();
+
+        let mut state = before.clone();
+        state[0] = b'X';
+        assert_eq!(cpu.load_state(&state).unwrap_err(), "Not a CHIP-8 save state");
+        assert_eq!(cpu.load_state(&before[..STATE_SIZE - 1]).unwrap_err(), "Not a CHIP-8 save state");
+        assert_eq!(cpu.load_state(&[]).unwrap_err(), "Not a CHIP-8 save state");
+
+        let mut state = before.clone();
+        state[4] = 1;
+        assert_eq!(cpu.load_state(&state).unwrap_err(), "Unsupported save state version 1");
+
+        let mut state = before.clone();
+        assert_eq!(u16::from_be_bytes([state[STATE_ROM_LEN], state[STATE_ROM_LEN + 1]]), 4);
+        state[STATE_ROM_LEN..STATE_ROM_LEN + 2].copy_from_slice(&0xFFFFu16.to_be_bytes());
+        assert_eq!(cpu.load_state(&state).unwrap_err(), "Save state ROM length 65535 doesn't fit in memory");
+        state[STATE_ROM_LEN..STATE_ROM_LEN + 2].copy_from_slice(&0xE01u16.to_be_bytes());
+        assert_eq!(cpu.load_state(&state).unwrap_err(), "Save state ROM length 3585 doesn't fit in memory");
+        state[STATE_ROM_LEN..STATE_ROM_LEN + 2].copy_from_slice(&0xE00u16.to_be_bytes());
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.memory().rom_end(), 0x1000);
+        cpu.save_state();
+        cpu.load_state(&before).unwrap();
+
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn reset_clears_input_state() {
+        let mut cpu = cpu(&[0xF30A, 0x1202]);
+        cpu.step().unwrap();
+        cpu.set_key(0x3, true);
+        cpu.end_frame();
+
+        cpu.reset();
+        assert!(!cpu.key(0x3));
+        cpu.load_rom(&[0xF3, 0x0A, 0x12, 0x02]).unwrap();
+        // No longer waiting from before the reset, so Fx0A runs again
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc(), 0x202);
+        // And waits for a fresh press and release
+        cpu.set_key(0x3, true);
+        cpu.step().unwrap();
+        cpu.end_frame();
+        cpu.set_key(0x3, false);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register(3), Some(3));
     }
 
 }
@@ -0,0 +1,727 @@
+extern crate serde_json;
+
+use serde_json::{json, Value};
+
+use super::cpu::Cpu;
+use super::debug_info::LineMap;
+use super::debugger::Debugger;
+use super::disassembler;
+use super::error::Error;
+use super::instruction::Instruction;
+use super::memory;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+const THREAD_ID: u64 = 1;
+
+const REGISTERS_REFERENCE: u64 = 1;
+const TIMERS_REFERENCE: u64 = 2;
+const STACK_REFERENCE: u64 = 3;
+
+// Bigger messages are skipped rather than buffered, no request needs more than a few kB
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+enum Step {
+    // Stop at the next source line (or instruction without a line map), entering calls
+    Into { line: Option<(PathBuf, u32)> },
+    // Same, but calls made from the current function run to completion
+    Over { sp: u8, line: Option<(PathBuf, u32)> },
+    // Run until the current subroutine returns
+    Out { sp: u8 },
+}
+
+// Debug Adapter Protocol server speaking over stdin / stdout. Requests are read on a
+// background thread and handled from the main loop once per frame, like the GDB server.
+pub struct DapServer {
+    requests: Receiver<Value>,
+    seq: u64,
+    line_map: LineMap,
+    // Requested lines per source file, resolved to addresses through the line map
+    source_breakpoints: HashMap<PathBuf, Vec<u32>>,
+    instruction_breakpoints: Vec<u16>,
+    function_breakpoints: Vec<u16>,
+    breakpoints: HashSet<u16>,
+    halted: bool,
+    stop_on_entry: bool,
+    // PC we resumed from, its breakpoint is ignored until execution moves elsewhere
+    resume_pc: Option<u16>,
+    step: Option<Step>,
+    quit: bool,
+}
+
+impl DapServer {
+
+    pub fn new() -> DapServer {
+
+        let (sender, requests) = mpsc::channel();
+
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut reader = stdin.lock();
+            while let Some(message) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+
+        DapServer {
+            requests,
+            seq: 1,
+            line_map: LineMap::new(),
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            function_breakpoints: Vec::new(),
+            breakpoints: HashSet::new(),
+            halted: true,
+            stop_on_entry: false,
+            resume_pc: None,
+            step: None,
+            quit: false,
+        }
+
+    }
+
+    // Handles requests until the client sends 'launch', returning the ROM to run.
+    // Returns None if the client went away first.
+    pub fn wait_for_launch(&mut self) -> Option<String> {
+
+        loop {
+
+            if self.quit {
+                return None;
+            }
+
+            let request = self.requests.recv().ok()?;
+
+            match command(&request) {
+                "initialize" => {
+                    let capabilities = json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsInstructionBreakpoints": true,
+                        "supportsSteppingGranularity": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsDisassembleRequest": true,
+                        "supportsSetVariable": true,
+                        "supportsTerminateRequest": true,
+                    });
+                    self.respond(&request, capabilities);
+                }
+                "launch" => {
+
+                    let arguments = &request["arguments"];
+
+                    let program = match arguments["program"].as_str() {
+                        Some(program) => program.to_string(),
+                        None => {
+                            self.respond_error(&request, "launch requires a 'program'");
+                            continue;
+                        }
+                    };
+
+                    self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+
+                    let line_map_path = match arguments["lineMap"].as_str() {
+                        Some(path) => PathBuf::from(path),
+                        None => LineMap::path_for_rom(&program),
+                    };
+                    if line_map_path.exists() {
+                        match LineMap::load(&line_map_path) {
+                            Ok(line_map) => self.line_map = line_map,
+                            Err(error) => self.output("stderr", &format!("{}\n", error)),
+                        }
+                    }
+
+                    self.respond(&request, Value::Null);
+                    self.event("initialized", Value::Null);
+
+                    return Some(program);
+
+                }
+                "disconnect" | "terminate" => {
+                    self.respond(&request, Value::Null);
+                    return None;
+                }
+                _ => self.respond_error(&request, "not launched yet"),
+            }
+
+        }
+
+    }
+
+    fn handle_request(&mut self, request: &Value, cpu: &mut Cpu) {
+
+        let arguments = &request["arguments"];
+
+        match command(request) {
+            "setBreakpoints" => {
+
+                let path = PathBuf::from(arguments["source"]["path"].as_str().unwrap_or(""));
+                let mut lines: Vec<u32> = Vec::new();
+                let mut results: Vec<Value> = Vec::new();
+
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+                    lines.push(line);
+                    let (found, actual_line) = self.line_map.addresses_for(&path, line);
+                    match (found.first(), actual_line) {
+                        (Some(&address), Some(actual_line)) => {
+                            results.push(json!({
+                                "verified": true,
+                                "line": actual_line,
+                                "instructionReference": format!("0x{:03X}", address),
+                            }));
+                        }
+                        _ => results.push(json!({ "verified": false, "line": line, "message": "No code at this line" })),
+                    }
+                }
+
+                self.source_breakpoints.insert(path, lines);
+                self.update_breakpoints();
+                self.respond(request, json!({ "breakpoints": results }));
+
+            }
+            "setInstructionBreakpoints" => {
+                let mut results: Vec<Value> = Vec::new();
+                self.instruction_breakpoints.clear();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let base = breakpoint["instructionReference"].as_str().and_then(parse_reference);
+                    let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+                    match base.and_then(|base| (base as i64).checked_add(offset)) {
+                        Some(address) if (0..memory::SIZE as i64).contains(&address) => {
+                            self.instruction_breakpoints.push(address as u16);
+                            results.push(json!({ "verified": true, "instructionReference": format!("0x{:03X}", address) }));
+                        }
+                        _ => results.push(json!({ "verified": false, "message": "Invalid address" })),
+                    }
+                }
+                self.update_breakpoints();
+                self.respond(request, json!({ "breakpoints": results }));
+            }
+            "setFunctionBreakpoints" => {
+                let mut results: Vec<Value> = Vec::new();
+                self.function_breakpoints.clear();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let name = breakpoint["name"].as_str().unwrap_or("");
+                    match cpu.symbols.address_of(name).or_else(|| parse_reference(name)) {
+                        Some(address) => {
+                            self.function_breakpoints.push(address);
+                            results.push(json!({ "verified": true, "instructionReference": format!("0x{:03X}", address) }));
+                        }
+                        None => results.push(json!({ "verified": false, "message": "Expected a label or an address like 0x2A4" })),
+                    }
+                }
+                self.update_breakpoints();
+                self.respond(request, json!({ "breakpoints": results }));
+            }
+            "setExceptionBreakpoints" => self.respond(request, json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                self.respond(request, Value::Null);
+                if self.stop_on_entry {
+                    self.stop("entry", None);
+                } else {
+                    self.resume(cpu);
+                }
+            }
+            "threads" => self.respond(request, json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => {
+                let frames = self.stack_frames(cpu);
+                let total = frames.len();
+                self.respond(request, json!({ "stackFrames": frames, "totalFrames": total }));
+            }
+            "scopes" => {
+                let scopes = json!([
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Timers", "variablesReference": TIMERS_REFERENCE, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                ]);
+                self.respond(request, json!({ "scopes": scopes }));
+            }
+            "variables" => {
+                let variables = variables(cpu, arguments["variablesReference"].as_u64().unwrap_or(0));
+                self.respond(request, json!({ "variables": variables }));
+            }
+            "setVariable" => {
+                let name = arguments["name"].as_str().unwrap_or("");
+                let value = arguments["value"].as_str().and_then(parse_reference);
+                match value.and_then(|value| set_variable(cpu, name, value)) {
+                    Some(value) => self.respond(request, json!({ "value": value })),
+                    None => self.respond_error(request, "Can't set that variable"),
+                }
+            }
+            "readMemory" => {
+                let base = arguments["memoryReference"].as_str().and_then(parse_reference);
+                let offset = arguments["offset"].as_i64().unwrap_or(0);
+                let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+                match base.and_then(|base| (base as i64).checked_add(offset)) {
+                    Some(address) if (0..memory::SIZE as i64).contains(&address) => {
+                        let start = address as usize;
+                        let end = start.saturating_add(count).min(memory::SIZE);
+                        self.respond(request, json!({
+                            "address": format!("0x{:03X}", start),
+                            "data": base64(&cpu.memory().data()[start..end]),
+                            "unreadableBytes": count - (end - start),
+                        }));
+                    }
+                    _ => self.respond(request, json!({ "address": "0x0", "unreadableBytes": count })),
+                }
+            }
+            "disassemble" => {
+                let base = arguments["memoryReference"].as_str().and_then(parse_reference).unwrap_or(0) as i64;
+                let offset = arguments["offset"].as_i64().unwrap_or(0);
+                let instruction_offset = arguments["instructionOffset"].as_i64().unwrap_or(0);
+                // More than fits in memory is padded with invalid instructions anyway
+                let count = arguments["instructionCount"].as_i64().unwrap_or(0).clamp(0, memory::SIZE as i64);
+                let start = base.saturating_add(offset).saturating_add(instruction_offset.saturating_mul(2));
+                let instructions: Vec<Value> = (0..count)
+                    .map(|index| self.disassemble_at(cpu, start.saturating_add(index * 2)))
+                    .collect();
+                self.respond(request, json!({ "instructions": instructions }));
+            }
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }));
+                self.resume(cpu);
+            }
+            "next" | "stepIn" | "stepOut" => {
+
+                let instruction = arguments["granularity"].as_str() == Some("instruction") || self.line_map.is_empty();
+                let line = if instruction {
+                    None
+                } else {
+                    self.line_map.line_for(cpu.pc()).map(|(file, line)| (file.to_path_buf(), line))
+                };
+
+                self.respond(request, Value::Null);
+
+                if command(request) == "stepIn" && instruction {
+                    // Single instruction steps don't need to wait for the main loop
+                    match cpu.step() {
+                        Ok(()) => self.stop("step", None),
+                        Err(error) => {
+                            self.report_error(cpu, &error);
+                        }
+                    }
+                    return;
+                }
+
+                self.step = Some(match command(request) {
+                    "next" => Step::Over { sp: cpu.sp(), line },
+                    "stepIn" => Step::Into { line },
+                    _ => Step::Out { sp: cpu.sp() },
+                });
+                self.halted = false;
+                self.resume_pc = Some(cpu.pc());
+
+            }
+            "pause" => {
+                self.respond(request, Value::Null);
+                if !self.halted {
+                    self.stop("pause", None);
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Value::Null);
+                if command(request) == "terminate" {
+                    self.event("terminated", Value::Null);
+                }
+                self.quit = true;
+            }
+            _ => self.respond_error(request, "Unsupported request"),
+        }
+
+    }
+
+    fn update_breakpoints(&mut self) {
+        let lines = self.source_breakpoints
+            .iter()
+            .flat_map(|(path, lines)| lines.iter().flat_map(|&line| self.line_map.addresses_for(path, line).0));
+        self.breakpoints = lines
+            .chain(self.instruction_breakpoints.iter().copied())
+            .chain(self.function_breakpoints.iter().copied())
+            .collect();
+    }
+
+    fn resume(&mut self, cpu: &Cpu) {
+        self.halted = false;
+        self.step = None;
+        self.resume_pc = Some(cpu.pc());
+    }
+
+    fn stop(&mut self, reason: &str, text: Option<&str>) {
+        self.halted = true;
+        self.step = None;
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+            body["description"] = json!(text);
+        }
+        self.event("stopped", body);
+    }
+
+    fn step_finished(&self, cpu: &Cpu) -> bool {
+
+        let location_changed = |line: &Option<(PathBuf, u32)>| match line {
+            Some((file, line)) => self.line_map
+                .line_for(cpu.pc())
+                .is_some_and(|(current_file, current_line)| current_file != file.as_path() || current_line != *line),
+            None => true,
+        };
+
+        match &self.step {
+            Some(Step::Into { line }) => location_changed(line),
+            Some(Step::Over { sp, line }) => cpu.sp() < *sp || (cpu.sp() == *sp && location_changed(line)),
+            Some(Step::Out { sp }) => cpu.sp() < *sp,
+            None => false,
+        }
+
+    }
+
+    fn source(&self, address: u16) -> Option<(Value, u32)> {
+        let (file, line) = self.line_map.line_for(address)?;
+        let name = file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        Some((json!({ "name": name, "path": file.to_string_lossy() }), line))
+    }
+
+    fn stack_frames(&self, cpu: &Cpu) -> Vec<Value> {
+
+        cpu.stack()
+            .frames(cpu.pc())
+            .into_iter()
+            .enumerate()
+            .map(|(index, address)| {
+                let mut frame = json!({
+                    "id": index,
+                    "name": cpu.symbols.name_for(address).unwrap_or_else(|| format!("0x{:03X}", address)),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:03X}", address),
+                });
+                if let Some((source, line)) = self.source(address) {
+                    frame["source"] = source;
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect()
+
+    }
+
+    fn disassemble_at(&self, cpu: &Cpu, address: i64) -> Value {
+
+        if address < 0 || address as usize + 1 >= memory::SIZE {
+            return json!({ "address": format!("0x{:X}", address.max(0)), "instruction": "??", "presentationHint": "invalid" });
+        }
+
+        let address = address as u16;
+        let instruction = Instruction::new(cpu.memory().read_u16(address));
+        let mut result = json!({
+            "address": format!("0x{:03X}", address),
+            "instructionBytes": format!("{:04X}", instruction.raw),
+            "instruction": disassembler::disassemble_with_symbols(&instruction, &cpu.symbols),
+        });
+        if let Some(name) = cpu.symbols.name_for(address).filter(|name| !name.contains('+')) {
+            result["symbol"] = json!(name);
+        }
+        if let Some((source, line)) = self.source(address) {
+            result["location"] = source;
+            result["line"] = json!(line);
+        }
+        result
+
+    }
+
+    fn output(&mut self, category: &str, text: &str) {
+        self.event("output", json!({ "category": category, "output": text }));
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+        });
+        if !body.is_null() {
+            response["body"] = body;
+        }
+        self.send(response);
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, name: &str, body: Value) {
+        let mut event = json!({ "type": "event", "event": name });
+        if !body.is_null() {
+            event["body"] = body;
+        }
+        self.send(event);
+    }
+
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        // The client went away, there's no one left to debug for
+        if write_message(&mut io::stdout().lock(), &message).is_err() {
+            self.quit = true;
+        }
+    }
+
+}
+
+impl Default for DapServer {
+    fn default() -> DapServer {
+        DapServer::new()
+    }
+}
+
+impl Debugger for DapServer {
+
+    fn poll(&mut self, cpu: &mut Cpu) {
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => self.handle_request(&request, cpu),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.quit = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.halted
+    }
+
+    fn check_stop(&mut self, cpu: &Cpu) -> bool {
+
+        let pc = cpu.pc();
+        if self.resume_pc == Some(pc) {
+            return false;
+        }
+        self.resume_pc = None;
+
+        if self.breakpoints.contains(&pc) {
+            self.stop("breakpoint", None);
+            return true;
+        }
+
+        if self.step_finished(cpu) {
+            self.stop("step", None);
+            return true;
+        }
+
+        false
+
+    }
+
+    fn set_line_map(&mut self, line_map: LineMap) {
+        self.line_map = line_map;
+        self.update_breakpoints();
+    }
+
+    fn quit(&self) -> bool {
+        self.quit
+    }
+
+    fn report_error(&mut self, _cpu: &Cpu, error: &Error) -> bool {
+        let text = error.to_string();
+        self.output("stderr", &format!("{}\n", text));
+        self.stop("exception", Some(&text));
+        true
+    }
+
+}
+
+fn command(request: &Value) -> &str {
+    request["command"].as_str().unwrap_or("")
+}
+
+fn read_message(reader: &mut dyn BufRead) -> Option<Value> {
+
+    loop {
+
+        let mut length: Option<usize> = None;
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse().ok();
+            }
+        }
+
+        match length {
+            Some(length) if length > MAX_MESSAGE_SIZE => {
+                eprintln!("Ignoring a {} byte debug adapter message", length);
+                let mut body = io::Read::take(&mut *reader, length as u64);
+                if io::copy(&mut body, &mut io::sink()).ok()? < length as u64 {
+                    return None;
+                }
+            }
+            Some(length) => {
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).ok()?;
+                if let Ok(message) = serde_json::from_slice(&body) {
+                    return Some(message);
+                }
+            }
+            None => {}
+        }
+
+    }
+
+}
+
+fn write_message(out: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+// Accepts "0x2A4", "2A4h" style hex or plain decimal
+fn parse_reference(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if let Some(hex) = text.strip_suffix('h') {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    text.parse().ok()
+}
+
+fn variables(cpu: &Cpu, reference: u64) -> Vec<Value> {
+
+    let byte = |name: String, value: u8| json!({ "name": name, "value": format!("0x{:02X} ({})", value, value), "variablesReference": 0 });
+    let address = |name: &str, value: u16| json!({
+        "name": name,
+        "value": format!("0x{:03X}", value),
+        "variablesReference": 0,
+        "memoryReference": format!("0x{:03X}", value),
+    });
+
+    match reference {
+        REGISTERS_REFERENCE => {
+            let mut variables: Vec<Value> = (0..16).map(|register| byte(format!("V{:X}", register), cpu.register(register).unwrap_or(0))).collect();
+            variables.push(address("I", cpu.i()));
+            variables.push(address("PC", cpu.pc()));
+            variables.push(byte(String::from("SP"), cpu.sp()));
+            variables
+        }
+        TIMERS_REFERENCE => vec![byte(String::from("DT"), cpu.dt), byte(String::from("ST"), cpu.st)],
+        STACK_REFERENCE => cpu.stack()
+            .entries()
+            .iter()
+            .enumerate()
+            .map(|(index, &value)| address(&format!("[{}]", index), value))
+            .collect(),
+        _ => Vec::new(),
+    }
+
+}
+
+fn set_variable(cpu: &mut Cpu, name: &str, value: u16) -> Option<String> {
+    match name {
+        "I" => cpu.set_i(value),
+        "PC" => cpu.set_pc(value),
+        "SP" => cpu.set_sp(u8::try_from(value).ok()?),
+        "DT" => cpu.dt = u8::try_from(value).ok()?,
+        "ST" => cpu.st = u8::try_from(value).ok()?,
+        _ => {
+            let register = usize::from_str_radix(name.strip_prefix('V')?, 16).ok().filter(|&register| register < 16)?;
+            cpu.set_register(register, u8::try_from(value).ok()?);
+        }
+    }
+    Some(format!("0x{:X}", value))
+}
+
+fn base64(data: &[u8]) -> String {
+
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let triple = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[((triple >> (18 - index * 6)) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn framed(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn messages_are_framed() {
+        let mut out: Vec<u8> = Vec::new();
+        write_message(&mut out, &json!({ "seq": 1, "type": "event" })).unwrap();
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), framed(r#"{"seq":1,"type":"event"}"#));
+        let message = read_message(&mut out.as_slice()).unwrap();
+        assert_eq!(message, json!({ "seq": 1, "type": "event" }));
+    }
+
+    #[test]
+    fn bad_messages_are_skipped() {
+        let input = [
+            // Other headers are ignored, as are messages without a length or with invalid JSON
+            String::from("Content-Type: application/vscode-jsonrpc\r\n\r\n"),
+            framed("{ not json"),
+            format!("Content-Type: x\r\n{}", framed(r#"{"seq":2}"#)),
+        ].concat();
+        let mut reader = input.as_bytes();
+        assert_eq!(read_message(&mut reader), Some(json!({ "seq": 2 })));
+        assert_eq!(read_message(&mut reader), None);
+    }
+
+    #[test]
+    fn oversized_messages_are_dropped() {
+        let huge = " ".repeat(MAX_MESSAGE_SIZE + 1);
+        let input = [framed(&huge), framed(r#"{"seq":3}"#)].concat();
+        let mut reader = input.as_bytes();
+        assert_eq!(read_message(&mut reader), Some(json!({ "seq": 3 })));
+        // A length the input can't back ends the stream without allocating it
+        let input = format!("Content-Length: {}\r\n\r\n{{}}", usize::MAX);
+        assert_eq!(read_message(&mut input.as_bytes()), None);
+    }
+
+    #[test]
+    fn truncated_messages_end_the_stream() {
+        let input = framed(r#"{"seq":4}"#);
+        assert_eq!(read_message(&mut &input.as_bytes()[..input.len() - 1]), None);
+    }
+
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Address -> source line map emitted alongside a ROM, e.g. roms/game.ch8 -> roms/game.lines.
+// One entry per line, '#' starts a comment:
+//   ADDR PATH:LINE      e.g. "2A4 game.8o:17"
+// Relative paths are relative to the map file.
+pub struct LineMap {
+    entries: Vec<(u16, PathBuf, u32)>,
+}
+
+impl LineMap {
+
+    pub fn path_for_rom(rom: &str) -> PathBuf {
+        Path::new(rom).with_extension("lines")
+    }
+
+    pub fn new() -> LineMap {
+        LineMap {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn from_entries(mut entries: Vec<(u16, PathBuf, u32)>) -> LineMap {
+        for entry in &mut entries {
+            entry.1 = normalize(&entry.1);
+        }
+        entries.sort_by_key(|entry| entry.0);
+        LineMap {
+            entries,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<LineMap, String> {
+
+        let contents = fs::read_to_string(path).map_err(|error| format!("Failed to read {}: {}", path.display(), error))?;
+        let directory = path.parent().unwrap_or(Path::new("."));
+
+        let mut entries: Vec<(u16, PathBuf, u32)> = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+
+            let line = match line.split_once('#') {
+                Some((line, _)) => line.trim(),
+                None => line.trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            let parsed = line.split_once(char::is_whitespace).and_then(|(address, location)| {
+                let (file, source_line) = location.trim().rsplit_once(':')?;
+                Some((u16::from_str_radix(address, 16).ok()?, file, source_line.parse::<u32>().ok()?))
+            });
+
+            match parsed {
+                Some((address, file, source_line)) => entries.push((address, normalize(&directory.join(file)), source_line)),
+                None => return Err(format!("{}:{}: expected 'ADDR FILE:LINE'", path.display(), number + 1)),
+            }
+
+        }
+
+        entries.sort_by_key(|entry| entry.0);
+
+        Ok(LineMap {
+            entries,
+        })
+
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn line_for(&self, address: u16) -> Option<(&Path, u32)> {
+        let index = self.entries.binary_search_by_key(&address, |entry| entry.0).ok()?;
+        let (_, file, line) = &self.entries[index];
+        Some((file.as_path(), *line))
+    }
+
+    // All addresses generated from the given line, falling back to the next line with code
+    // so breakpoints on blank lines or comments still land somewhere sensible
+    pub fn addresses_for(&self, file: &Path, line: u32) -> (Vec<u16>, Option<u32>) {
+
+        let file = normalize(file);
+        let in_file = self.entries.iter().filter(|entry| entry.1 == file);
+
+        let target = match in_file.clone().map(|entry| entry.2).filter(|&l| l >= line).min() {
+            Some(target) => target,
+            None => return (Vec::new(), None),
+        };
+
+        let addresses = in_file.filter(|entry| entry.2 == target).map(|entry| entry.0).collect();
+        (addresses, Some(target))
+
+    }
+
+}
+
+impl Default for LineMap {
+    fn default() -> LineMap {
+        LineMap::new()
+    }
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,21 @@
+use super::cpu::Cpu;
+use super::debug_info::LineMap;
+use super::error::Error;
+
+// Common interface for the remote debugger frontends (GDB, DAP), driven by the main loop
+pub trait Debugger {
+    // Handle incoming requests, called once per frame
+    fn poll(&mut self, cpu: &mut Cpu);
+    // While halted the main loop doesn't run instructions or tick timers
+    fn halted(&self) -> bool;
+    // Called before every instruction while running, returns true if execution should stop here
+    fn check_stop(&mut self, cpu: &Cpu) -> bool;
+    // Returns true if the error was reported to an attached debugger instead of being fatal
+    fn report_error(&mut self, cpu: &Cpu, error: &Error) -> bool;
+    // Source lines for the program, when it was compiled from source
+    fn set_line_map(&mut self, _line_map: LineMap) {}
+    // True once the client ended the session, the main loop exits on it
+    fn quit(&self) -> bool {
+        false
+    }
+}
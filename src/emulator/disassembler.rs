@@ -0,0 +1,137 @@
+use super::instruction::Instruction;
+use super::symbols::Symbols;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Class {
+    System,
+    Flow,
+    Load,
+    Alu,
+    Memory,
+    Draw,
+    Input,
+    Timer,
+    Random,
+}
+
+impl Class {
+
+    pub fn parse(name: &str) -> Option<Class> {
+        match name {
+            "sys" => Some(Class::System),
+            "flow" => Some(Class::Flow),
+            "load" => Some(Class::Load),
+            "alu" => Some(Class::Alu),
+            "memory" => Some(Class::Memory),
+            "draw" => Some(Class::Draw),
+            "input" => Some(Class::Input),
+            "timer" => Some(Class::Timer),
+            "random" => Some(Class::Random),
+            _ => None,
+        }
+    }
+
+}
+
+pub fn class(instruction: &Instruction) -> Class {
+    match instruction.raw & 0xF000 {
+        0x0000 => match instruction.raw {
+            0x00E0 => Class::Draw,
+            0x00EE => Class::Flow,
+            _ => Class::System,
+        },
+        0x1000 | 0x2000 | 0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xB000 => Class::Flow,
+        0x6000 | 0xA000 => Class::Load,
+        0x7000 => Class::Alu,
+        0x8000 => match instruction.n() {
+            0x0 => Class::Load,
+            _ => Class::Alu,
+        },
+        0xC000 => Class::Random,
+        0xD000 => Class::Draw,
+        0xE000 => Class::Input,
+        _ => match instruction.kk() {
+            0x07 | 0x15 | 0x18 => Class::Timer,
+            0x0A => Class::Input,
+            0x1E => Class::Alu,
+            0x29 => Class::Load,
+            _ => Class::Memory,
+        },
+    }
+}
+
+pub fn disassemble(instruction: &Instruction) -> String {
+
+    let x = instruction.x();
+    let y = instruction.y();
+    let nnn = instruction.nnn();
+    let kk = instruction.kk();
+
+    match instruction.raw & 0xF000 {
+        0x0000 => match instruction.raw {
+            0x00E0 => String::from("CLS"),
+            0x00EE => String::from("RET"),
+            _ => format!("SYS {:03X}", nnn),
+        },
+        0x1000 => format!("JP {:03X}", nnn),
+        0x2000 => format!("CALL {:03X}", nnn),
+        0x3000 => format!("SE V{:X}, {:02X}", x, kk),
+        0x4000 => format!("SNE V{:X}, {:02X}", x, kk),
+        0x5000 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, {:02X}", x, kk),
+        0x7000 => format!("ADD V{:X}, {:02X}", x, kk),
+        0x8000 => match instruction.n() {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("DW {:04X}", instruction.raw),
+        },
+        0x9000 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, {:03X}", nnn),
+        0xB000 => format!("JP V0, {:03X}", nnn),
+        0xC000 => format!("RND V{:X}, {:02X}", x, kk),
+        0xD000 => format!("DRW V{:X}, V{:X}, {:X}", x, y, instruction.n()),
+        0xE000 => match kk {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("DW {:04X}", instruction.raw),
+        },
+        _ => match kk {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => format!("DW {:04X}", instruction.raw),
+        },
+    }
+
+}
+
+// Same as disassemble, with jump, call and I targets shown by symbol name where there is one
+pub fn disassemble_with_symbols(instruction: &Instruction, symbols: &Symbols) -> String {
+
+    let name = match symbols.name_for(instruction.nnn()) {
+        Some(name) => name,
+        None => return disassemble(instruction),
+    };
+
+    match instruction.raw & 0xF000 {
+        0x1000 => format!("JP {}", name),
+        0x2000 => format!("CALL {}", name),
+        0xA000 => format!("LD I, {}", name),
+        0xB000 => format!("JP V0, {}", name),
+        _ => disassemble(instruction),
+    }
+
+}
@@ -0,0 +1,198 @@
+extern crate triple_buffer;
+
+use super::cheats::Cheats;
+use super::cpu::Cpu;
+
+use triple_buffer::{triple_buffer, Output};
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// The latest finished frame, handed to the render thread through a triple buffer so neither
+// side ever waits for the other
+#[derive(Clone)]
+pub struct Frame {
+    pub display: Vec<u8>,
+    pub sound_active: bool,
+    // Total since the thread started, the render thread works out rates from the difference
+    pub instructions: u64,
+    // Frames emulated so far, including ones the program spent stopped at an error but not
+    // paused ones
+    pub frames: u64,
+    // Errors so far and the last one. The program stops at an error until the machine is borrowed.
+    pub errors: u64,
+    pub error: Option<String>,
+}
+
+enum Message {
+    Input { keys: [bool; 0x10], halted: bool },
+    // Runs this many more frames even while halted, for frame advance
+    Advance(u64),
+    // Hands the machine to the render thread until it's sent back
+    Lend,
+    Quit,
+}
+
+// Runs a machine and its cheats at 60 frames per second on a thread of its own, so slow buffer
+// swaps or window drags on the render thread don't stall emulation
+pub struct EmulationThread {
+    messages: Sender<Message>,
+    lent: Receiver<(Cpu, Cheats)>,
+    returned: Sender<(Cpu, Cheats)>,
+    frames: Output<Frame>,
+    // Returns the machine, or nothing if the render thread went away while it was lent out
+    thread: JoinHandle<Option<Cpu>>,
+}
+
+impl EmulationThread {
+
+    pub fn spawn(cpu: Cpu, cheats: Cheats) -> EmulationThread {
+
+        let (messages, message_receiver) = mpsc::channel();
+        let (lent_sender, lent) = mpsc::channel();
+        let (returned, returned_receiver) = mpsc::channel();
+
+        let first = Frame {
+            display: cpu.display().to_vec(),
+            sound_active: false,
+            instructions: 0,
+            frames: 0,
+            errors: 0,
+            error: None,
+        };
+        let (mut frame_input, frames) = triple_buffer(&first);
+
+        let thread = thread::spawn(move || {
+
+            let (mut cpu, mut cheats) = (cpu, cheats);
+            let mut keys = [false; 0x10];
+            let mut halted = true;
+            let mut advance: u64 = 0;
+            let mut instructions: u64 = 0;
+            let mut frames: u64 = 0;
+            let mut errors: u64 = 0;
+            let mut error: Option<String> = None;
+            let mut stopped = false;
+            let mut next_frame = Instant::now();
+
+            loop {
+
+                for message in message_receiver.try_iter() {
+                    match message {
+                        Message::Input { keys: new_keys, halted: new_halted } => {
+                            keys = new_keys;
+                            halted = new_halted;
+                        }
+                        Message::Advance(count) => advance += count,
+                        Message::Lend => {
+                            if lent_sender.send((cpu, cheats)).is_err() {
+                                return None;
+                            }
+                            (cpu, cheats) = match returned_receiver.recv() {
+                                Ok(machine) => machine,
+                                Err(_) => return None,
+                            };
+                            // Borrowing is how programs get reloaded and reset, the error was the old one's
+                            stopped = false;
+                        }
+                        Message::Quit => return Some(cpu),
+                    }
+                }
+
+                cpu.set_keys(&keys);
+
+                let running = !halted || advance > 0;
+                if halted && advance > 0 {
+                    advance -= 1;
+                }
+                if running {
+                    frames += 1;
+                }
+
+                if running && !stopped {
+                    cpu.tick_timers();
+                    cheats.apply(&mut cpu);
+                    for _ in 0..cpu.cycles_per_frame {
+                        if let Err(step_error) = cpu.step() {
+                            if let Some(tracer) = &mut cpu.tracer {
+                                tracer.dump();
+                            }
+                            eprint!("Backtrace:\n{}", cpu.backtrace(step_error.pc()));
+                            eprintln!("{}", step_error);
+                            errors += 1;
+                            error = Some(step_error.to_string());
+                            stopped = true;
+                            break;
+                        }
+                        instructions += 1;
+                    }
+                }
+
+                cpu.end_frame();
+
+                let frame = frame_input.input_buffer();
+                frame.display.copy_from_slice(cpu.display());
+                frame.sound_active = cpu.sound_active() && running && !stopped;
+                frame.instructions = instructions;
+                frame.frames = frames;
+                frame.errors = errors;
+                frame.error = error.clone();
+                frame_input.publish();
+
+                next_frame += Duration::from_secs_f64(1.0 / 60.0);
+                let now = Instant::now();
+                if next_frame > now {
+                    thread::sleep(next_frame - now);
+                } else {
+                    // Too far behind to catch up, e.g. after being lent out for a while
+                    next_frame = now;
+                }
+
+            }
+
+        });
+
+        EmulationThread {
+            messages,
+            lent,
+            returned,
+            frames,
+            thread,
+        }
+
+    }
+
+    // Keys and whether to run, applied from the thread's next frame on
+    pub fn send_input(&self, keys: [bool; 0x10], halted: bool) {
+        let _ = self.messages.send(Message::Input { keys, halted });
+    }
+
+    // Runs the next frames even if halted, then halts again
+    pub fn advance(&self, frames: u64) {
+        let _ = self.messages.send(Message::Advance(frames));
+    }
+
+    pub fn frame(&mut self) -> &Frame {
+        self.frames.read()
+    }
+
+    // Runs f with the machine between two of the thread's frames, waiting for it
+    pub fn with_cpu<T>(&mut self, f: impl FnOnce(&mut Cpu, &mut Cheats) -> T) -> T {
+        self.messages.send(Message::Lend).expect("Emulation thread stopped");
+        let (mut cpu, mut cheats) = self.lent.recv().expect("Emulation thread stopped");
+        let result = f(&mut cpu, &mut cheats);
+        self.returned.send((cpu, cheats)).expect("Emulation thread stopped");
+        result
+    }
+
+    // Stops the thread, handing the machine back
+    pub fn join(self) -> Cpu {
+        let _ = self.messages.send(Message::Quit);
+        match self.thread.join() {
+            Ok(Some(cpu)) => cpu,
+            _ => panic!("Emulation thread stopped"),
+        }
+    }
+
+}
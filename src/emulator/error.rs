@@ -0,0 +1,32 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    PcOutOfBounds { pc: u16 },
+}
+
+impl Error {
+
+    pub fn pc(&self) -> u16 {
+        match self {
+            Error::UnknownOpcode { pc, .. } | Error::StackOverflow { pc } | Error::StackUnderflow { pc } | Error::PcOutOfBounds { pc } => *pc,
+        }
+    }
+
+}
+
+impl fmt::Display for Error {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownOpcode { pc, opcode } => write!(f, "Unhandled instruction {:#06X} at {:#05X}", opcode, pc),
+            Error::StackOverflow { pc } => write!(f, "Stack overflow at {:#05X}", pc),
+            Error::StackUnderflow { pc } => write!(f, "Stack underflow at {:#05X}", pc),
+            Error::PcOutOfBounds { pc } => write!(f, "Program counter out of bounds: {:#05X}", pc),
+        }
+    }
+
+}
@@ -0,0 +1,155 @@
+use super::audio::AudioSink;
+use super::cpu::{Cpu, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::error::Error;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+// The core doesn't draw, beep or read keys itself. Frontends implement these and the main loop
+// (or run() below) moves data between them and the Cpu. AudioSink is the audio gate.
+
+pub trait Display {
+    // One byte per pixel from 0 (off) to 255 (on), row by row
+    fn present(&mut self, pixels: &[u8], width: usize, height: usize);
+}
+
+pub trait InputSource {
+    // Updates the keypad, returns false once the user asked to quit
+    fn read_keys(&mut self, keys: &mut [bool; 0x10]) -> bool;
+}
+
+// Never presses anything and never quits, for frontends without input
+pub struct NoInput;
+
+impl InputSource for NoInput {
+    fn read_keys(&mut self, _keys: &mut [bool; 0x10]) -> bool {
+        true
+    }
+}
+
+// Draws nothing, for runs that only care about sound or the machine's state
+pub struct NoDisplay;
+
+impl Display for NoDisplay {
+    fn present(&mut self, _pixels: &[u8], _width: usize, _height: usize) {}
+}
+
+// Writes frames as binary PGM images. "{}" in the path is replaced by the frame number to keep
+// every frame, otherwise the file is overwritten and ends up holding the last one.
+pub struct ImageSink {
+    path: String,
+    frame: u64,
+    // Set once a write fails, the remaining frames are dropped
+    failed: bool,
+}
+
+impl ImageSink {
+
+    pub fn new(path: &str) -> ImageSink {
+        ImageSink {
+            path: path.to_string(),
+            frame: 0,
+            failed: false,
+        }
+    }
+
+}
+
+impl Display for ImageSink {
+
+    fn present(&mut self, pixels: &[u8], width: usize, height: usize) {
+
+        if self.failed {
+            return;
+        }
+
+        let path = self.path.replace("{}", &format!("{:06}", self.frame));
+        self.frame += 1;
+
+        let mut image = format!("P5\n{} {}\n255\n", width, height).into_bytes();
+        image.extend_from_slice(&pixels[..width * height]);
+
+        if let Err(error) = fs::write(&path, image) {
+            eprintln!("Failed to write {}, not saving any more frames: {}", path, error);
+            self.failed = true;
+        }
+
+    }
+
+}
+
+// Plain emulation loop: runs until the input source quits, frame_limit frames have run or the
+// program hits an error. Realtime runs at 60 frames per second, otherwise as fast as it can.
+pub fn run(cpu: &mut Cpu, display: &mut dyn Display, audio: &mut dyn AudioSink, input: &mut dyn InputSource, frame_limit: Option<u64>, realtime: bool) -> Result<(), Error> {
+
+    let mut keys = [false; 0x10];
+    let mut frame: u64 = 0;
+    let mut audio_failed = false;
+
+    while input.read_keys(&mut keys) {
+
+        if frame_limit.is_some_and(|limit| frame >= limit) {
+            break;
+        }
+
+        cpu.set_keys(&keys);
+
+        if cpu.sound_active() {
+            audio.resume();
+        } else {
+            audio.pause();
+        }
+        cpu.tick_timers();
+
+        for _ in 0..cpu.cycles_per_frame {
+            cpu.step()?;
+        }
+
+        cpu.end_frame();
+        display.present(cpu.display(), DISPLAY_WIDTH, DISPLAY_HEIGHT);
+        if !audio_failed {
+            if let Err(error) = audio.end_frame() {
+                eprintln!("Failed to write audio, continuing without sound: {}", error);
+                audio_failed = true;
+            }
+        }
+
+        frame += 1;
+
+        if realtime {
+            thread::sleep(Duration::from_secs_f64(1.0 / 60.0));
+        }
+
+    }
+
+    Ok(())
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn image_sink() {
+        let path = env::temp_dir().join(format!("chip_8_frame_test_{}_{{}}.pgm", std::process::id())).to_string_lossy().into_owned();
+        let mut sink = ImageSink::new(&path);
+        sink.present(&[0, 255, 255, 0, 7], 2, 2);
+        sink.present(&[255; 4], 2, 2);
+        let first = path.replace("{}", "000000");
+        assert_eq!(fs::read(&first).unwrap(), b"P5\n2 2\n255\n\x00\xFF\xFF\x00");
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(path.replace("{}", "000001")).unwrap();
+
+        // A failed write stops the sink instead of taking the run down with it
+        let mut sink = ImageSink::new("/nonexistent/frame.pgm");
+        sink.present(&[0; 4], 2, 2);
+        assert!(sink.failed);
+        sink.present(&[0; 4], 2, 2);
+        assert_eq!(sink.frame, 1);
+    }
+
+}
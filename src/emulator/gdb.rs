@@ -0,0 +1,544 @@
+use super::cpu::Cpu;
+use super::debugger::Debugger;
+use super::error::Error;
+use super::memory;
+
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// Register layout shared by 'g'/'G', 'p'/'P' and target.xml. 16-bit registers are little endian.
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const REG_DT: usize = 19;
+const REG_ST: usize = 20;
+const REGISTER_COUNT: usize = REG_ST + 1;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\"><feature name=\"org.chip8.core\">",
+    ));
+    for register in 0..16 {
+        xml += &format!("<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\" regnum=\"{}\"/>", register, register);
+    }
+    xml += "<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>";
+    xml += "<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>";
+    xml += "<reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>";
+    xml += "<reg name=\"dt\" bitsize=\"8\" type=\"uint8\"/>";
+    xml += "<reg name=\"st\" bitsize=\"8\" type=\"uint8\"/>";
+    xml += "</feature></target>";
+    xml
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_address_length(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+// write_all() for the nonblocking socket, waiting out a full send buffer instead of failing
+fn write_all(client: &mut TcpStream, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        match client.write(bytes) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => bytes = &bytes[written..],
+            Err(error) if error.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+fn register_bytes(cpu: &Cpu, register: usize) -> Vec<u8> {
+    match register {
+        0..=15 => cpu.register(register).into_iter().collect(),
+        REG_I => cpu.i().to_le_bytes().to_vec(),
+        REG_PC => cpu.pc().to_le_bytes().to_vec(),
+        REG_SP => vec![cpu.sp()],
+        REG_DT => vec![cpu.dt],
+        _ => vec![cpu.st],
+    }
+}
+
+fn register_size(register: usize) -> usize {
+    match register {
+        REG_I | REG_PC => 2,
+        _ => 1,
+    }
+}
+
+fn set_register_bytes(cpu: &mut Cpu, register: usize, bytes: &[u8]) {
+    match register {
+        0..=15 => cpu.set_register(register, bytes[0]),
+        REG_I => cpu.set_i(u16::from_le_bytes([bytes[0], bytes[1]])),
+        REG_PC => cpu.set_pc(u16::from_le_bytes([bytes[0], bytes[1]])),
+        REG_SP => cpu.set_sp(bytes[0]),
+        REG_DT => cpu.dt = bytes[0],
+        _ => cpu.st = bytes[0],
+    }
+}
+
+// GDB remote serial protocol server. Polled once per frame from the main loop, it never blocks:
+// while a debugger is attached and the target is stopped, the main loop simply skips execution.
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+    breakpoints: HashSet<u16>,
+    halted: bool,
+    // PC we resumed from, its breakpoint is ignored until execution moves elsewhere
+    resume_pc: Option<u16>,
+}
+
+impl GdbServer {
+
+    pub fn new(port: u16) -> io::Result<GdbServer> {
+
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+
+        println!("GDB server listening on 127.0.0.1:{}", port);
+
+        Ok(GdbServer {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            breakpoints: HashSet::new(),
+            halted: false,
+            resume_pc: None,
+        })
+
+    }
+
+    fn attached(&self) -> bool {
+        self.client.is_some()
+    }
+
+    fn handle_client(&mut self, cpu: &mut Cpu) {
+
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(error) = stream.set_nonblocking(true).and_then(|_| stream.set_nodelay(true)) {
+                        eprintln!("Failed to set up GDB connection: {}", error);
+                        return;
+                    }
+                    self.client = Some(stream);
+                    self.buffer.clear();
+                    // GDB expects the target to be stopped when it connects
+                    self.halted = true;
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    eprintln!("Failed to accept GDB connection: {}", error);
+                    return;
+                }
+            }
+        }
+
+        let mut chunk = [0u8; 4096];
+        loop {
+            let result = match &mut self.client {
+                Some(client) => client.read(&mut chunk),
+                None => return,
+            };
+            match result {
+                Ok(0) => {
+                    self.disconnect();
+                    return;
+                }
+                Ok(length) => self.buffer.extend_from_slice(&chunk[..length]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.disconnect();
+                    return;
+                }
+            }
+        }
+
+        while let Some(packet) = self.next_packet() {
+            self.handle_packet(&packet, cpu);
+        }
+
+    }
+
+    fn stop(&mut self, signal: u8) {
+        self.halted = true;
+        self.send(&format!("S{:02x}", signal));
+    }
+
+    fn resume(&mut self, cpu: &Cpu) {
+        self.halted = false;
+        self.resume_pc = Some(cpu.pc());
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.breakpoints.clear();
+        self.halted = false;
+        self.resume_pc = None;
+    }
+
+    // Pulls the next complete '$data#cs' packet out of the buffer, acking it as we go
+    fn next_packet(&mut self) -> Option<String> {
+
+        loop {
+
+            let start = self.buffer.iter().position(|&byte| byte == b'$' || byte == 0x03)?;
+
+            if self.buffer[start] == 0x03 {
+                self.buffer.drain(..=start);
+                if !self.halted {
+                    self.stop(SIGINT);
+                }
+                continue;
+            }
+
+            let end = self.buffer[start..].iter().position(|&byte| byte == b'#')? + start;
+            if self.buffer.len() < end + 3 {
+                return None;
+            }
+
+            let data = String::from_utf8_lossy(&self.buffer[start + 1..end]).into_owned();
+            let expected = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            self.buffer.drain(..end + 3);
+
+            if expected == Some(checksum(&data)) {
+                self.write_raw(b"+");
+                return Some(data);
+            }
+            self.write_raw(b"-");
+
+        }
+
+    }
+
+    fn handle_packet(&mut self, packet: &str, cpu: &mut Cpu) {
+
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => {
+                let mut bytes: Vec<u8> = Vec::new();
+                for register in 0..REGISTER_COUNT {
+                    bytes.extend(register_bytes(cpu, register));
+                }
+                to_hex(&bytes)
+            }
+            "G" => match from_hex(arguments) {
+                Some(bytes) => {
+                    let mut offset = 0;
+                    for register in 0..REGISTER_COUNT {
+                        let size = register_size(register);
+                        if offset + size > bytes.len() {
+                            break;
+                        }
+                        set_register_bytes(cpu, register, &bytes[offset..offset + size]);
+                        offset += size;
+                    }
+                    String::from("OK")
+                }
+                None => String::from("E01"),
+            },
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(register) if register < REGISTER_COUNT => to_hex(&register_bytes(cpu, register)),
+                _ => String::from("E01"),
+            },
+            "P" => {
+                let parsed = arguments.split_once('=').and_then(|(register, value)| {
+                    Some((usize::from_str_radix(register, 16).ok()?, from_hex(value)?))
+                });
+                match parsed {
+                    Some((register, bytes)) if register < REGISTER_COUNT && bytes.len() == register_size(register) => {
+                        set_register_bytes(cpu, register, &bytes);
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            "m" => match parse_address_length(arguments) {
+                Some((address, length)) if address < memory::SIZE => match address.checked_add(length) {
+                    Some(end) => to_hex(&cpu.memory().data()[address..end.min(memory::SIZE)]),
+                    None => String::from("E01"),
+                },
+                _ => String::from("E01"),
+            },
+            "M" => {
+                let parsed = arguments.split_once(':').and_then(|(range, data)| {
+                    Some((parse_address_length(range)?, from_hex(data)?))
+                });
+                match parsed {
+                    Some(((address, length), bytes)) if bytes.len() == length && address.checked_add(length).is_some_and(|end| end <= memory::SIZE) => {
+                        for (offset, byte) in bytes.iter().enumerate() {
+                            cpu.memory_mut().set_u8((address + offset) as u16, *byte);
+                        }
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            "Z" | "z" => {
+                let parsed = arguments.split(',').collect::<Vec<&str>>();
+                match (parsed.first(), parsed.get(1).and_then(|address| u16::from_str_radix(address, 16).ok())) {
+                    (Some(&"0"), Some(address)) => {
+                        if command == "Z" {
+                            self.breakpoints.insert(address);
+                        } else {
+                            self.breakpoints.remove(&address);
+                        }
+                        String::from("OK")
+                    }
+                    // Only software breakpoints are supported
+                    _ => String::new(),
+                }
+            }
+            "c" => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    cpu.set_pc(address);
+                }
+                self.resume(cpu);
+                return;
+            }
+            "s" => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    cpu.set_pc(address);
+                }
+                match cpu.step() {
+                    Ok(()) => format!("S{:02x}", SIGTRAP),
+                    Err(error) => {
+                        eprintln!("{}", error);
+                        format!("S{:02x}", SIGILL)
+                    }
+                }
+            }
+            "k" => {
+                self.disconnect();
+                return;
+            }
+            "D" => {
+                self.send("OK");
+                self.disconnect();
+                return;
+            }
+            "H" => String::from("OK"),
+            "q" => self.handle_query(arguments),
+            _ => String::new(),
+        };
+
+        self.send(&reply);
+
+    }
+
+    fn handle_query(&self, query: &str) -> String {
+
+        if query.starts_with("Supported") {
+            return String::from("PacketSize=4000;qXfer:features:read+");
+        }
+
+        if query == "Attached" {
+            return String::from("1");
+        }
+
+        if query == "C" {
+            return String::from("QC1");
+        }
+
+        if query == "fThreadInfo" {
+            return String::from("m1");
+        }
+
+        if query == "sThreadInfo" {
+            return String::from("l");
+        }
+
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_xml();
+            return match parse_address_length(range) {
+                Some((offset, length)) if offset < xml.len() => {
+                    let end = offset.saturating_add(length).min(xml.len());
+                    let marker = if end == xml.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &xml[offset..end])
+                }
+                Some(_) => String::from("l"),
+                None => String::from("E01"),
+            };
+        }
+
+        String::new()
+
+    }
+
+    fn send(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.write_raw(packet.as_bytes());
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) {
+        let failed = match &mut self.client {
+            Some(client) => write_all(client, bytes).is_err(),
+            None => false,
+        };
+        if failed {
+            self.disconnect();
+        }
+    }
+
+}
+
+impl Debugger for GdbServer {
+
+    fn poll(&mut self, cpu: &mut Cpu) {
+        self.handle_client(cpu);
+    }
+
+    fn halted(&self) -> bool {
+        self.halted
+    }
+
+    fn check_stop(&mut self, cpu: &Cpu) -> bool {
+        let pc = cpu.pc();
+        if self.resume_pc == Some(pc) {
+            return false;
+        }
+        self.resume_pc = None;
+        if self.attached() && self.breakpoints.contains(&pc) {
+            self.stop(SIGTRAP);
+            return true;
+        }
+        false
+    }
+
+    fn report_error(&mut self, _cpu: &Cpu, error: &Error) -> bool {
+        if !self.attached() {
+            return false;
+        }
+        eprintln!("{}", error);
+        self.stop(SIGILL);
+        true
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::emulator::options::Options;
+
+    fn connect() -> (GdbServer, TcpStream, Cpu) {
+        let mut server = GdbServer::new(0).unwrap();
+        let client = TcpStream::connect(server.listener.local_addr().unwrap()).unwrap();
+        let mut cpu = Cpu::new(&Options::new());
+        cpu.load_rom(&[0x60, 0x01, 0x12, 0x02]).unwrap();
+        server.poll(&mut cpu);
+        assert!(server.attached());
+        (server, client, cpu)
+    }
+
+    // Sends a packet and returns the data of the reply, after checking the ack and its checksum
+    fn exchange(server: &mut GdbServer, client: &mut TcpStream, cpu: &mut Cpu, packet: &str) -> String {
+        client.write_all(format!("${}#{:02x}", packet, checksum(packet)).as_bytes()).unwrap();
+        server.poll(cpu);
+        let mut reply: Vec<u8> = Vec::new();
+        let mut byte = [0u8];
+        while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+            client.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+        }
+        let reply = String::from_utf8(reply).unwrap();
+        let data = reply.strip_prefix("+$").unwrap().split_once('#').unwrap();
+        assert_eq!(format!("{:02x}", checksum(data.0)), data.1);
+        data.0.to_string()
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum(""), 0);
+        assert_eq!(checksum("OK"), 0x9A);
+        assert_eq!(checksum("\u{FF}"), 0xC3_u8.wrapping_add(0xBF));
+    }
+
+    #[test]
+    fn packets_are_framed() {
+        let mut server = GdbServer::new(0).unwrap();
+        server.buffer.extend_from_slice(b"+$g#00$g#67\x03$m20");
+        assert_eq!(server.next_packet(), Some(String::from("g")));
+        // The interrupt stops the target before the next, incomplete, packet
+        assert_eq!(server.next_packet(), None);
+        assert!(server.halted());
+        server.buffer.extend_from_slice(b"0,2#5d");
+        assert_eq!(server.next_packet(), Some(String::from("m200,2")));
+        assert!(server.buffer.is_empty());
+    }
+
+    #[test]
+    fn memory_is_read_and_written() {
+        let (mut server, mut client, mut cpu) = connect();
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "m200,4"), "60011202");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "mffe,4"), "0000");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "m1000,1"), "E01");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "m200,ffffffffffffffff"), "E01");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "m200"), "E01");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "M300,2:abcd"), "OK");
+        assert_eq!(cpu.memory().read_u16(0x300), 0xABCD);
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "M300,2:ab"), "E01");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "Mfff,2:abcd"), "E01");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "M300,1:zz"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_are_set_and_cleared() {
+        let (mut server, mut client, mut cpu) = connect();
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "Z0,202,2"), "OK");
+        assert!(server.breakpoints.contains(&0x202));
+        // Hardware breakpoints and watchpoints are unsupported
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "Z1,204,2"), "");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "Z0,xyz,2"), "");
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, "z0,202,2"), "OK");
+        assert!(server.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn target_description_is_read_in_chunks() {
+        let (mut server, mut client, mut cpu) = connect();
+        let xml = target_xml();
+        let first = exchange(&mut server, &mut client, &mut cpu, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[..0x10]));
+        let rest = exchange(&mut server, &mut client, &mut cpu, "qXfer:features:read:target.xml:10,ffffffffffffffff");
+        assert_eq!(rest, format!("l{}", &xml[0x10..]));
+        let past = format!("qXfer:features:read:target.xml:{:x},10", xml.len());
+        assert_eq!(exchange(&mut server, &mut client, &mut cpu, &past), "l");
+    }
+
+}
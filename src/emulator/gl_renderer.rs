@@ -0,0 +1,402 @@
+extern crate sdl2;
+extern crate gl;
+
+use gl::types::*;
+
+use super::osd;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+
+pub fn compile_shader(source: &str, shader_type: GLenum) -> Result<GLuint, String> {
+
+    let shader: GLuint;
+    
+    unsafe {
+        shader = gl::CreateShader(shader_type);
+        gl::ShaderSource(shader, 1, &(source.as_ptr() as *const i8), &(source.len() as i32) as *const GLint);
+        gl::CompileShader(shader);
+    }
+
+    let mut success: GLint = 1;
+    unsafe {
+        gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut success);
+    }
+
+    if success == 0 {
+
+        let mut len: GLint = 0;
+        unsafe {
+            gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
+        }
+
+        let error: String = if len > 0 {
+            
+            let mut buffer: [u8; 512] = [0; 512];
+            
+            unsafe {
+                gl::GetShaderInfoLog(shader, 512, std::ptr::null_mut(), buffer.as_mut_ptr() as *mut GLchar);
+            }
+
+            String::from_utf8_lossy(&buffer).into_owned()
+
+        } else {
+            String::new()
+        };
+
+        return Err(format!("Failed to compile shader: {}", error));
+        
+    }
+
+    Ok(shader)
+
+}
+
+pub fn link_shader_program(vertex_shader: GLuint, fragment_shader: GLuint) -> Result<GLuint, String> {
+
+    let program;
+    unsafe {
+        program = gl::CreateProgram();
+        gl::AttachShader(program, vertex_shader);
+        gl::AttachShader(program, fragment_shader);
+        gl::LinkProgram(program);
+    }
+
+    let mut success: GLint = 1;
+    unsafe {
+        gl::GetProgramiv(program, gl::LINK_STATUS, &mut success);
+    }
+
+    if success == 0 {
+
+        let mut len: GLint = 0;
+        unsafe {
+            gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
+        }
+
+        let error: String = if len > 0 {
+            
+            let mut buffer: [u8; 512] = [0; 512];
+            
+            unsafe {
+                gl::GetProgramInfoLog(program, 512, std::ptr::null_mut(), buffer.as_mut_ptr() as *mut GLchar);
+            }
+
+            String::from_utf8_lossy(&buffer).into_owned()
+
+        } else {
+            String::new()
+        };
+
+        return Err(format!("Failed to link program: {}", error));
+
+    }
+    
+    unsafe {
+        gl::DeleteShader(vertex_shader);
+        gl::DeleteShader(fragment_shader);
+    }
+
+    Ok(program)
+
+}
+
+fn create_texture(width: usize, height: usize) -> GLuint {
+
+    let pixels = vec![0u8; width * height];
+    let mut texture: GLuint = 0;
+
+    unsafe {
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+        gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RED as i32, width as i32, height as i32, 0, gl::RED, gl::UNSIGNED_BYTE, pixels.as_ptr() as *const std::os::raw::c_void);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+    }
+
+    texture
+
+}
+
+// A linked shader program drawing on the full window quad, and where its uniforms go (-1 for
+// the ones it doesn't use)
+pub struct Program {
+    id: GLuint,
+    texture: GLint,
+    previous_frame: GLint,
+    palette: GLint,
+    time: GLint,
+    output_size: GLint,
+}
+
+fn create_program(fragment_shader_src: &str) -> Result<Program, String> {
+
+    let vertex_shader = compile_shader(include_str!("shader/vert.glsl"), gl::VERTEX_SHADER)?;
+    let fragment_shader = compile_shader(fragment_shader_src, gl::FRAGMENT_SHADER)?;
+    let id = link_shader_program(vertex_shader, fragment_shader)?;
+
+    unsafe {
+        Ok(Program {
+            id,
+            texture: gl::GetUniformLocation(id, c"textureSampler".as_ptr()),
+            previous_frame: gl::GetUniformLocation(id, c"previousFrame".as_ptr()),
+            palette: gl::GetUniformLocation(id, c"palette".as_ptr()),
+            time: gl::GetUniformLocation(id, c"time".as_ptr()),
+            output_size: gl::GetUniformLocation(id, c"outputSize".as_ptr()),
+        })
+    }
+
+}
+
+// Frames between checks of the shader file's modification time
+const SHADER_CHECK_INTERVAL: u32 = 30;
+
+// OpenGL 3.3 core renderer, the game and the OSD are two textured quads over the whole window
+pub struct GlRenderer {
+    pub window: sdl2::video::Window,
+    // Only kept alive, dropping it destroys the context
+    _gl_context: sdl2::video::GLContext,
+    pub gl_texture: GLuint,
+    pub previous_texture: GLuint,
+    pub gl_vao: GLuint,
+    pub program: Program,
+    pub osd_texture: GLuint,
+    pub osd_program: Program,
+    // Off / on colours, 0.0 to 1.0
+    palette: [GLfloat; 6],
+    width: u32,
+    height: u32,
+    start: Instant,
+    last_frame: (Vec<u8>, usize, usize),
+    // User fragment shader replacing shader/frag.glsl, reloaded when it changes
+    pub shader_path: Option<PathBuf>,
+    shader_modified: Option<SystemTime>,
+    shader_countdown: u32,
+}
+
+impl GlRenderer {
+
+    // Fails on machines without OpenGL 3.3, the caller falls back to the software renderer
+    pub fn new(video: &sdl2::VideoSubsystem, width: u32, height: u32, palette: &[[u8; 3]; 2]) -> Result<GlRenderer, String> {
+
+        let gl_attr = video.gl_attr();
+        gl_attr.set_context_profile(sdl2::video::GLProfile::Core);
+        gl_attr.set_context_version(3, 3);
+
+        let window = video
+            .window("Emulator", width, height)
+            .opengl()
+            .position_centered()
+            .build()
+            .map_err(|error| error.to_string())?;
+
+        let gl_context = window.gl_create_context()?;
+        gl::load_with(|s| video.gl_get_proc_address(s) as *const std::os::raw::c_void);
+        if !gl::GenVertexArrays::is_loaded() {
+            return Err(String::from("OpenGL 3.3 functions are missing"));
+        }
+        video.gl_set_swap_interval(0)?;
+
+        let mut gl_vao: GLuint = 0;
+        let mut gl_vbo: GLuint = 0;
+        let mut gl_ebo: GLuint = 0;
+
+        let quad_vertices: [GLfloat; 16] = [
+            1.0, 1.0, 1.0, 0.0,
+            1.0, -1.0, 1.0, 1.0,
+            -1.0, -1.0, 0.0, 1.0,
+            -1.0, 1.0, 0.0, 0.0,
+        ];
+
+        let quad_indices: [GLuint; 6] = [
+            0, 1, 2, 0, 2, 3,
+        ];
+
+        unsafe {
+            
+            gl::Viewport(0, 0, width as GLsizei, height as GLsizei);
+            gl::ClearColor(0.3, 0.3, 0.3, 1.0); // Set background color
+            
+            gl::GenVertexArrays(1, &mut gl_vao);
+            gl::GenBuffers(1, &mut gl_vbo);
+            gl::GenBuffers(1, &mut gl_ebo);
+            
+            gl::BindVertexArray(gl_vao);
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, gl_vbo);
+            gl::BufferData(
+                gl::ARRAY_BUFFER,
+                (quad_vertices.len() * std::mem::size_of::<GLfloat>()) as GLsizeiptr,
+                &quad_vertices[0] as *const _ as *const GLvoid,
+                gl::STATIC_DRAW,
+            );
+            
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, gl_ebo);
+            gl::BufferData(
+                gl::ELEMENT_ARRAY_BUFFER,
+                (quad_indices.len() * std::mem::size_of::<GLuint>()) as GLsizeiptr,
+                &quad_indices[0] as *const _ as *const GLvoid,
+                gl::STATIC_DRAW,
+            );
+
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 4 * std::mem::size_of::<GLfloat>() as GLsizei, std::ptr::null());
+            gl::EnableVertexAttribArray(0); 
+                                                                                                                                                                                
+            gl::VertexAttribPointer(1, 2, gl::FLOAT, gl::FALSE, 4 * std::mem::size_of::<GLfloat>() as GLsizei, (2 * std::mem::size_of::<GLfloat>()) as *const std::os::raw::c_void);
+            gl::EnableVertexAttribArray(1);
+
+            gl::BindVertexArray(0);
+
+        }
+
+        let gl_texture = create_texture(64, 32);
+        let previous_texture = create_texture(64, 32);
+        let program = create_program(include_str!("shader/frag.glsl"))?;
+
+        let osd_texture = create_texture(osd::WIDTH, osd::HEIGHT);
+        let osd_program = create_program(include_str!("shader/osd_frag.glsl"))?;
+
+        let colours: Vec<GLfloat> = palette.iter().flatten().map(|channel| *channel as GLfloat / 255.0).collect();
+
+        Ok(GlRenderer {
+            window,
+            _gl_context: gl_context,
+            gl_texture,
+            previous_texture,
+            gl_vao,
+            program,
+            osd_texture,
+            osd_program,
+            palette: [colours[0], colours[1], colours[2], colours[3], colours[4], colours[5]],
+            width,
+            height,
+            start: Instant::now(),
+            last_frame: (vec![0; 64 * 32], 64, 32),
+            shader_path: None,
+            shader_modified: None,
+            shader_countdown: SHADER_CHECK_INTERVAL,
+        })
+
+    }
+
+    // Compiles the user shader again, falling back to the built-in one (and returning the compile
+    // log) if it doesn't work
+    pub fn reload_shader(&mut self) -> Result<(), String> {
+
+        let path = match &self.shader_path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        self.shader_modified = modified(&path);
+
+        let program = fs::read_to_string(&path)
+            .map_err(|error| format!("Failed to read {}: {}", path.display(), error))
+            .and_then(|source| create_program(&source).map_err(|error| format!("{}: {}", path.display(), error)));
+
+        let (program, result) = match program {
+            Ok(program) => (program, Ok(())),
+            Err(error) => (create_program(include_str!("shader/frag.glsl"))?, Err(error)),
+        };
+
+        unsafe {
+            gl::DeleteProgram(self.program.id);
+        }
+        self.program = program;
+
+        result
+
+    }
+
+    // Called once per frame, true once for every change to the shader file
+    pub fn shader_changed(&mut self) -> bool {
+
+        let path = match &self.shader_path {
+            Some(path) if self.shader_countdown == 0 => path,
+            Some(_) => {
+                self.shader_countdown -= 1;
+                return false;
+            }
+            None => return false,
+        };
+        self.shader_countdown = SHADER_CHECK_INTERVAL;
+
+        // Editors often delete and recreate the file on save, wait until it's back
+        let modified = modified(path);
+        modified.is_some() && modified != self.shader_modified
+
+    }
+
+    pub fn update_texture(&mut self, pixels: &[u8], width: usize, height: usize) {
+
+        // The frame being replaced becomes previousFrame, for shaders doing ghosting / blending
+        let (previous, previous_width, previous_height) = &self.last_frame;
+        let previous = if (*previous_width, *previous_height) == (width, height) { previous.as_slice() } else { pixels };
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.previous_texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RED as i32, width as i32, height as i32, 0, gl::RED, gl::UNSIGNED_BYTE, previous.as_ptr() as *const std::os::raw::c_void);
+            gl::BindTexture(gl::TEXTURE_2D, self.gl_texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RED as i32, width as i32, height as i32, 0, gl::RED, gl::UNSIGNED_BYTE, pixels.as_ptr() as *const std::os::raw::c_void);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
+
+        self.last_frame = (pixels[..width * height].to_vec(), width, height);
+
+    }
+
+    pub fn render(&mut self, osd_pixels: &[u8]) {
+        
+        unsafe {
+
+            gl::Clear(gl::COLOR_BUFFER_BIT); // Draw background
+            
+            gl::UseProgram(self.program.id);
+
+            gl::ActiveTexture(gl::TEXTURE1);
+            gl::BindTexture(gl::TEXTURE_2D, self.previous_texture);
+            gl::Uniform1i(self.program.previous_frame, 1);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, self.gl_texture);
+            gl::Uniform1i(self.program.texture, 0);
+
+            gl::Uniform3fv(self.program.palette, 2, self.palette.as_ptr());
+            gl::Uniform1f(self.program.time, self.start.elapsed().as_secs_f32());
+            gl::Uniform2f(self.program.output_size, self.width as GLfloat, self.height as GLfloat);
+
+            gl::BindVertexArray(self.gl_vao);
+            gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, std::ptr::null());
+
+            // OSD on top, same quad with its own texture and blending
+            gl::BindTexture(gl::TEXTURE_2D, self.osd_texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RED as i32, osd::WIDTH as i32, osd::HEIGHT as i32, 0, gl::RED, gl::UNSIGNED_BYTE, osd_pixels.as_ptr() as *const std::os::raw::c_void);
+
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+
+            gl::UseProgram(self.osd_program.id);
+            gl::Uniform1i(self.osd_program.texture, 0);
+            gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, std::ptr::null());
+
+            gl::Disable(gl::BLEND);
+            gl::BindVertexArray(0);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::UseProgram(0);
+
+        }
+
+        self.window.gl_swap_window();
+
+    }
+
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
@@ -1,12 +1,17 @@
+pub const SIZE: usize = 0x1000;
+
 pub struct Memory {
-    data: [u8; 0xFFF],
+    data: [u8; SIZE],
+    // Addresses written since the last clear_written(), for the memory viewer
+    written: [bool; SIZE],
+    rom_len: usize,
 }
 
 impl Memory {
 
     pub fn new() -> Memory {
         
-        let mut data: [u8; 0xFFF] = [0; 0xFFF];
+        let mut data: [u8; SIZE] = [0; SIZE];
 
         let font_set: [u8; 80] = [
             0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -27,35 +32,91 @@ impl Memory {
             0xF0, 0x80, 0xF0, 0x80, 0x80  // F
         ];
         
-        for i in 0..80 {
-            data[0x50 + i] = font_set[i];
-        }
+        data[0x50..0x50 + 80].copy_from_slice(&font_set);
 
         Memory {
             data,
+            written: [false; SIZE],
+            rom_len: 0,
         }
 
     }
 
+    // Memory as saved in a save state, nothing counts as written
+    pub fn restore(data: &[u8], rom_len: usize) -> Memory {
+        let mut memory = Memory::new();
+        memory.data.copy_from_slice(data);
+        memory.rom_len = rom_len;
+        memory
+    }
+
+    // Addresses are 12 bits, anything past the end of memory wraps around to the start
+    fn index(address: u16) -> usize {
+        address as usize % SIZE
+    }
+
     pub fn read_u8(&self, address: u16) -> u8 {
-        self.data[address as usize]
+        self.data[Memory::index(address)]
     }
 
     pub fn read_u16(&self, address: u16) -> u16 {
         // Big endian so first byte goes in top 8 bits
-        ((self.data[address as usize] as u16) << 8) | (self.data[(address + 1) as usize]) as u16
+        ((self.read_u8(address) as u16) << 8) | self.read_u8(address.wrapping_add(1)) as u16
     }
 
     pub fn set_u8(&mut self, address: u16, value: u8) {
-        self.data[address as usize] = value;
+        self.data[Memory::index(address)] = value;
+        self.written[Memory::index(address)] = true;
+    }
+
+    // A write from outside the program (cheats, debuggers), not marked as written
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.data[Memory::index(address)] = value;
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // For hosts that poke memory directly, writes aren't tracked
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
     }
 
-    pub fn load_rom(&mut self, data: &Vec<u8>) {
-        if 0x200 + data.len() <= 0xFFF {
-            self.data[0x200..(0x200 + data.len())].copy_from_slice(&data);
+    pub fn rom_end(&self) -> u16 {
+        (0x200 + self.rom_len) as u16
+    }
+
+    pub fn was_written(&self, address: u16) -> bool {
+        self.written[Memory::index(address)]
+    }
+
+    pub fn clear_written(&mut self) {
+        self.written.fill(false);
+    }
+
+    pub fn patch_rom(&mut self, old: &[u8], new: &[u8]) {
+        for (offset, &byte) in new.iter().enumerate() {
+            if old.get(offset) != Some(&byte) {
+                self.set_u8((0x200 + offset) as u16, byte);
+            }
+        }
+        self.rom_len = new.len();
+    }
+
+    pub fn load_rom(&mut self, data: &[u8]) {
+        if 0x200 + data.len() <= SIZE {
+            self.data[0x200..(0x200 + data.len())].copy_from_slice(data);
+            self.rom_len = data.len();
         } else {
             panic!("Failed to road ROM: File too large");
         }
     }
 
 }
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
+}
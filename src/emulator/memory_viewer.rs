@@ -0,0 +1,461 @@
+extern crate sdl2;
+
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use super::cheats::{Cheat, Cheats};
+use super::cpu::Cpu;
+use super::font;
+use super::memory;
+use super::search::{self, Filter, Search};
+
+const SCALE: i32 = 2;
+const MARGIN: i32 = 8;
+const BYTES_PER_ROW: usize = 16;
+const VISIBLE_ROWS: usize = 32;
+const TOTAL_ROWS: usize = memory::SIZE.div_ceil(BYTES_PER_ROW);
+const COLUMNS: i32 = 64;
+const LINES: i32 = VISIBLE_ROWS as i32 + 5;
+
+const FONT_START: u16 = 0x50;
+const FONT_END: u16 = 0xA0;
+
+const TEXT_COLOR: Color = Color::RGB(200, 200, 200);
+const FONT_COLOR: Color = Color::RGB(150, 120, 200);
+const WRITTEN_COLOR: Color = Color::RGB(255, 80, 80);
+const PC_COLOR: Color = Color::RGB(30, 110, 30);
+const I_COLOR: Color = Color::RGB(30, 60, 140);
+const RESULT_COLOR: Color = Color::RGB(110, 30, 110);
+const CURSOR_COLOR: Color = Color::RGB(230, 200, 40);
+
+enum Mode {
+    Edit,
+    Pattern,
+    Value,
+}
+
+pub struct MemoryViewer {
+    canvas: Canvas<Window>,
+    top_row: usize,
+    cursor: u16,
+    pending_nibble: Option<u8>,
+    mode: Mode,
+    pattern: Vec<u8>,
+    pattern_nibble: Option<u8>,
+    value: Option<u8>,
+    search: Option<Search>,
+    results: Vec<u16>,
+    written: Vec<u16>,
+    message: String,
+}
+
+impl MemoryViewer {
+
+    pub fn new(video: &sdl2::VideoSubsystem) -> MemoryViewer {
+
+        let width = (MARGIN * 2 + COLUMNS * font::CHAR_WIDTH * SCALE) as u32;
+        let height = (MARGIN * 2 + LINES * font::LINE_HEIGHT * SCALE) as u32;
+
+        let window = video
+            .window("Memory", width, height)
+            .build()
+            .unwrap();
+
+        // Software renderer so we never touch the main window's GL context
+        let canvas = window.into_canvas().software().build().unwrap();
+
+        MemoryViewer {
+            canvas,
+            top_row: 0x200 / BYTES_PER_ROW,
+            cursor: 0x200,
+            pending_nibble: None,
+            mode: Mode::Edit,
+            pattern: Vec::new(),
+            pattern_nibble: None,
+            value: None,
+            search: None,
+            results: Vec::new(),
+            written: Vec::new(),
+            message: String::new(),
+        }
+
+    }
+
+    // Returns false once the viewer window has been closed
+    // Takes the events the main window's renderer routed to other windows
+    pub fn update(&mut self, cpu: &mut Cpu, cheats: &mut Cheats, events: Vec<Event>) -> bool {
+
+        let window_id = self.canvas.window().id();
+
+        for event in events {
+            if event.get_window_id() != Some(window_id) {
+                continue;
+            }
+            match event {
+                Event::Window { win_event: WindowEvent::Close, .. } => return false,
+                Event::KeyDown { keycode: Some(key), .. } => self.handle_key(key, cpu, cheats),
+                Event::MouseWheel { y, .. } => {
+                    let rows = (self.top_row as i32 - y * 2).clamp(0, (TOTAL_ROWS - VISIBLE_ROWS) as i32);
+                    self.top_row = rows as usize;
+                }
+                _ => (),
+            }
+        }
+
+        self.written = (0..memory::SIZE as u16)
+            .filter(|&address| cpu.memory().was_written(address))
+            .collect();
+        cpu.memory_mut().clear_written();
+
+        self.draw(cpu);
+
+        true
+
+    }
+
+    fn handle_key(&mut self, key: Keycode, cpu: &mut Cpu, cheats: &mut Cheats) {
+
+        match self.mode {
+            Mode::Pattern => match key {
+                Keycode::Return | Keycode::KpEnter => {
+                    self.results = search::find_pattern(cpu.memory(), &self.pattern);
+                    self.message = format!("{} MATCHES", self.results.len());
+                    self.mode = Mode::Edit;
+                    self.next_result();
+                }
+                Keycode::Escape => {
+                    self.message.clear();
+                    self.mode = Mode::Edit;
+                }
+                Keycode::Backspace => {
+                    if self.pattern_nibble.take().is_none() {
+                        self.pattern.pop();
+                    }
+                }
+                _ => if let Some(byte) = hex_digit(key).and_then(|digit| enter_nibble(&mut self.pattern_nibble, digit)) {
+                    self.pattern.push(byte);
+                },
+            },
+            Mode::Value => match key {
+                Keycode::Return | Keycode::KpEnter => {
+                    if let Some(value) = self.value {
+                        if self.search.is_none() {
+                            self.search = Some(Search::new(cpu.memory()));
+                        }
+                        self.filter_search(cpu, Filter::Equal(value));
+                    }
+                    self.mode = Mode::Edit;
+                }
+                Keycode::Escape => self.mode = Mode::Edit,
+                Keycode::Backspace => self.value = None,
+                _ => if let Some(digit) = hex_digit(key) {
+                    self.value = Some((self.value.unwrap_or(0) << 4) | digit);
+                },
+            },
+            Mode::Edit => match key {
+                Keycode::Left => self.move_cursor(-1),
+                Keycode::Right => self.move_cursor(1),
+                Keycode::Up => self.move_cursor(-(BYTES_PER_ROW as i32)),
+                Keycode::Down => self.move_cursor(BYTES_PER_ROW as i32),
+                Keycode::PageUp => self.move_cursor(-((BYTES_PER_ROW * VISIBLE_ROWS) as i32)),
+                Keycode::PageDown => self.move_cursor((BYTES_PER_ROW * VISIBLE_ROWS) as i32),
+                Keycode::Escape => self.pending_nibble = None,
+                Keycode::P => self.set_cursor(cpu.pc()),
+                Keycode::I => self.set_cursor(cpu.i()),
+                Keycode::N => self.next_result(),
+                Keycode::Slash => {
+                    self.pattern.clear();
+                    self.pattern_nibble = None;
+                    self.mode = Mode::Pattern;
+                }
+                Keycode::S => {
+                    self.search = Some(Search::new(cpu.memory()));
+                    self.results.clear();
+                    self.message = String::from("SNAPSHOT TAKEN");
+                }
+                Keycode::X => self.filter_search(cpu, Filter::Changed),
+                Keycode::U => self.filter_search(cpu, Filter::Unchanged),
+                Keycode::Period | Keycode::KpPlus => self.filter_search(cpu, Filter::Increased),
+                Keycode::Comma | Keycode::KpMinus => self.filter_search(cpu, Filter::Decreased),
+                Keycode::Equals => {
+                    self.value = None;
+                    self.mode = Mode::Value;
+                }
+                Keycode::K => {
+                    let cheat = Cheat::Write { address: self.cursor, value: cpu.memory().read_u8(self.cursor) };
+                    let text = cheat.to_string();
+                    self.message = match cheats.add(cheat) {
+                        Ok(()) => format!("CHEAT {} ADDED", text),
+                        Err(error) => {
+                            eprintln!("{}", error);
+                            String::from("FAILED TO SAVE CHEAT")
+                        }
+                    };
+                }
+                _ => if let Some(byte) = hex_digit(key).and_then(|digit| enter_nibble(&mut self.pending_nibble, digit)) {
+                    cpu.memory_mut().set_u8(self.cursor, byte);
+                    self.move_cursor(1);
+                },
+            },
+        }
+
+    }
+
+    fn filter_search(&mut self, cpu: &Cpu, filter: Filter) {
+        match &mut self.search {
+            Some(search) => {
+                search.filter(cpu.memory(), filter);
+                self.results = search.candidates.clone();
+                self.message = format!("{} CANDIDATES", self.results.len());
+            }
+            None => self.message = String::from("PRESS S TO TAKE A SNAPSHOT FIRST"),
+        }
+    }
+
+    fn next_result(&mut self) {
+        if let Some(address) = next_result(&self.results, self.cursor) {
+            self.set_cursor(address);
+        }
+    }
+
+    fn move_cursor(&mut self, offset: i32) {
+        self.set_cursor(offset_address(self.cursor, offset));
+    }
+
+    fn set_cursor(&mut self, address: u16) {
+        self.cursor = address.min(memory::SIZE as u16 - 1);
+        self.pending_nibble = None;
+        self.top_row = scroll_to(self.top_row, self.cursor as usize / BYTES_PER_ROW);
+    }
+
+    fn draw(&mut self, cpu: &Cpu) {
+
+        self.canvas.set_draw_color(Color::RGB(20, 20, 20));
+        self.canvas.clear();
+
+        let memory = cpu.memory();
+        let value = memory.read_u8(self.cursor);
+        let header = format!("PC {:04X}  I {:04X}  CURSOR {:04X} = {:02X}", cpu.pc(), cpu.i(), self.cursor, value);
+        self.draw_text(0, 0, &header, TEXT_COLOR);
+
+        for line in 0..VISIBLE_ROWS {
+
+            let row = self.top_row + line;
+            if row >= TOTAL_ROWS {
+                break;
+            }
+
+            let y = line as i32 + 2;
+            let row_address = (row * BYTES_PER_ROW) as u16;
+            self.draw_text(0, y, &format!("{:04X}:", row_address), TEXT_COLOR);
+
+            for column in 0..BYTES_PER_ROW {
+
+                let address = row_address + column as u16;
+                if address as usize >= memory::SIZE {
+                    break;
+                }
+
+                let x = 6 + column as i32 * 3;
+
+                let background = if in_instruction(address, cpu.pc()) {
+                    Some(PC_COLOR)
+                } else if address == cpu.i() {
+                    Some(I_COLOR)
+                } else if self.results.binary_search(&address).is_ok() {
+                    Some(RESULT_COLOR)
+                } else {
+                    None
+                };
+                if let Some(color) = background {
+                    self.fill_cell(x, y, 2, color);
+                }
+
+                let color = if self.written.binary_search(&address).is_ok() {
+                    WRITTEN_COLOR
+                } else if (FONT_START..FONT_END).contains(&address) {
+                    FONT_COLOR
+                } else {
+                    TEXT_COLOR
+                };
+
+                let text = match self.pending_nibble {
+                    Some(high) if address == self.cursor => format!("{:X}_", high),
+                    _ => format!("{:02X}", memory.read_u8(address)),
+                };
+                self.draw_text(x, y, &text, color);
+
+                if address == self.cursor {
+                    self.outline_cell(x, y, 2, CURSOR_COLOR);
+                }
+
+            }
+
+        }
+
+        let status = match self.mode {
+            Mode::Pattern => {
+                let mut text = String::from("FIND:");
+                for byte in &self.pattern {
+                    text += &format!(" {:02X}", byte);
+                }
+                match self.pattern_nibble {
+                    Some(high) => text += &format!(" {:X}_", high),
+                    None => text += " _",
+                }
+                text
+            }
+            Mode::Value => match self.value {
+                Some(value) => format!("EQUAL TO: {:02X}", value),
+                None => String::from("EQUAL TO: _"),
+            },
+            Mode::Edit => self.message.clone(),
+        };
+        self.draw_text(0, LINES - 3, &status, CURSOR_COLOR);
+        self.draw_text(0, LINES - 2, "HEX POKE / FIND N NEXT P PC I I K KEEP AS CHEAT", TEXT_COLOR);
+        self.draw_text(0, LINES - 1, "S SNAP X CHANGED U SAME . MORE , LESS = EQUAL", TEXT_COLOR);
+
+        self.canvas.present();
+
+    }
+
+    fn cell_rect(x: i32, y: i32, width: i32) -> Rect {
+        let step = font::CHAR_WIDTH * SCALE;
+        let line = font::LINE_HEIGHT * SCALE;
+        Rect::new(
+            MARGIN + x * step - SCALE,
+            MARGIN + y * line - SCALE,
+            (width * step + SCALE) as u32,
+            (font::GLYPH_HEIGHT * SCALE + SCALE * 2) as u32,
+        )
+    }
+
+    fn fill_cell(&mut self, x: i32, y: i32, width: i32, color: Color) {
+        self.canvas.set_draw_color(color);
+        self.canvas.fill_rect(MemoryViewer::cell_rect(x, y, width)).unwrap();
+    }
+
+    fn outline_cell(&mut self, x: i32, y: i32, width: i32, color: Color) {
+        self.canvas.set_draw_color(color);
+        self.canvas.draw_rect(MemoryViewer::cell_rect(x, y, width)).unwrap();
+    }
+
+    fn draw_text(&mut self, x: i32, y: i32, text: &str, color: Color) {
+        let origin_x = MARGIN + x * font::CHAR_WIDTH * SCALE;
+        let origin_y = MARGIN + y * font::LINE_HEIGHT * SCALE;
+        let mut rects: Vec<Rect> = Vec::new();
+        font::for_each_pixel(text, |px, py| {
+            rects.push(Rect::new(origin_x + px * SCALE, origin_y + py * SCALE, SCALE as u32, SCALE as u32));
+        });
+        self.canvas.set_draw_color(color);
+        self.canvas.fill_rects(&rects).unwrap();
+    }
+
+}
+
+// Two digits make a byte, the first one waits in `pending`
+fn enter_nibble(pending: &mut Option<u8>, digit: u8) -> Option<u8> {
+    match pending.take() {
+        Some(high) => Some((high << 4) | digit),
+        None => {
+            *pending = Some(digit);
+            None
+        }
+    }
+}
+
+// The first result after the cursor, wrapping around to the first one
+fn next_result(results: &[u16], cursor: u16) -> Option<u16> {
+    results.iter().find(|&&address| address > cursor).or(results.first()).copied()
+}
+
+// Moves an address, stopping at either end of memory
+fn offset_address(address: u16, offset: i32) -> u16 {
+    (address as i32 + offset).clamp(0, memory::SIZE as i32 - 1) as u16
+}
+
+// The top row after scrolling just enough to show `row`
+fn scroll_to(top_row: usize, row: usize) -> usize {
+    if row < top_row {
+        row
+    } else if row >= top_row + VISIBLE_ROWS {
+        row + 1 - VISIBLE_ROWS
+    } else {
+        top_row
+    }
+}
+
+// Both bytes of the instruction at pc, which wraps around the end of memory like every access
+fn in_instruction(address: u16, pc: u16) -> bool {
+    let pc = pc as usize % memory::SIZE;
+    address as usize == pc || address as usize == (pc + 1) % memory::SIZE
+}
+
+fn hex_digit(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::Num0 | Keycode::Kp0 => Some(0x0),
+        Keycode::Num1 | Keycode::Kp1 => Some(0x1),
+        Keycode::Num2 | Keycode::Kp2 => Some(0x2),
+        Keycode::Num3 | Keycode::Kp3 => Some(0x3),
+        Keycode::Num4 | Keycode::Kp4 => Some(0x4),
+        Keycode::Num5 | Keycode::Kp5 => Some(0x5),
+        Keycode::Num6 | Keycode::Kp6 => Some(0x6),
+        Keycode::Num7 | Keycode::Kp7 => Some(0x7),
+        Keycode::Num8 | Keycode::Kp8 => Some(0x8),
+        Keycode::Num9 | Keycode::Kp9 => Some(0x9),
+        Keycode::A => Some(0xA),
+        Keycode::B => Some(0xB),
+        Keycode::C => Some(0xC),
+        Keycode::D => Some(0xD),
+        Keycode::E => Some(0xE),
+        Keycode::F => Some(0xF),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn hex_entry() {
+        assert_eq!(hex_digit(Keycode::Kp7), Some(0x7));
+        assert_eq!(hex_digit(Keycode::E), Some(0xE));
+        assert_eq!(hex_digit(Keycode::G), None);
+
+        let mut pending = None;
+        assert_eq!(enter_nibble(&mut pending, 0xA), None);
+        assert_eq!(pending, Some(0xA));
+        assert_eq!(enter_nibble(&mut pending, 0x5), Some(0xA5));
+        assert_eq!(pending, None);
+    }
+
+    #[test]
+    fn cursor_movement() {
+        assert_eq!(offset_address(0x010, -0x20), 0x000);
+        assert_eq!(offset_address(0xFF0, 0x20), 0xFFF);
+        assert_eq!(offset_address(0x200, 0x10), 0x210);
+
+        assert_eq!(scroll_to(10, 5), 5);
+        assert_eq!(scroll_to(10, 20), 10);
+        assert_eq!(scroll_to(10, 10 + VISIBLE_ROWS), 11);
+
+        assert_eq!(next_result(&[0x100, 0x300], 0x200), Some(0x300));
+        assert_eq!(next_result(&[0x100, 0x300], 0x300), Some(0x100));
+        assert_eq!(next_result(&[], 0x300), None);
+    }
+
+    #[test]
+    fn instruction_highlight_wraps() {
+        assert!(in_instruction(0x200, 0x200) && in_instruction(0x201, 0x200));
+        assert!(!in_instruction(0x202, 0x200));
+        assert!(in_instruction(0xFFF, 0xFFF) && in_instruction(0x000, 0xFFF));
+        assert!(in_instruction(0xFFF, 0xFFFF) && in_instruction(0x000, 0xFFFF));
+    }
+
+}
@@ -1,8 +1,70 @@
 mod instruction;
 mod memory;
 mod stack;
+#[cfg(feature = "sdl")]
 mod renderer;
+#[cfg(feature = "sdl")]
+mod gl_renderer;
+#[cfg(feature = "sdl")]
+mod software_renderer;
+mod frontend;
+#[cfg(unix)]
+mod terminal;
 mod audio;
+#[cfg(feature = "sdl")]
+mod sdl_audio;
+mod square_wave;
 mod cpu;
+mod emulation_thread;
+mod options;
+#[cfg(feature = "sdl")]
+mod font;
+#[cfg(feature = "sdl")]
+mod osd;
+#[cfg(feature = "sdl")]
+mod search;
+#[cfg(feature = "sdl")]
+mod memory_viewer;
+mod cheats;
+mod error;
+mod disassembler;
+mod trace;
+mod profiler;
+mod gdb;
+mod debugger;
+mod debug_info;
+mod dap;
+mod symbols;
+pub mod rom;
+#[cfg(feature = "sdl")]
+pub mod rom_browser;
 
-pub use self::cpu::Cpu;
+pub use self::cpu::{Cpu, CYCLES_PER_FRAME, DISPLAY_HEIGHT, DISPLAY_WIDTH, STATE_SIZE};
+pub use self::error::Error;
+pub use self::memory::Memory;
+pub use self::stack::Stack;
+#[cfg(feature = "sdl")]
+pub use self::renderer::Renderer;
+pub use self::audio::{AudioBackend, AudioSink, NullAudio, WavAudio};
+pub use self::square_wave::SquareWave;
+#[cfg(feature = "sdl")]
+pub use self::sdl_audio::open as open_audio;
+pub use self::frontend::{Display, ImageSink, InputSource, NoDisplay, NoInput};
+pub use self::frontend::run;
+#[cfg(unix)]
+pub use self::terminal::{Glyphs, TerminalBell, TerminalKeys, TerminalScreen};
+pub use self::emulation_thread::{EmulationThread, Frame};
+pub use self::options::{Options, Quirks};
+#[cfg(feature = "sdl")]
+pub use self::memory_viewer::MemoryViewer;
+pub use self::cheats::{Cheat, Cheats};
+pub use self::disassembler::Class;
+pub use self::trace::{TraceOptions, Tracer};
+pub use self::profiler::ProfileOptions;
+pub use self::gdb::GdbServer;
+pub use self::debugger::Debugger;
+pub use self::dap::DapServer;
+pub use self::debug_info::LineMap;
+pub use self::symbols::Symbols;
+#[cfg(feature = "sdl")]
+pub use self::rom_browser::RomBrowser;
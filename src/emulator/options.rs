@@ -0,0 +1,138 @@
+use super::audio::AudioBackend;
+use super::profiler::ProfileOptions;
+use super::cpu::CYCLES_PER_FRAME;
+use super::rom::Platform;
+
+const PRESETS: [&str; 3] = ["vip", "schip", "xochip"];
+
+// Behaviours that differ between CHIP-8 interpreters, the defaults are the original COSMAC VIP's
+// except for clipping
+#[derive(Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8xy6 / 8xyE shift VY into VX, otherwise VX is shifted in place
+    pub shift_vy: bool,
+    // Fx55 / Fx65 leave I pointing past the last register
+    pub memory_increment: bool,
+    // 8xy1 / 8xy2 / 8xy3 clear VF
+    pub vf_reset: bool,
+    // Bnnn jumps to nnn + VX (x being nnn's top nibble) instead of nnn + V0
+    pub jump_vx: bool,
+    // Sprites are cut off at the edges of the screen instead of wrapping horizontally
+    pub clip: bool,
+    // Only one sprite is drawn per frame, waiting for the vertical blank
+    pub display_wait: bool,
+    // Which interpreter's program size limit applies
+    pub platform: Platform,
+}
+
+impl Quirks {
+
+    pub fn new() -> Quirks {
+        Quirks {
+            shift_vy: true,
+            memory_increment: true,
+            vf_reset: true,
+            jump_vx: false,
+            clip: false,
+            display_wait: true,
+            platform: Platform::Chip8,
+        }
+    }
+
+    // Named sets matching the common interpreters: vip (the default), schip and xochip
+    pub fn preset(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::new()),
+            "schip" => Some(Quirks {
+                shift_vy: false,
+                memory_increment: false,
+                vf_reset: false,
+                jump_vx: true,
+                clip: true,
+                display_wait: false,
+                platform: Platform::Schip,
+            }),
+            "xochip" => Some(Quirks {
+                shift_vy: true,
+                memory_increment: true,
+                vf_reset: false,
+                jump_vx: false,
+                clip: false,
+                display_wait: false,
+                platform: Platform::XoChip,
+            }),
+            _ => None,
+        }
+    }
+
+    // The preset these quirks match, "custom" for any other combination
+    pub fn name(&self) -> &'static str {
+        PRESETS.into_iter().find(|name| Quirks::preset(name).as_ref() == Some(self)).unwrap_or("custom")
+    }
+
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::new()
+    }
+}
+
+pub struct Options {
+    pub audio: AudioBackend,
+    pub profile: Option<ProfileOptions>,
+    // Keep running and playing sound while the window doesn't have focus
+    pub background: bool,
+    // Draw with SDL's software renderer instead of OpenGL
+    pub software_renderer: bool,
+    // Fragment shader to draw the display with instead of the built-in one (OpenGL only)
+    pub shader: Option<String>,
+    // Off / on colours of the display
+    pub palette: [[u8; 3]; 2],
+    pub quirks: Quirks,
+    // Instructions executed per 60Hz frame
+    pub cycles_per_frame: usize,
+    // Seed for CXNN's random numbers, None picks one at random
+    pub seed: Option<u64>,
+}
+
+impl Options {
+
+    pub fn new() -> Options {
+        Options {
+            audio: AudioBackend::Sdl,
+            profile: None,
+            background: false,
+            software_renderer: false,
+            shader: None,
+            palette: [[0, 0, 0], [255, 0, 0]],
+            quirks: Quirks::new(),
+            cycles_per_frame: CYCLES_PER_FRAME,
+            seed: None,
+        }
+    }
+
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn quirk_names() {
+        for name in PRESETS {
+            assert_eq!(Quirks::preset(name).unwrap().name(), name);
+        }
+        let custom = Quirks { clip: true, ..Quirks::new() };
+        assert_eq!(custom.name(), "custom");
+        assert!(Quirks::preset("cosmac").is_none());
+    }
+
+}
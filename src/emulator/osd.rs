@@ -0,0 +1,147 @@
+use super::font;
+
+use std::time::{Duration, Instant};
+
+// Drawn over the whole window, a quarter of its resolution
+pub const WIDTH: usize = 200;
+pub const HEIGHT: usize = 100;
+
+const COLUMNS: usize = WIDTH / font::CHAR_WIDTH as usize;
+
+// Pixel values: 0 lets the game through, TEXT is drawn white and anything else is a black box
+// with that much opacity
+pub const TEXT: u8 = 255;
+const BOX: u8 = 160;
+
+// Notifications stay up for three seconds, only the newest few are shown
+const MESSAGE_FRAMES: u32 = 180;
+const MAX_MESSAGES: usize = 4;
+
+pub struct Osd {
+    pixels: Vec<u8>,
+    messages: Vec<(String, u32)>,
+    frames: u32,
+    instructions: u64,
+    since: Instant,
+    fps: f64,
+    ips: f64,
+    // FPS / IPS and the quirk set in the top left corner, toggled with F1
+    pub show_stats: bool,
+    pub quirks: String,
+    pub paused: bool,
+    // Stays up until cleared, e.g. the emulator hitting an unknown opcode
+    pub error: Option<String>,
+}
+
+impl Osd {
+
+    pub fn new() -> Osd {
+
+        Osd {
+            pixels: vec![0; WIDTH * HEIGHT],
+            messages: Vec::new(),
+            frames: 0,
+            instructions: 0,
+            since: Instant::now(),
+            fps: 0.0,
+            ips: 0.0,
+            show_stats: false,
+            quirks: String::new(),
+            paused: false,
+            error: None,
+        }
+
+    }
+
+    pub fn notify(&mut self, message: &str) {
+        self.messages.push((message.to_string(), MESSAGE_FRAMES));
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+    }
+
+    // Called once per frame with the number of instructions executed during it
+    pub fn end_frame(&mut self, instructions: u32) {
+
+        self.frames += 1;
+        self.instructions += instructions as u64;
+
+        let elapsed = self.since.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.ips = self.instructions as f64 / elapsed.as_secs_f64();
+            self.frames = 0;
+            self.instructions = 0;
+            self.since = Instant::now();
+        }
+
+        for message in &mut self.messages {
+            message.1 -= 1;
+        }
+        self.messages.retain(|message| message.1 > 0);
+
+    }
+
+    // The overlay as WIDTH x HEIGHT pixels, drawn over the game by the renderer
+    pub fn draw(&mut self) -> &[u8] {
+
+        self.pixels.fill(0);
+
+        if self.show_stats {
+            let stats = format!("FPS {:.0}  IPS {:.0}", self.fps, self.ips);
+            self.text(0, 0, &stats);
+            let quirks = format!("QUIRKS {}", self.quirks);
+            self.text(0, font::LINE_HEIGHT as usize, &quirks);
+        }
+
+        if self.paused {
+            let x = WIDTH - 6 * font::CHAR_WIDTH as usize - 2;
+            self.text(x, 0, "PAUSED");
+        }
+
+        if let Some(error) = self.error.clone() {
+            let lines = wrap(&error);
+            let y = (HEIGHT - lines.len() * font::LINE_HEIGHT as usize) / 2;
+            for (index, text) in lines.iter().enumerate() {
+                self.text(0, y + index * font::LINE_HEIGHT as usize, text);
+            }
+        }
+
+        let messages: Vec<String> = self.messages.iter().map(|message| message.0.clone()).collect();
+        let bottom = HEIGHT - messages.len() * (font::LINE_HEIGHT as usize + 1);
+        for (index, message) in messages.iter().enumerate() {
+            self.text(0, bottom + index * (font::LINE_HEIGHT as usize + 1), message);
+        }
+
+        &self.pixels
+
+    }
+
+    // One line of text on a box one pixel larger on each side
+    fn text(&mut self, x: usize, y: usize, text: &str) {
+
+        let text: String = text.chars().take(COLUMNS).collect();
+        let width = text.chars().count() * font::CHAR_WIDTH as usize + 1;
+
+        for row in y..(y + font::LINE_HEIGHT as usize).min(HEIGHT) {
+            let start = row * WIDTH + x;
+            self.pixels[start..start + width.min(WIDTH - x)].fill(BOX);
+        }
+
+        let pixels = &mut self.pixels;
+        font::for_each_pixel(&text, |glyph_x, glyph_y| {
+            let column = x + 1 + glyph_x as usize;
+            let row = y + 1 + glyph_y as usize;
+            if column < WIDTH && row < HEIGHT {
+                pixels[row * WIDTH + column] = TEXT;
+            }
+        });
+
+    }
+
+}
+
+fn wrap(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.chunks(COLUMNS - 1).map(|chunk| chunk.iter().collect()).collect()
+}
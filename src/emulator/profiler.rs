@@ -0,0 +1,218 @@
+use super::disassembler::{self, Class};
+use super::instruction::Instruction;
+use super::memory::{self, Memory};
+use super::symbols::Symbols;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+const CLASSES: [(Class, &str); 9] = [
+    (Class::System, "sys"),
+    (Class::Flow, "flow"),
+    (Class::Load, "load"),
+    (Class::Alu, "alu"),
+    (Class::Memory, "memory"),
+    (Class::Draw, "draw"),
+    (Class::Input, "input"),
+    (Class::Timer, "timer"),
+    (Class::Random, "random"),
+];
+
+pub struct ProfileOptions {
+    // Annotated disassembly with execution counts
+    pub annotated: Option<String>,
+    // Folded stacks, one "main;sub_2A4;sub_300 count" line per call stack, for flamegraph tools
+    pub folded: Option<String>,
+}
+
+pub struct Profiler {
+    annotated: Option<String>,
+    folded: Option<String>,
+    pc_counts: Vec<u64>,
+    class_counts: [u64; CLASSES.len()],
+    call_counts: HashMap<u16, u64>,
+    call_stack: Vec<u16>,
+    stack_counts: HashMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+
+    pub fn new(options: &ProfileOptions) -> Profiler {
+        Profiler {
+            annotated: options.annotated.clone(),
+            folded: options.folded.clone(),
+            pc_counts: vec![0; memory::SIZE],
+            class_counts: [0; CLASSES.len()],
+            call_counts: HashMap::new(),
+            call_stack: Vec::new(),
+            stack_counts: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, pc: u16, instruction: &Instruction) {
+
+        self.pc_counts[pc as usize] += 1;
+
+        let class = disassembler::class(instruction);
+        if let Some(index) = CLASSES.iter().position(|(c, _)| *c == class) {
+            self.class_counts[index] += 1;
+        }
+
+        match self.stack_counts.get_mut(&self.call_stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.stack_counts.insert(self.call_stack.clone(), 1);
+            }
+        }
+
+    }
+
+    pub fn call(&mut self, address: u16) {
+        *self.call_counts.entry(address).or_insert(0) += 1;
+        self.call_stack.push(address);
+    }
+
+    pub fn ret(&mut self) {
+        self.call_stack.pop();
+    }
+
+    // The machine was reset or restored, calls in progress won't return
+    pub fn clear_calls(&mut self) {
+        self.call_stack.clear();
+    }
+
+    // Writes out whichever reports were asked for, called when the emulator exits
+    pub fn finish(&self, memory: &Memory, symbols: &Symbols) {
+
+        if let Some(path) = &self.annotated {
+            match File::create(path) {
+                Ok(file) => if let Err(error) = self.write_annotated(&mut BufWriter::new(file), memory, symbols) {
+                    eprintln!("Failed to write profile {}: {}", path, error);
+                },
+                Err(error) => eprintln!("Failed to create profile {}: {}", path, error),
+            }
+        }
+
+        if let Some(path) = &self.folded {
+            match File::create(path) {
+                Ok(file) => if let Err(error) = self.write_folded(&mut BufWriter::new(file), symbols) {
+                    eprintln!("Failed to write profile {}: {}", path, error);
+                },
+                Err(error) => eprintln!("Failed to create profile {}: {}", path, error),
+            }
+        }
+
+    }
+
+    fn write_annotated(&self, out: &mut dyn Write, memory: &Memory, symbols: &Symbols) -> std::io::Result<()> {
+
+        let total: u64 = self.class_counts.iter().sum();
+
+        writeln!(out, "; {} instructions executed", total)?;
+        writeln!(out, ";")?;
+        writeln!(out, "; By class:")?;
+        for (index, (_, name)) in CLASSES.iter().enumerate() {
+            let count = self.class_counts[index];
+            let percent = if total > 0 { count as f64 * 100.0 / total as f64 } else { 0.0 };
+            writeln!(out, ";   {:<8} {:>10} {:>6.2}%", name, count, percent)?;
+        }
+
+        let mut calls: Vec<(&u16, &u64)> = self.call_counts.iter().collect();
+        calls.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(out, ";")?;
+        writeln!(out, "; Subroutine calls:")?;
+        for (address, count) in calls {
+            writeln!(out, ";   {:03X} {:>10} {}", address, count, symbols.name_for(*address).unwrap_or_default())?;
+        }
+        writeln!(out)?;
+
+        // Never executed instructions are left without a count so gaps in coverage stand out
+        let end = (memory.rom_end() as usize).max(self.last_executed() + 2).min(memory::SIZE - 1);
+        let mut address = 0x200;
+        while address < end {
+            if let Some(name) = symbols.name_for(address as u16).filter(|name| !name.contains('+')) {
+                writeln!(out, "{}:", name)?;
+            }
+            let count = self.pc_counts[address];
+            if count == 0 && self.pc_counts[address + 1] > 0 {
+                writeln!(out, "{:>10}  {:03X}: {:02X}    DB {:02X}", "", address, memory.read_u8(address as u16), memory.read_u8(address as u16))?;
+                address += 1;
+                continue;
+            }
+            let instruction = Instruction::new(memory.read_u16(address as u16));
+            let count = if count > 0 { count.to_string() } else { String::new() };
+            writeln!(out, "{:>10}  {:03X}: {:04X}  {}", count, address, instruction.raw, disassembler::disassemble_with_symbols(&instruction, symbols))?;
+            address += 2;
+        }
+
+        out.flush()
+
+    }
+
+    fn write_folded(&self, out: &mut dyn Write, symbols: &Symbols) -> std::io::Result<()> {
+
+        let mut stacks: Vec<(String, u64)> = self.stack_counts
+            .iter()
+            .map(|(stack, count)| {
+                let mut name = String::from("main");
+                for address in stack {
+                    match symbols.name_for(*address) {
+                        Some(symbol) => name += &format!(";{}", symbol),
+                        None => name += &format!(";sub_{:03X}", address),
+                    }
+                }
+                (name, *count)
+            })
+            .collect();
+        stacks.sort();
+
+        for (name, count) in stacks {
+            writeln!(out, "{} {}", name, count)?;
+        }
+
+        out.flush()
+
+    }
+
+    fn last_executed(&self) -> usize {
+        self.pc_counts.iter().rposition(|&count| count > 0).unwrap_or(0)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn folded(profiler: &Profiler) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        profiler.write_folded(&mut out, &Symbols::new()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn stacks_are_folded() {
+        let mut profiler = Profiler::new(&ProfileOptions { annotated: None, folded: None });
+        profiler.record(0x200, &Instruction::new(0x22A0));
+        profiler.call(0x2A0);
+        profiler.record(0x2A0, &Instruction::new(0x6001));
+        profiler.record(0x2A2, &Instruction::new(0x00EE));
+        profiler.ret();
+        profiler.record(0x202, &Instruction::new(0x1202));
+        assert_eq!(folded(&profiler), "main 2\nmain;sub_2A0 2\n");
+    }
+
+    #[test]
+    fn cleared_calls_leave_no_stale_frames() {
+        let mut profiler = Profiler::new(&ProfileOptions { annotated: None, folded: None });
+        profiler.call(0x2A0);
+        profiler.record(0x2A0, &Instruction::new(0x1200));
+        profiler.clear_calls();
+        profiler.record(0x200, &Instruction::new(0x1200));
+        assert_eq!(folded(&profiler), "main 1\nmain;sub_2A0 1\n");
+    }
+
+}
@@ -0,0 +1,370 @@
+extern crate zip;
+
+use super::memory;
+
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
+
+// Programs are loaded at 0x200 and can use the rest of the emulated memory
+pub const MAX_SIZE: usize = memory::SIZE - 0x200;
+
+// The interpreters the quirk presets are named after, they give programs different amounts of
+// memory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Platform {
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Platform {
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::Schip => "SCHIP",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+
+    // Everything from 0x200 to the end of memory, 4K on CHIP-8 and SCHIP, 64K on XO-CHIP
+    pub fn max_rom_size(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::Schip => 0x1000 - 0x200,
+            Platform::XoChip => 0x10000 - 0x200,
+        }
+    }
+
+}
+
+const ROM_EXTENSIONS: [&str; 5] = ["ch8", "c8", "rom", "bin", "hex"];
+
+// Reads a ROM image from a path:
+//   -                 stdin
+//   foo.zip           the single ROM in the archive, asking which one if there are several
+//   foo.hex / .txt    hex digits as text ("00E0 A22A ..."), or Intel HEX records
+//   anything else     raw bytes
+pub fn read(path: &str) -> Result<Vec<u8>, String> {
+
+    let data = read_data(path)?;
+
+    if data.len() % 2 == 1 {
+        eprintln!("Warning: {} has an odd length ({} bytes), it may be truncated", path, data.len());
+    }
+
+    Ok(data)
+
+}
+
+fn read_data(path: &str) -> Result<Vec<u8>, String> {
+
+    if path == "-" {
+        let mut data: Vec<u8> = Vec::new();
+        io::stdin().read_to_end(&mut data).map_err(|error| format!("Failed to read ROM from stdin: {}", error))?;
+        return Ok(data);
+    }
+
+    if extension(path) == "zip" {
+        return read_zip(path);
+    }
+
+    let data = fs::read(path).map_err(|error| format!("Failed to read ROM {}: {}", path, error))?;
+
+    match extension(path).as_str() {
+        "hex" | "txt" => parse_text(&data).map_err(|error| format!("{}: {}", path, error)),
+        _ => Ok(data),
+    }
+
+}
+
+pub fn validate(data: &[u8], platform: Platform) -> Result<(), String> {
+
+    if data.is_empty() {
+        return Err(String::from("ROM is empty"));
+    }
+
+    if data.len() > platform.max_rom_size() {
+        return Err(format!("ROM is {} bytes, {} programs can be at most {} bytes", data.len(), platform.name(), platform.max_rom_size()));
+    }
+
+    if data.len() > MAX_SIZE {
+        return Err(format!("ROM is {} bytes, only {} bytes fit in the emulated memory", data.len(), MAX_SIZE));
+    }
+
+    Ok(())
+
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path).extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default()
+}
+
+fn read_zip(path: &str) -> Result<Vec<u8>, String> {
+
+    let file = File::open(path).map_err(|error| format!("Failed to open {}: {}", path, error))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|error| format!("Failed to read archive {}: {}", path, error))?;
+
+    let files: Vec<String> = archive.file_names().filter(|name| !name.ends_with('/')).map(String::from).collect();
+
+    let mut candidates: Vec<String> = files.iter().filter(|name| ROM_EXTENSIONS.contains(&extension(name).as_str())).cloned().collect();
+    if candidates.is_empty() {
+        candidates = files;
+    }
+    candidates.sort();
+
+    let name = match candidates.len() {
+        0 => return Err(format!("{} doesn't contain any files", path)),
+        1 => candidates.remove(0),
+        _ => choose(path, &candidates)?,
+    };
+
+    let mut entry = archive.by_name(&name).map_err(|error| format!("Failed to read {} from {}: {}", name, path, error))?;
+    let mut data: Vec<u8> = Vec::new();
+    entry.read_to_end(&mut data).map_err(|error| format!("Failed to read {} from {}: {}", name, path, error))?;
+
+    match extension(&name).as_str() {
+        "hex" | "txt" => parse_text(&data).map_err(|error| format!("{}/{}: {}", path, name, error)),
+        _ => Ok(data),
+    }
+
+}
+
+fn choose(path: &str, names: &[String]) -> Result<String, String> {
+
+    if !io::stdin().is_terminal() {
+        return Err(format!("{} contains several ROMs: {}", path, names.join(", ")));
+    }
+
+    eprintln!("{} contains several ROMs:", path);
+    for (index, name) in names.iter().enumerate() {
+        eprintln!("  {}: {}", index + 1, name);
+    }
+
+    loop {
+        eprint!("Load which one? ");
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).map_err(|error| error.to_string())? == 0 {
+            return Err(String::from("No ROM chosen"));
+        }
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=names.len()).contains(&number) => return Ok(names[number - 1].clone()),
+            _ => eprintln!("Expected a number from 1 to {}", names.len()),
+        }
+    }
+
+}
+
+fn parse_text(data: &[u8]) -> Result<Vec<u8>, String> {
+
+    let text = std::str::from_utf8(data).map_err(|_| String::from("hex file isn't valid text"))?;
+
+    if text.trim_start().starts_with(':') {
+        parse_intel_hex(text)
+    } else {
+        parse_hex_text(text)
+    }
+
+}
+
+// Hex digits separated by anything, '#' or '//' start a comment and 0x prefixes are allowed
+fn parse_hex_text(text: &str) -> Result<Vec<u8>, String> {
+
+    let mut digits: Vec<u8> = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+
+        let line = line.split('#').next().unwrap_or("");
+        let line = line.split("//").next().unwrap_or("");
+
+        for word in line.split(|c: char| c.is_whitespace() || c == ',') {
+            let word = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")).unwrap_or(word);
+            for c in word.chars() {
+                match c.to_digit(16) {
+                    Some(digit) => digits.push(digit as u8),
+                    None => return Err(format!("line {}: '{}' isn't a hex digit", number + 1, c)),
+                }
+            }
+        }
+
+    }
+
+    if digits.len() % 2 == 1 {
+        return Err(String::from("odd number of hex digits"));
+    }
+
+    Ok(digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect())
+
+}
+
+// Records are ":LLAAAATT<data>CC". Addresses may be absolute (starting at 0x200) or relative
+// to the start of the ROM.
+fn parse_intel_hex(text: &str) -> Result<Vec<u8>, String> {
+
+    let mut chunks: Vec<(usize, Vec<u8>)> = Vec::new();
+    let mut base: usize = 0;
+
+    for (number, line) in text.lines().enumerate() {
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |message: &str| format!("line {}: {}", number + 1, message);
+
+        let record = line.strip_prefix(':').ok_or_else(|| error("expected a record starting with ':'"))?;
+        if record.len() < 10 || record.len() % 2 == 1 {
+            return Err(error("record is too short"));
+        }
+        // Keeps the slicing below on character boundaries, and from_str_radix() would take a '+'
+        if !record.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(error("invalid hex digits"));
+        }
+
+        let bytes: Vec<u8> = (0..record.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&record[index..index + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| error("invalid hex digits"))?;
+
+        let length = bytes[0] as usize;
+        if bytes.len() != length + 5 {
+            return Err(error("record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(error("checksum mismatch"));
+        }
+
+        let address = (bytes[1] as usize) << 8 | bytes[2] as usize;
+        let payload = &bytes[4..4 + length];
+
+        match bytes[3] {
+            0x00 => chunks.push((base + address, payload.to_vec())),
+            0x01 => break,
+            0x02 if length == 2 => base = ((payload[0] as usize) << 8 | payload[1] as usize) * 16,
+            0x04 if length == 2 => base = ((payload[0] as usize) << 8 | payload[1] as usize) << 16,
+            // Start addresses don't mean anything here
+            0x03 | 0x05 => {}
+            kind => return Err(error(&format!("unsupported record type {:02X}", kind))),
+        }
+
+    }
+
+    let start = match chunks.iter().map(|chunk| chunk.0).min() {
+        Some(start) if start >= 0x200 => 0x200,
+        Some(_) => 0,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut data: Vec<u8> = Vec::new();
+    for (address, payload) in chunks {
+        let offset = address.checked_sub(start)
+            .ok_or_else(|| format!("data at {:X} is below program memory", address))?;
+        if offset + payload.len() > MAX_SIZE {
+            return Err(format!("data at {:X} is outside of program memory", address));
+        }
+        if data.len() < offset + payload.len() {
+            data.resize(offset + payload.len(), 0);
+        }
+        data[offset..offset + payload.len()].copy_from_slice(&payload);
+    }
+
+    Ok(data)
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::io::Write;
+
+    fn record(address: u16, kind: u8, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+        bytes.push(checksum);
+        format!(":{}\n", bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<String>())
+    }
+
+    fn zip(name: &str, files: &[(&str, &[u8])]) -> String {
+        let path = std::env::temp_dir().join(format!("chip_8_rom_test_{}_{}.zip", std::process::id(), name));
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        for (name, data) in files {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn hex_text() {
+        assert_eq!(parse_text(b"00E0 A22A # clear\n0x12,0X00 // loop\n").unwrap(), vec![0x00, 0xE0, 0xA2, 0x2A, 0x12, 0x00]);
+        assert_eq!(parse_text(b"00E0 A").unwrap_err(), "odd number of hex digits");
+        assert_eq!(parse_text(b"00E0\nA2G2").unwrap_err(), "line 2: 'G' isn't a hex digit");
+    }
+
+    #[test]
+    fn intel_hex_absolute_and_relative() {
+        let absolute = record(0x200, 0x00, &[0x00, 0xE0]) + &record(0x204, 0x00, &[0x12, 0x04]) + &record(0, 0x01, &[]);
+        assert_eq!(parse_text(absolute.as_bytes()).unwrap(), vec![0x00, 0xE0, 0x00, 0x00, 0x12, 0x04]);
+
+        let relative = record(0, 0x00, &[0x00, 0xE0]) + &record(0, 0x01, &[]);
+        assert_eq!(parse_text(relative.as_bytes()).unwrap(), vec![0x00, 0xE0]);
+
+        // Nothing after the end of file record counts
+        let ended = record(0, 0x00, &[0xAA]) + &record(0, 0x01, &[]) + &record(1, 0x00, &[0xBB]);
+        assert_eq!(parse_text(ended.as_bytes()).unwrap(), vec![0xAA]);
+
+        // Segment base 0x20 * 16 puts a record at 0 at 0x200
+        let segment = record(0, 0x02, &[0x00, 0x20]) + &record(0, 0x00, &[0x00, 0xE0]);
+        assert_eq!(parse_text(segment.as_bytes()).unwrap(), vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn intel_hex_errors() {
+        let mut corrupt = record(0x200, 0x00, &[0x00, 0xE0]);
+        corrupt.replace_range(9..11, "01");
+        assert_eq!(parse_text(corrupt.as_bytes()).unwrap_err(), "line 1: checksum mismatch");
+
+        assert_eq!(parse_text(b":0102\n").unwrap_err(), "line 1: record is too short");
+        assert_eq!(parse_text(b":02020000E0\n").unwrap_err(), "line 1: record length doesn't match its byte count");
+        assert_eq!(parse_text(record(0, 0x07, &[]).as_bytes()).unwrap_err(), "line 1: unsupported record type 07");
+        assert_eq!(parse_text(":0000010\u{E9}0\n".as_bytes()).unwrap_err(), "line 1: invalid hex digits");
+        assert_eq!(parse_text(b":+0000001FF\n").unwrap_err(), "line 1: invalid hex digits");
+
+        let outside = record(0xFFF, 0x00, &[0x00, 0xE0]);
+        assert_eq!(parse_text(outside.as_bytes()).unwrap_err(), "data at FFF is outside of program memory");
+    }
+
+    #[test]
+    fn zip_archives() {
+        let single = zip("single", &[("game.ch8", &[0x00, 0xE0])]);
+        assert_eq!(read(&single).unwrap(), vec![0x00, 0xE0]);
+
+        // Files without a ROM extension are ignored when there is one, .hex files are parsed
+        let mixed = zip("mixed", &[("readme.md", b"Instructions"), ("game.hex", b"12 00")]);
+        assert_eq!(read(&mixed).unwrap(), vec![0x12, 0x00]);
+
+        let empty = zip("empty", &[]);
+        assert!(read(&empty).unwrap_err().ends_with("doesn't contain any files"));
+
+        for path in [single, mixed, empty] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn size_limits() {
+        assert_eq!(validate(&[], Platform::Chip8).unwrap_err(), "ROM is empty");
+        assert!(validate(&[0; 3584], Platform::Chip8).is_ok());
+        assert!(validate(&[0; 3584], Platform::Schip).is_ok());
+        assert_eq!(validate(&[0; 3585], Platform::Chip8).unwrap_err(), "ROM is 3585 bytes, CHIP-8 programs can be at most 3584 bytes");
+        assert_eq!(validate(&[0; 3585], Platform::XoChip).unwrap_err(), "ROM is 3585 bytes, only 3584 bytes fit in the emulated memory");
+        assert_eq!(validate(&[0; 0x10000], Platform::XoChip).unwrap_err(), "ROM is 65536 bytes, XO-CHIP programs can be at most 65024 bytes");
+    }
+
+}
@@ -0,0 +1,238 @@
+extern crate sdl2;
+
+use sdl2::keyboard::Keycode;
+
+use super::font;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Drawn into its own buffer, shown in place of the game while the browser is open
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 128;
+
+const COLUMNS: usize = WIDTH / font::CHAR_WIDTH as usize;
+const LINES: usize = HEIGHT / font::LINE_HEIGHT as usize;
+// Title, blank line and help line around the list
+const VISIBLE_ENTRIES: usize = LINES - 3;
+
+const HISTORY_SIZE: usize = 10;
+// Files that can be opened, also picked up by --batch from directories
+pub const EXTENSIONS: [&str; 7] = ["ch8", "c8", "8o", "zip", "hex", "rom", "bin"];
+
+enum Entry {
+    Recent(PathBuf),
+    Directory(PathBuf),
+    Rom(PathBuf),
+}
+
+pub struct RomBrowser {
+    directory: PathBuf,
+    history_path: PathBuf,
+    history: Vec<PathBuf>,
+    entries: Vec<Entry>,
+    selected: usize,
+    top: usize,
+    message: String,
+    pixels: Vec<u8>,
+    pub open: bool,
+}
+
+impl RomBrowser {
+
+    pub fn new(directory: &Path) -> RomBrowser {
+
+        // Recently played ROMs, most recent first
+        let history_path = env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(".chip_8_history");
+        let history = fs::read_to_string(&history_path)
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect();
+
+        RomBrowser {
+            directory: directory.to_path_buf(),
+            history_path,
+            history,
+            entries: Vec::new(),
+            selected: 0,
+            top: 0,
+            message: String::new(),
+            pixels: vec![0; WIDTH * HEIGHT],
+            open: false,
+        }
+
+    }
+
+    pub fn add_to_history(&mut self, rom: &str) {
+
+        if rom == "-" {
+            return;
+        }
+
+        let path = fs::canonicalize(rom).unwrap_or_else(|_| PathBuf::from(rom));
+        self.history.retain(|entry| *entry != path);
+        self.history.insert(0, path);
+        self.history.truncate(HISTORY_SIZE);
+
+        let contents: Vec<String> = self.history.iter().map(|path| path.display().to_string()).collect();
+        if let Err(error) = fs::write(&self.history_path, contents.join("\n") + "\n") {
+            eprintln!("Failed to save ROM history {}: {}", self.history_path.display(), error);
+        }
+
+    }
+
+    // Opens the browser with the error on its bottom line
+    pub fn show_error(&mut self, message: &str) {
+        if !self.open {
+            self.open = true;
+            self.refresh();
+        }
+        self.message = message.to_string();
+    }
+
+    // Handles this frame's key presses, returns the ROM picked by the user if any.
+    // Tab opens / closes the browser, the keys are ignored while it's closed.
+    pub fn update(&mut self, keys: &[Keycode]) -> Option<String> {
+
+        for key in keys {
+
+            if *key == Keycode::Tab || (self.open && *key == Keycode::Escape) {
+                self.open = !self.open && *key == Keycode::Tab;
+                if self.open {
+                    self.refresh();
+                }
+                continue;
+            }
+
+            if !self.open {
+                continue;
+            }
+
+            match key {
+                Keycode::Up => self.selected = self.selected.saturating_sub(1),
+                Keycode::Down => self.selected = (self.selected + 1).min(self.entries.len().saturating_sub(1)),
+                Keycode::PageUp => self.selected = self.selected.saturating_sub(VISIBLE_ENTRIES),
+                Keycode::PageDown => self.selected = (self.selected + VISIBLE_ENTRIES).min(self.entries.len().saturating_sub(1)),
+                Keycode::Backspace => {
+                    if let Some(parent) = self.directory.parent() {
+                        self.directory = parent.to_path_buf();
+                        self.refresh();
+                    }
+                }
+                Keycode::Return => match self.entries.get(self.selected) {
+                    Some(Entry::Directory(path)) => {
+                        self.directory = path.clone();
+                        self.refresh();
+                    }
+                    Some(Entry::Recent(path)) | Some(Entry::Rom(path)) => {
+                        self.open = false;
+                        return Some(path.display().to_string());
+                    }
+                    None => {}
+                },
+                _ => {}
+            }
+
+        }
+
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + VISIBLE_ENTRIES {
+            self.top = self.selected + 1 - VISIBLE_ENTRIES;
+        }
+
+        None
+
+    }
+
+    fn refresh(&mut self) {
+
+        self.directory = fs::canonicalize(&self.directory).unwrap_or_else(|_| self.directory.clone());
+
+        self.entries = self.history.iter().filter(|path| path.exists()).cloned().map(Entry::Recent).collect();
+
+        let mut directories: Vec<PathBuf> = Vec::new();
+        let mut roms: Vec<PathBuf> = Vec::new();
+
+        match fs::read_dir(&self.directory) {
+            Ok(listing) => {
+                for path in listing.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                    let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+                    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+                    if hidden {
+                        continue;
+                    } else if path.is_dir() {
+                        directories.push(path);
+                    } else if EXTENSIONS.contains(&extension.as_str()) {
+                        roms.push(path);
+                    }
+                }
+                self.message.clear();
+            }
+            Err(error) => self.message = format!("Can't read directory: {}", error),
+        }
+
+        directories.sort();
+        roms.sort();
+        self.entries.extend(directories.into_iter().map(Entry::Directory));
+        self.entries.extend(roms.into_iter().map(Entry::Rom));
+
+        self.selected = 0;
+        self.top = 0;
+
+    }
+
+    pub fn draw(&mut self) -> &[u8] {
+
+        self.pixels.fill(0);
+
+        let title = format!("{}", self.directory.display());
+        self.text(0, &title, false);
+
+        for (line, index) in (self.top..self.entries.len()).take(VISIBLE_ENTRIES).enumerate() {
+            let name = |path: &Path| path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            let label = match &self.entries[index] {
+                Entry::Recent(path) => format!("* {}", name(path)),
+                Entry::Directory(path) => format!("  {}/", name(path)),
+                Entry::Rom(path) => format!("  {}", name(path)),
+            };
+            self.text(line + 2, &label, index == self.selected);
+        }
+
+        let help = if self.message.is_empty() {
+            String::from("Enter: load  Backspace: up  Tab: close")
+        } else {
+            self.message.clone()
+        };
+        self.text(LINES - 1, &help, false);
+
+        &self.pixels
+
+    }
+
+    fn text(&mut self, line: usize, text: &str, highlighted: bool) {
+
+        let top = line * font::LINE_HEIGHT as usize;
+
+        // Keep the end of long lines, it's the part that tells paths apart
+        let skip = text.chars().count().saturating_sub(COLUMNS);
+        let text: String = text.chars().skip(skip).collect();
+
+        if highlighted {
+            self.pixels[top * WIDTH..(top + font::LINE_HEIGHT as usize).min(HEIGHT) * WIDTH].fill(255);
+        }
+
+        let pixels = &mut self.pixels;
+        font::for_each_pixel(&text, |x, y| {
+            let index = (top + 1 + y as usize) * WIDTH + x as usize;
+            if index < pixels.len() {
+                pixels[index] = if highlighted { 0 } else { 255 };
+            }
+        });
+
+    }
+
+}
@@ -0,0 +1,75 @@
+extern crate sdl2;
+
+use sdl2::audio::AudioSpecDesired;
+use sdl2::audio::AudioDevice;
+
+use super::audio::{AudioBackend, AudioSink, NullAudio, WavAudio};
+use super::square_wave::SquareWave;
+
+// Only fails for a WAV file that can't be created, a missing audio device means no sound
+pub fn open(backend: &AudioBackend, sdl_context: &sdl2::Sdl, frequency: i32, sample_rate: u16) -> Result<Box<dyn AudioSink>, String> {
+    Ok(match backend {
+        AudioBackend::Sdl => match SdlAudio::new(sdl_context, frequency, sample_rate) {
+            Ok(audio) => Box::new(audio),
+            Err(error) => {
+                eprintln!("Failed to open audio device, continuing without sound: {}", error);
+                Box::new(NullAudio)
+            }
+        },
+        AudioBackend::Null => Box::new(NullAudio),
+        AudioBackend::Wav(path) => match WavAudio::new(path, frequency) {
+            Ok(audio) => Box::new(audio),
+            Err(error) => return Err(format!("Failed to create WAV file {}: {}", path, error)),
+        },
+    })
+}
+
+pub struct SdlAudio {
+    pub device: AudioDevice<SquareWave>,
+}
+
+impl SdlAudio {
+
+    pub fn new(sdl_context: &sdl2::Sdl, frequency: i32, sample_rate: u16) -> Result<SdlAudio, String> {
+
+        let audio_subsystem = sdl_context.audio()?;
+
+        let want = AudioSpecDesired {
+            freq: Some(frequency),
+            channels: Some(1),
+            samples: Some(sample_rate),
+        };
+
+        let device = audio_subsystem.open_playback(None, &want, |spec| {
+            SquareWave::new(spec.freq)
+        })?;
+
+        Ok(SdlAudio {
+            device,
+        })
+
+    }
+
+}
+
+impl AudioSink for SdlAudio {
+
+    fn resume(&mut self) {
+        self.device.resume();
+    }
+
+    fn pause(&mut self) {
+        self.device.pause();
+    }
+
+}
+
+impl sdl2::audio::AudioCallback for SquareWave {
+
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        self.fill(out);
+    }
+
+}
@@ -0,0 +1,111 @@
+use super::memory::{self, Memory};
+
+pub enum Filter {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    Equal(u8),
+}
+
+// Cheat-search style narrowing: start from every address, then keep the ones whose
+// value matches the filter compared to the previous snapshot.
+pub struct Search {
+    snapshot: Vec<u8>,
+    pub candidates: Vec<u16>,
+}
+
+impl Search {
+
+    pub fn new(memory: &Memory) -> Search {
+        Search {
+            snapshot: memory.data().to_vec(),
+            candidates: (0..memory::SIZE as u16).collect(),
+        }
+    }
+
+    pub fn filter(&mut self, memory: &Memory, filter: Filter) {
+        let data = memory.data();
+        self.candidates.retain(|&address| {
+            let old = self.snapshot[address as usize];
+            let new = data[address as usize];
+            match filter {
+                Filter::Changed => new != old,
+                Filter::Unchanged => new == old,
+                Filter::Increased => new > old,
+                Filter::Decreased => new < old,
+                Filter::Equal(value) => new == value,
+            }
+        });
+        self.snapshot = data.to_vec();
+    }
+
+}
+
+pub fn find_pattern(memory: &Memory, pattern: &[u8]) -> Vec<u16> {
+    if pattern.is_empty() {
+        return Vec::new();
+    }
+    memory.data()
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, window)| *window == pattern)
+        .map(|(address, _)| address as u16)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn narrowing() {
+        let mut memory = Memory::new();
+        memory.set_u8(0x300, 5);
+        memory.set_u8(0x301, 5);
+        memory.set_u8(0x302, 5);
+        let mut search = Search::new(&memory);
+        assert_eq!(search.candidates.len(), memory::SIZE);
+
+        memory.set_u8(0x300, 6);
+        memory.set_u8(0x301, 4);
+        search.filter(&memory, Filter::Changed);
+        assert_eq!(search.candidates, vec![0x300, 0x301]);
+
+        // Compared with the snapshot taken by the last filter, not the first one
+        memory.set_u8(0x300, 7);
+        search.filter(&memory, Filter::Increased);
+        assert_eq!(search.candidates, vec![0x300]);
+
+        search.filter(&memory, Filter::Unchanged);
+        assert_eq!(search.candidates, vec![0x300]);
+        search.filter(&memory, Filter::Equal(8));
+        assert!(search.candidates.is_empty());
+    }
+
+    #[test]
+    fn decreased_and_equal() {
+        let mut memory = Memory::new();
+        memory.set_u8(0x400, 3);
+        let mut search = Search::new(&memory);
+        memory.set_u8(0x400, 2);
+        search.filter(&memory, Filter::Decreased);
+        assert_eq!(search.candidates, vec![0x400]);
+
+        let mut search = Search::new(&memory);
+        search.filter(&memory, Filter::Equal(2));
+        assert_eq!(search.candidates, vec![0x400]);
+    }
+
+    #[test]
+    fn patterns() {
+        let mut memory = Memory::new();
+        memory.load_rom(&[0xA2, 0x2A, 0x00, 0xA2, 0x2A]);
+        assert_eq!(find_pattern(&memory, &[0xA2, 0x2A]), vec![0x200, 0x203]);
+        // The font's 0 starts at 0x50
+        assert_eq!(find_pattern(&memory, &[0xF0, 0x90, 0x90, 0x90, 0xF0]), vec![0x50]);
+        assert!(find_pattern(&memory, &[]).is_empty());
+    }
+
+}
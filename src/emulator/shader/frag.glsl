@@ -4,9 +4,10 @@ in vec2 TexCoord;
 out vec4 FragColor;
 
 uniform sampler2D textureSampler;
+uniform vec3 palette[2];
 
 void main() 
 {
-    FragColor = texture(textureSampler, TexCoord);
+    float value = texture(textureSampler, TexCoord).r;
+    FragColor = vec4(mix(palette[0], palette[1], value), 1.0);
 }
-
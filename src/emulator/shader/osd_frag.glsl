@@ -0,0 +1,13 @@
+#version 330 core
+
+in vec2 TexCoord;
+out vec4 FragColor;
+
+uniform sampler2D textureSampler;
+
+void main() 
+{
+    // 1.0 is text, lower values are the translucent box behind it, 0.0 lets the game through
+    float value = texture(textureSampler, TexCoord).r;
+    FragColor = value > 0.99 ? vec4(1.0) : vec4(0.0, 0.0, 0.0, value);
+}
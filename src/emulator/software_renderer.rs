@@ -0,0 +1,99 @@
+extern crate sdl2;
+
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use super::osd;
+
+// For machines without OpenGL 3.3: palette, scaling and the OSD are done on the CPU into an
+// RGB frame the size of the window, streamed to an SDL texture
+pub struct SoftwareRenderer {
+    pub canvas: Canvas<Window>,
+    // Off / on colours, custom shaders aren't supported here
+    palette: [[u8; 3]; 2],
+    source: Vec<u8>,
+    source_width: usize,
+    source_height: usize,
+    frame: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl SoftwareRenderer {
+
+    pub fn new(video: &sdl2::VideoSubsystem, width: u32, height: u32, palette: &[[u8; 3]; 2]) -> Result<SoftwareRenderer, String> {
+
+        let window = video
+            .window("Emulator", width, height)
+            .position_centered()
+            .build()
+            .map_err(|error| error.to_string())?;
+
+        let canvas = window.into_canvas().software().build().map_err(|error| error.to_string())?;
+
+        Ok(SoftwareRenderer {
+            canvas,
+            palette: *palette,
+            source: vec![0; 64 * 32],
+            source_width: 64,
+            source_height: 32,
+            frame: vec![0; width as usize * height as usize * 3],
+            width: width as usize,
+            height: height as usize,
+        })
+
+    }
+
+    pub fn update_texture(&mut self, pixels: &[u8], width: usize, height: usize) {
+        self.source = pixels[..width * height].to_vec();
+        self.source_width = width;
+        self.source_height = height;
+    }
+
+    pub fn render(&mut self, osd_pixels: &[u8]) {
+
+        // Nearest neighbour scaling of both layers to the window
+        for y in 0..self.height {
+
+            let source_row = y * self.source_height / self.height * self.source_width;
+            let osd_row = y * osd::HEIGHT / self.height * osd::WIDTH;
+
+            for x in 0..self.width {
+
+                let value = self.source[source_row + x * self.source_width / self.width];
+                let mut colour = self.palette[(value > 0) as usize];
+
+                let overlay = osd_pixels[osd_row + x * osd::WIDTH / self.width];
+                if overlay == osd::TEXT {
+                    colour = [255, 255, 255];
+                } else if overlay > 0 {
+                    // Black box over the game with the pixel's opacity
+                    let keep = 255 - overlay as u16;
+                    colour = colour.map(|channel| (channel as u16 * keep / 255) as u8);
+                }
+
+                let index = (y * self.width + x) * 3;
+                self.frame[index..index + 3].copy_from_slice(&colour);
+
+            }
+
+        }
+
+        let texture_creator = self.canvas.texture_creator();
+        let texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, self.width as u32, self.height as u32);
+
+        match texture {
+            Ok(mut texture) => {
+                if let Err(error) = texture.update(None, &self.frame, self.width * 3) {
+                    eprintln!("Failed to update texture: {}", error);
+                }
+                let _ = self.canvas.copy(&texture, None, None);
+                self.canvas.present();
+            }
+            Err(error) => eprintln!("Failed to create texture: {}", error),
+        }
+
+    }
+
+}
@@ -0,0 +1,30 @@
+// The beeper's tone, a 440Hz square wave at a low volume. Plain samples, so the WAV writer and
+// the libretro core can use it without an audio device.
+pub struct SquareWave {
+    phase_inc: f32,
+    phase: f32,
+    volume: f32,
+}
+
+impl SquareWave {
+
+    pub fn new(frequency: i32) -> SquareWave {
+        SquareWave {
+            phase_inc: 440.0 / frequency as f32,
+            phase: 0.0,
+            volume: 0.1,
+        }
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            *x = if self.phase <= 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+        }
+    }
+
+}
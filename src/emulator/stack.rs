@@ -12,14 +12,48 @@ impl Stack {
         }
     }
 
-    pub fn push(&mut self, value: u16) {
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    // Return addresses currently on the stack, innermost last
+    pub fn entries(&self) -> &[u16] {
+        &self.data[..self.sp as usize]
+    }
+
+    // The given PC followed by the CALL behind each return address, innermost first
+    pub fn frames(&self, pc: u16) -> Vec<u16> {
+        let mut frames = vec![pc];
+        frames.extend(self.entries().iter().rev().map(|address| address.wrapping_sub(2)));
+        frames
+    }
+
+    pub fn set_sp(&mut self, sp: u8) {
+        self.sp = sp.min(self.data.len() as u8);
+    }
+
+    // Overflow / underflow are reported to the caller instead of panicking
+    pub fn push(&mut self, value: u16) -> bool {
+        if self.sp as usize >= self.data.len() {
+            return false;
+        }
         self.data[self.sp as usize] = value;
         self.sp += 1;
+        true
     }
 
-    pub fn pop(&mut self) -> u16 {
+    pub fn pop(&mut self) -> Option<u16> {
+        if self.sp == 0 {
+            return None;
+        }
         self.sp -= 1;
-        self.data[self.sp as usize]
+        Some(self.data[self.sp as usize])
     }
 
 }
+
+impl Default for Stack {
+    fn default() -> Stack {
+        Stack::new()
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Label names for addresses, from a symbol file next to the ROM (roms/game.ch8 -> roms/game.sym)
+// or the labels of a compiled .8o. One label per line, '#' starts a comment:
+//   ADDR NAME       e.g. "2A4 main_loop" or "0x2A4 main_loop"
+//   NAME ADDR       Octo style label dumps, "main_loop 0x2A4" or "main_loop = 0x2A4", the address
+//                   needs the 0x unless there's an '='
+pub struct Symbols {
+    entries: Vec<(u16, String)>,
+}
+
+impl Symbols {
+
+    pub fn path_for_rom(rom: &str) -> PathBuf {
+        Path::new(rom).with_extension("sym")
+    }
+
+    pub fn new() -> Symbols {
+        Symbols {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn from_labels(labels: Vec<(String, u16)>) -> Symbols {
+        let mut entries: Vec<(u16, String)> = labels.into_iter().map(|(name, address)| (address, name)).collect();
+        entries.sort_by_key(|entry| entry.0);
+        Symbols {
+            entries,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Symbols, String> {
+
+        let contents = fs::read_to_string(path).map_err(|error| format!("Failed to read {}: {}", path.display(), error))?;
+
+        let mut labels: Vec<(String, u16)> = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+
+            let line = match line.split_once('#') {
+                Some((line, _)) => line.trim(),
+                None => line.trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            // The layout decides which field is the address, so a hex looking name like "add" stays a name
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let columns = match line.split_once('=') {
+                Some((name, address)) => Some((name.trim(), address.trim())),
+                None => match fields[..] {
+                    [name, address] if address.starts_with("0x") || address.starts_with("0X") => Some((name, address)),
+                    [address, name] => Some((name, address)),
+                    _ => None,
+                },
+            };
+
+            let parsed = columns
+                .filter(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+                .and_then(|(name, address)| Some((name, parse_address(address)?)));
+
+            match parsed {
+                Some((name, address)) => labels.push((name.to_string(), address)),
+                None => return Err(format!("{}:{}: expected 'ADDR NAME'", path.display(), number + 1)),
+            }
+
+        }
+
+        Ok(Symbols::from_labels(labels))
+
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // "main_loop" for a labelled address, "main_loop+4" past one, None before the first label
+    pub fn name_for(&self, address: u16) -> Option<String> {
+
+        let index = self.entries.partition_point(|entry| entry.0 <= address);
+        let label = self.entries[..index].last()?.0;
+        let (label, name) = &self.entries[self.entries.partition_point(|entry| entry.0 < label)];
+
+        Some(match address - label {
+            0 => name.clone(),
+            offset => format!("{}+{}", name, offset),
+        })
+
+    }
+
+    // Accepts "main_loop" and "main_loop+4"
+    pub fn address_of(&self, text: &str) -> Option<u16> {
+
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name.trim(), parse_offset(offset.trim())?),
+            None => (text.trim(), 0),
+        };
+
+        let (address, _) = self.entries.iter().find(|entry| entry.1 == name)?;
+        address.checked_add(offset)
+
+    }
+
+}
+
+impl Default for Symbols {
+    fn default() -> Symbols {
+        Symbols::new()
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let hex = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+fn parse_offset(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+
+    // Errors come back without the temporary file's path
+    fn load(name: &str, contents: &str) -> Result<Symbols, String> {
+        let path = env::temp_dir().join(format!("chip_8_symbols_test_{}_{}.sym", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let symbols = Symbols::load(&path);
+        fs::remove_file(&path).unwrap();
+        symbols.map_err(|error| error.replace(&format!("{}:", path.display()), ""))
+    }
+
+    #[test]
+    fn layouts() {
+        let symbols = load("layouts", concat!(
+            "# Both layouts can be mixed\n",
+            "200 main\n",
+            "0x2A4 draw   # comment\n",
+            "\n",
+            "add 0x300\n",
+            "beef = 320\n",
+            "  loop=0x340\n",
+            "360 cafe\n",
+        )).unwrap();
+        assert_eq!(symbols.address_of("main"), Some(0x200));
+        assert_eq!(symbols.address_of("draw"), Some(0x2A4));
+        assert_eq!(symbols.address_of("add"), Some(0x300));
+        assert_eq!(symbols.address_of("beef"), Some(0x320));
+        assert_eq!(symbols.address_of("loop"), Some(0x340));
+        assert_eq!(symbols.address_of("cafe"), Some(0x360));
+        assert_eq!(symbols.address_of("loop+0x4"), Some(0x344));
+        assert_eq!(symbols.name_for(0x2A8).as_deref(), Some("draw+4"));
+        assert_eq!(symbols.name_for(0x1FF), None);
+    }
+
+    #[test]
+    fn bad_lines() {
+        assert_eq!(load("bad_lines", "200 main\nmain loop\n").err().as_deref(), Some("2: expected 'ADDR NAME'"));
+        assert_eq!(load("bad_lines", "main 200\n").err().as_deref(), Some("1: expected 'ADDR NAME'"));
+        assert_eq!(load("bad_lines", "200\n").err().as_deref(), Some("1: expected 'ADDR NAME'"));
+        assert_eq!(load("bad_lines", "= 200\n").err().as_deref(), Some("1: expected 'ADDR NAME'"));
+        assert_eq!(load("bad_lines", "200 main extra\n").err().as_deref(), Some("1: expected 'ADDR NAME'"));
+        assert!(load("bad_lines", "").unwrap().is_empty());
+        assert!(Symbols::load(Path::new("/nonexistent/missing.sym")).is_err());
+    }
+
+}
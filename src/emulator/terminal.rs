@@ -0,0 +1,275 @@
+extern crate libc;
+
+use super::audio::AudioSink;
+use super::frontend::{Display, InputSource};
+
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Terminals only send key presses (repeated while held), so a key counts as held until this long
+// after its last press
+const KEY_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Ctrl-C, raw mode turns it into a plain byte
+const QUIT: u8 = 0x03;
+
+// Same layout as the SDL frontend: 1234 / QWER / ASDF / ZXCV
+const KEYPAD: [(u8, usize); 16] = [
+    (b'1', 0x1), (b'2', 0x2), (b'3', 0x3), (b'4', 0xC),
+    (b'q', 0x4), (b'w', 0x5), (b'e', 0x6), (b'r', 0xD),
+    (b'a', 0x7), (b's', 0x8), (b'd', 0x9), (b'f', 0xE),
+    (b'z', 0xA), (b'x', 0x0), (b'c', 0xB), (b'v', 0xF),
+];
+
+// ANSI colours for lit / unlit pixels, as foreground codes (background is + 10)
+const ON: u8 = 97;
+const OFF: u8 = 30;
+
+#[derive(PartialEq)]
+pub enum Glyphs {
+    // Two pixels per character cell, in colour
+    HalfBlock,
+    // 2x4 pixels per character cell, a quarter of the space
+    Braille,
+}
+
+// Draws the display on the terminal's alternate screen, restoring it when dropped
+pub struct TerminalScreen {
+    glyphs: Glyphs,
+    last_frame: Vec<u8>,
+}
+
+impl TerminalScreen {
+
+    pub fn new(glyphs: Glyphs) -> TerminalScreen {
+        // Alternate screen, hidden cursor, cleared
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        TerminalScreen {
+            glyphs,
+            last_frame: Vec::new(),
+        }
+    }
+
+    fn half_blocks(pixels: &[u8], width: usize, height: usize) -> String {
+
+        let mut out = String::new();
+
+        for row in 0..height.div_ceil(2) {
+
+            out += &format!("\x1b[{};1H", row + 1);
+            let mut colours: Option<(u8, u8)> = None;
+
+            for x in 0..width {
+                let top = pixels[row * 2 * width + x] > 0;
+                let bottom = row * 2 + 1 < height && pixels[(row * 2 + 1) * width + x] > 0;
+                let wanted = (if top { ON } else { OFF }, (if bottom { ON } else { OFF }) + 10);
+                if colours != Some(wanted) {
+                    out += &format!("\x1b[{};{}m", wanted.0, wanted.1);
+                    colours = Some(wanted);
+                }
+                out.push('▀');
+            }
+
+            out += "\x1b[0m";
+
+        }
+
+        out
+
+    }
+
+    fn braille(pixels: &[u8], width: usize, height: usize) -> String {
+
+        // Bit for each dot of a braille cell, by [y][x]
+        const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+        let mut out = format!("\x1b[{}m", ON);
+
+        for row in 0..height.div_ceil(4) {
+            out += &format!("\x1b[{};1H", row + 1);
+            for column in 0..width.div_ceil(2) {
+                let mut cell = 0x2800;
+                for (dy, dots) in DOTS.iter().enumerate() {
+                    for (dx, dot) in dots.iter().enumerate() {
+                        let (x, y) = (column * 2 + dx, row * 4 + dy);
+                        if x < width && y < height && pixels[y * width + x] > 0 {
+                            cell |= dot;
+                        }
+                    }
+                }
+                out.push(char::from_u32(cell).unwrap_or(' '));
+            }
+        }
+
+        out + "\x1b[0m"
+
+    }
+
+}
+
+impl Display for TerminalScreen {
+
+    fn present(&mut self, pixels: &[u8], width: usize, height: usize) {
+
+        // Most frames don't change anything, don't flood the terminal with them
+        if self.last_frame == pixels {
+            return;
+        }
+        self.last_frame = pixels.to_vec();
+
+        let out = match self.glyphs {
+            Glyphs::HalfBlock => TerminalScreen::half_blocks(pixels, width, height),
+            Glyphs::Braille => TerminalScreen::braille(pixels, width, height),
+        };
+
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(out.as_bytes());
+        let _ = stdout.flush();
+
+    }
+
+}
+
+impl Drop for TerminalScreen {
+
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+
+}
+
+// Reads keys from stdin in raw mode, restoring the terminal's settings when dropped
+pub struct TerminalKeys {
+    input: Receiver<u8>,
+    pressed_at: [Option<Instant>; 0x10],
+    original: Option<libc::termios>,
+}
+
+impl TerminalKeys {
+
+    pub fn new() -> TerminalKeys {
+
+        let original = unsafe {
+            let mut termios = MaybeUninit::<libc::termios>::uninit();
+            if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) == 0 {
+                let original = termios.assume_init();
+                let mut raw = original;
+                libc::cfmakeraw(&mut raw);
+                // Keep \n meaning a new line on output
+                raw.c_oflag |= libc::OPOST;
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+                Some(original)
+            } else {
+                eprintln!("stdin isn't a terminal, keys won't be read");
+                None
+            }
+        };
+
+        let (sender, input) = mpsc::channel();
+
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => {}
+                    _ => return,
+                }
+            }
+        });
+
+        TerminalKeys {
+            input,
+            pressed_at: [None; 0x10],
+            original,
+        }
+
+    }
+
+}
+
+impl Default for TerminalKeys {
+    fn default() -> TerminalKeys {
+        TerminalKeys::new()
+    }
+}
+
+impl InputSource for TerminalKeys {
+
+    fn read_keys(&mut self, keys: &mut [bool; 0x10]) -> bool {
+
+        let now = Instant::now();
+
+        loop {
+            match self.input.try_recv() {
+                Ok(QUIT) => return false,
+                Ok(byte) => {
+                    let byte = byte.to_ascii_lowercase();
+                    if let Some(&(_, key)) = KEYPAD.iter().find(|(c, _)| *c == byte) {
+                        self.pressed_at[key] = Some(now);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                // Without a terminal stdin may just end, keep running with no keys
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        for (key, pressed_at) in self.pressed_at.iter().enumerate() {
+            keys[key] = pressed_at.is_some_and(|time| now.duration_since(time) < KEY_TIMEOUT);
+        }
+
+        true
+
+    }
+
+}
+
+impl Drop for TerminalKeys {
+
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+
+}
+
+// Rings the terminal bell each time the sound timer starts
+pub struct TerminalBell {
+    playing: bool,
+}
+
+impl TerminalBell {
+
+    pub fn new() -> TerminalBell {
+        TerminalBell { playing: false }
+    }
+
+}
+
+impl Default for TerminalBell {
+    fn default() -> TerminalBell {
+        TerminalBell::new()
+    }
+}
+
+impl AudioSink for TerminalBell {
+
+    fn resume(&mut self) {
+        if !self.playing {
+            print!("\x07");
+            let _ = io::stdout().flush();
+        }
+        self.playing = true;
+    }
+
+    fn pause(&mut self) {
+        self.playing = false;
+    }
+
+}
@@ -0,0 +1,228 @@
+use super::disassembler::{self, Class};
+use super::instruction::Instruction;
+use super::symbols::Symbols;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+
+pub struct TraceOptions {
+    // "-" traces to stdout
+    pub path: String,
+    // Inclusive address range, None traces everything
+    pub range: Option<(u16, u16)>,
+    // Empty traces every class
+    pub classes: Vec<Class>,
+    // Keep only the last N (more than 0) lines in memory and write them out when an error occurs
+    pub ring: Option<usize>,
+}
+
+pub struct Tracer {
+    output: Box<dyn Write + Send>,
+    range: Option<(u16, u16)>,
+    classes: Vec<Class>,
+    ring: Option<(VecDeque<String>, usize)>,
+    frame: u64,
+    cycle: u64,
+}
+
+impl Tracer {
+
+    pub fn new(options: &TraceOptions) -> io::Result<Tracer> {
+
+        let output: Box<dyn Write + Send> = if options.path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(&options.path)?))
+        };
+
+        Ok(Tracer {
+            output,
+            range: options.range,
+            classes: options.classes.clone(),
+            ring: options.ring.map(|size| (VecDeque::with_capacity(size), size)),
+            frame: 0,
+            cycle: 0,
+        })
+
+    }
+
+    pub fn trace(&mut self, pc: u16, instruction: &Instruction, registers: &[u8; 16], i: u16, sp: u8, symbols: &Symbols) {
+
+        let cycle = self.cycle;
+        self.cycle += 1;
+
+        if let Some((start, end)) = self.range {
+            if pc < start || pc > end {
+                return;
+            }
+        }
+
+        if !self.classes.is_empty() && !self.classes.contains(&disassembler::class(instruction)) {
+            return;
+        }
+
+        let mut line = format!(
+            "{:6} {:9} {:03X}: {:04X} {:<16}",
+            self.frame, cycle, pc, instruction.raw, disassembler::disassemble_with_symbols(instruction, symbols)
+        );
+        for register in registers {
+            line += &format!(" {:02X}", register);
+        }
+        line += &format!(" I={:03X} SP={:X}", i, sp);
+        if let Some(name) = symbols.name_for(pc) {
+            line += &format!(" {}", name);
+        }
+
+        match &mut self.ring {
+            Some((lines, size)) => {
+                if *size == 0 {
+                    return;
+                }
+                if lines.len() >= *size {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+            None => {
+                let result = writeln!(self.output, "{}", line);
+                self.check(result);
+            }
+        }
+
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frame += 1;
+        let result = self.output.flush();
+        self.check(result);
+    }
+
+    // Writes out the ring buffer, called when the cpu hits an error
+    pub fn dump(&mut self) {
+        let mut result = Ok(());
+        if let Some((lines, _)) = &mut self.ring {
+            for line in lines.drain(..) {
+                result = result.and_then(|_| writeln!(self.output, "{}", line));
+            }
+        }
+        let result = result.and_then(|_| self.output.flush());
+        self.check(result);
+    }
+
+    // Stops tracing once the output fails (full disk, or the reader of a piped trace went away)
+    // rather than taking the emulator down with it
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(error) = result {
+            eprintln!("Failed to write trace, tracing stopped: {}", error);
+            self.output = Box::new(io::sink());
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        // Instructions of the lines written so far
+        fn opcodes(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+                .lines()
+                .map(|line| line.split_whitespace().nth(3).unwrap().to_string())
+                .collect()
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn tracer(range: Option<(u16, u16)>, classes: Vec<Class>, ring: Option<usize>) -> (Tracer, Captured) {
+        let captured = Captured::default();
+        let tracer = Tracer {
+            output: Box::new(captured.clone()),
+            range,
+            classes,
+            ring: ring.map(|size| (VecDeque::with_capacity(size), size)),
+            frame: 0,
+            cycle: 0,
+        };
+        (tracer, captured)
+    }
+
+    fn run(tracer: &mut Tracer, program: &[u16]) {
+        for (index, &opcode) in program.iter().enumerate() {
+            tracer.trace(0x200 + index as u16 * 2, &Instruction::new(opcode), &[0; 16], 0, 0, &Symbols::new());
+        }
+    }
+
+    #[test]
+    fn lines_are_filtered() {
+        let (mut tracer, captured) = tracer(Some((0x202, 0x206)), vec![Class::Alu, Class::Draw], None);
+        run(&mut tracer, &[0x7001, 0x7002, 0x6003, 0xD015, 0x7004]);
+        assert_eq!(captured.opcodes(), vec!["7002", "D015"]);
+    }
+
+    #[test]
+    fn ring_keeps_the_last_lines_until_dumped() {
+        let (mut tracer, captured) = tracer(None, Vec::new(), Some(2));
+        run(&mut tracer, &[0x6001, 0x6002, 0x6003]);
+        tracer.end_frame();
+        assert!(captured.opcodes().is_empty());
+        tracer.dump();
+        assert_eq!(captured.opcodes(), vec!["6002", "6003"]);
+        // Dumped lines are gone
+        tracer.dump();
+        assert_eq!(captured.opcodes().len(), 2);
+    }
+
+    #[test]
+    fn frames_and_cycles_are_counted() {
+        let (mut tracer, captured) = tracer(None, Vec::new(), None);
+        run(&mut tracer, &[0x6001, 0x6002]);
+        tracer.end_frame();
+        run(&mut tracer, &[0x6003]);
+        let text = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let counters: Vec<(&str, &str)> = text.lines().map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next().unwrap(), fields.next().unwrap())
+        }).collect();
+        assert_eq!(counters, vec![("0", "0"), ("0", "1"), ("1", "2")]);
+    }
+
+    #[test]
+    fn write_errors_stop_tracing() {
+        let (mut tracer, _) = tracer(None, Vec::new(), None);
+        tracer.output = Box::new(Broken);
+        // Reported once, then everything goes to a sink
+        run(&mut tracer, &[0x6001, 0x6002]);
+        tracer.end_frame();
+        tracer.dump();
+    }
+
+}
@@ -0,0 +1,215 @@
+//! C API for driving the emulator from other languages, without SDL. `include/chip_8.h` is
+//! generated from this file, the functions are exported from the crate's `cdylib`.
+//!
+//! Every function accepts a null machine and does nothing (returning false / 0 / null). A panic
+//! inside the emulator doesn't cross into C either, the function fails the same way.
+
+use crate::emulator::{Cpu, Options, DISPLAY_HEIGHT, DISPLAY_WIDTH, STATE_SIZE};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// Framebuffer width in pixels.
+pub const CHIP8_DISPLAY_WIDTH: usize = 64;
+/// Framebuffer height in pixels.
+pub const CHIP8_DISPLAY_HEIGHT: usize = 32;
+
+const _: () = assert!(CHIP8_DISPLAY_WIDTH == DISPLAY_WIDTH && CHIP8_DISPLAY_HEIGHT == DISPLAY_HEIGHT);
+
+// Unwinding out of an extern "C" function aborts the host, so panics are caught (the panic hook
+// has already printed them) and `failed` is returned instead. The libretro core uses it too.
+pub(crate) fn catch<T>(failed: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(failed)
+}
+
+/// A machine, created by `chip8_new()` and destroyed by `chip8_free()`.
+pub struct Chip8 {
+    cpu: Cpu,
+    // Set once the program hits an error, frames don't run until a ROM or state is loaded
+    halted: bool,
+}
+
+/// A machine in its power-on state with no ROM loaded.
+#[no_mangle]
+pub extern "C" fn chip8_new() -> *mut Chip8 {
+    catch(ptr::null_mut(), || Box::into_raw(Box::new(Chip8 {
+        cpu: Cpu::new(&Options::new()),
+        halted: false,
+    })))
+}
+
+/// Resets the machine and loads `len` bytes of program at 0x200. False if the ROM is empty or
+/// doesn't fit in memory.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()` and `data` point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(chip8: *mut Chip8, data: *const u8, len: usize) -> bool {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) if !data.is_null() => chip8,
+        _ => return false,
+    };
+    let rom = slice::from_raw_parts(data, len);
+    catch(false, || {
+        chip8.cpu.reset();
+        chip8.halted = false;
+        match chip8.cpu.load_rom(rom) {
+            Ok(()) => true,
+            Err(error) => {
+                eprintln!("chip_8: {}", error);
+                false
+            }
+        }
+    })
+}
+
+/// Runs one 60Hz frame. False if the program hit an error (unknown opcode, stack overflow, ...),
+/// which is printed to stderr and stops the machine.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frame(chip8: *mut Chip8) -> bool {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) if !chip8.halted => chip8,
+        _ => return false,
+    };
+    let ran = catch(false, || match chip8.cpu.run_frame() {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("chip_8: {}", error);
+            false
+        }
+    });
+    chip8.halted = !ran;
+    ran
+}
+
+/// `CHIP8_DISPLAY_WIDTH * CHIP8_DISPLAY_HEIGHT` bytes, row by row, 0 for unlit pixels and 255
+/// for lit ones. Valid until the next call on the machine.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_get_framebuffer(chip8: *const Chip8) -> *const u8 {
+    catch(ptr::null(), || match chip8.as_ref() {
+        Some(chip8) => chip8.cpu.display().as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Presses or releases key 0x0 to 0xF, other keys are ignored.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_key(chip8: *mut Chip8, key: u8, pressed: bool) {
+    if let Some(chip8) = chip8.as_mut() {
+        catch((), || chip8.cpu.set_key(key as usize, pressed));
+    }
+}
+
+/// True while the beeper should be on.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_active(chip8: *const Chip8) -> bool {
+    catch(false, || chip8.as_ref().is_some_and(|chip8| chip8.cpu.sound_active()))
+}
+
+/// Writes a save state to `out` if `len` is large enough. Returns the size of a save state, so
+/// `chip8_save_state(chip8, NULL, 0)` tells how much to allocate.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()` and `out` point to `len` writable bytes (or be null).
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(chip8: *const Chip8, out: *mut u8, len: usize) -> usize {
+    let chip8 = match chip8.as_ref() {
+        Some(chip8) => chip8,
+        None => return 0,
+    };
+    catch(0, || {
+        if !out.is_null() && len >= STATE_SIZE {
+            ptr::copy_nonoverlapping(chip8.cpu.save_state().as_ptr(), out, STATE_SIZE);
+        }
+        STATE_SIZE
+    })
+}
+
+/// Restores a state written by `chip8_save_state()`. False, leaving the machine untouched, if it
+/// isn't one.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()` and `data` point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(chip8: *mut Chip8, data: *const u8, len: usize) -> bool {
+    let chip8 = match chip8.as_mut() {
+        Some(chip8) if !data.is_null() => chip8,
+        _ => return false,
+    };
+    let state = slice::from_raw_parts(data, len);
+    catch(false, || match chip8.cpu.load_state(state) {
+        Ok(()) => {
+            chip8.halted = false;
+            true
+        }
+        Err(error) => {
+            eprintln!("chip_8: {}", error);
+            false
+        }
+    })
+}
+
+/// Destroys a machine.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new()` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_free(chip8: *mut Chip8) {
+    if !chip8.is_null() {
+        catch((), || drop(Box::from_raw(chip8)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+    use std::fs;
+
+    // include/chip_8.h is checked in so C hosts don't need to build first, this keeps it in step
+    // with the functions above
+    #[test]
+    fn header_is_up_to_date() {
+
+        let config = cbindgen::Config {
+            language: cbindgen::Language::C,
+            include_guard: Some(String::from("CHIP_8_H")),
+            header: Some(String::from("/* Generated from src/ffi.rs, don't edit. CHIP_8_UPDATE_HEADER=1 cargo test header regenerates it */")),
+            sys_includes: vec![String::from("stdbool.h"), String::from("stddef.h"), String::from("stdint.h")],
+            no_includes: true,
+            usize_is_size_t: true,
+            // extern "C" guards so the C++ harness can include it too
+            cpp_compat: true,
+            ..Default::default()
+        };
+
+        let mut generated: Vec<u8> = Vec::new();
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(concat!(env!("CARGO_MANIFEST_DIR"), "/src/ffi.rs"))
+            .generate()
+            .unwrap()
+            .write(&mut generated);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/include/chip_8.h");
+        if env::var_os("CHIP_8_UPDATE_HEADER").is_some() {
+            fs::write(path, &generated).unwrap();
+        }
+        assert!(fs::read(path).unwrap() == generated, "include/chip_8.h is out of date, regenerate it with CHIP_8_UPDATE_HEADER=1 cargo test header");
+
+    }
+
+}
@@ -0,0 +1,298 @@
+use crate::emulator::Cpu;
+
+// Integer expressions over the machine's state, for rewards and end conditions:
+//   [0x300]          byte of memory
+//   bcd(0x300)       three BCD digits as written by Fx33, i.e. [0x300] * 100 + [0x301] * 10 + [0x302]
+//   V3, I, DT, ST    registers and timers
+//   + - * / %  == != < <= > >=  && || !  ( )  and decimal or 0x hex numbers
+// Comparisons and logic give 0 or 1, dividing by zero gives 0.
+pub enum Expression {
+    Number(i64),
+    Memory(Box<Expression>),
+    Bcd(Box<Expression>),
+    Register(usize),
+    I,
+    Dt,
+    St,
+    Negate(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+// Longest first so "<=" isn't read as "<"
+const SYMBOLS: [&str; 18] = ["==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "(", ")", "[", "]"];
+
+// Loosest binding first
+const PRECEDENCE: [&[(&str, Operator)]; 5] = [
+    &[("||", Operator::Or)],
+    &[("&&", Operator::And)],
+    &[("==", Operator::Equal), ("!=", Operator::NotEqual), ("<=", Operator::LessEqual), (">=", Operator::GreaterEqual), ("<", Operator::Less), (">", Operator::Greater)],
+    &[("+", Operator::Add), ("-", Operator::Subtract)],
+    &[("*", Operator::Multiply), ("/", Operator::Divide), ("%", Operator::Remainder)],
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("unexpected '{}' in '{}'", rest.chars().next().unwrap_or_default(), text));
+            }
+            let word = &rest[..end];
+            let number = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                None => word.parse().ok(),
+            };
+            tokens.push(match number {
+                Some(number) => Token::Number(number),
+                None if word.starts_with(|c: char| c.is_ascii_digit()) => return Err(format!("bad number '{}'", word)),
+                None => Token::Name(word.to_ascii_uppercase()),
+            });
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+
+    fn accept(&mut self, symbol: &str) -> bool {
+        if matches!(self.tokens.get(self.position), Some(Token::Symbol(next)) if *next == symbol) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.accept(symbol) { Ok(()) } else { Err(format!("expected '{}'", symbol)) }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expression, String> {
+
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for (symbol, operator) in PRECEDENCE[level] {
+                if self.accept(symbol) {
+                    let right = self.binary(level + 1)?;
+                    left = Expression::Binary(Box::new(left), *operator, Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+
+        if self.accept("-") {
+            return Ok(Expression::Negate(Box::new(self.unary()?)));
+        }
+        if self.accept("!") {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+        if self.accept("(") {
+            let inner = self.binary(0)?;
+            self.expect(")")?;
+            return Ok(inner);
+        }
+        if self.accept("[") {
+            let address = self.binary(0)?;
+            self.expect("]")?;
+            return Ok(Expression::Memory(Box::new(address)));
+        }
+
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+
+        match token {
+            Some(Token::Number(number)) => Ok(Expression::Number(*number)),
+            Some(Token::Name(name)) => match name.as_str() {
+                "I" => Ok(Expression::I),
+                "DT" => Ok(Expression::Dt),
+                "ST" => Ok(Expression::St),
+                "BCD" => {
+                    self.expect("(")?;
+                    let address = self.binary(0)?;
+                    self.expect(")")?;
+                    Ok(Expression::Bcd(Box::new(address)))
+                }
+                _ => match name.strip_prefix('V').and_then(|register| usize::from_str_radix(register, 16).ok()) {
+                    Some(register) if register < 0x10 && name.len() == 2 => Ok(Expression::Register(register)),
+                    _ => Err(format!("unknown name '{}'", name)),
+                },
+            },
+            Some(Token::Symbol(symbol)) => Err(format!("unexpected '{}'", symbol)),
+            None => Err(String::from("unexpected end")),
+        }
+
+    }
+
+}
+
+impl Expression {
+
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        let expression = parser.binary(0).map_err(|error| format!("{} in '{}'", error, text))?;
+        if parser.position < parser.tokens.len() {
+            return Err(format!("unexpected trailing input in '{}'", text));
+        }
+        Ok(expression)
+    }
+
+    pub fn evaluate(&self, cpu: &Cpu) -> i64 {
+
+        // Addresses outside memory read as 0
+        let byte = |address: i64| -> i64 {
+            usize::try_from(address).ok().and_then(|address| cpu.memory().data().get(address)).copied().unwrap_or(0) as i64
+        };
+
+        match self {
+            Expression::Number(number) => *number,
+            Expression::Memory(address) => byte(address.evaluate(cpu)),
+            Expression::Bcd(address) => {
+                let address = address.evaluate(cpu);
+                byte(address) * 100 + byte(address + 1) * 10 + byte(address + 2)
+            }
+            Expression::Register(register) => cpu.register(*register).unwrap_or(0) as i64,
+            Expression::I => cpu.i() as i64,
+            Expression::Dt => cpu.dt as i64,
+            Expression::St => cpu.st as i64,
+            Expression::Negate(inner) => inner.evaluate(cpu).wrapping_neg(),
+            Expression::Not(inner) => (inner.evaluate(cpu) == 0) as i64,
+            Expression::Binary(left, operator, right) => {
+                let left = left.evaluate(cpu);
+                // Short circuit like C, the right side may read memory that isn't set up yet
+                match operator {
+                    Operator::And if left == 0 => return 0,
+                    Operator::Or if left != 0 => return 1,
+                    _ => (),
+                }
+                let right = right.evaluate(cpu);
+                match operator {
+                    Operator::Add => left.wrapping_add(right),
+                    Operator::Subtract => left.wrapping_sub(right),
+                    Operator::Multiply => left.wrapping_mul(right),
+                    Operator::Divide => left.checked_div(right).unwrap_or(0),
+                    Operator::Remainder => left.checked_rem(right).unwrap_or(0),
+                    Operator::Equal => (left == right) as i64,
+                    Operator::NotEqual => (left != right) as i64,
+                    Operator::Less => (left < right) as i64,
+                    Operator::LessEqual => (left <= right) as i64,
+                    Operator::Greater => (left > right) as i64,
+                    Operator::GreaterEqual => (left >= right) as i64,
+                    Operator::And | Operator::Or => (right != 0) as i64,
+                }
+            }
+        }
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::emulator::Options;
+
+    fn evaluate(text: &str) -> i64 {
+        let mut cpu = Cpu::new(&Options::new());
+        cpu.memory_mut().set_u8(0x300, 1);
+        cpu.memory_mut().set_u8(0x301, 2);
+        cpu.memory_mut().set_u8(0x302, 3);
+        cpu.set_register(0x3, 7);
+        cpu.set_register(0xA, 5);
+        cpu.set_i(0x2F0);
+        cpu.dt = 9;
+        Expression::parse(text).unwrap().evaluate(&cpu)
+    }
+
+    fn error(text: &str) -> String {
+        Expression::parse(text).err().unwrap()
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9);
+        assert_eq!(evaluate("10 - 4 - 3"), 3);
+        assert_eq!(evaluate("-2 * 3"), -6);
+        assert_eq!(evaluate("!0 + 1"), 2);
+        assert_eq!(evaluate("1 < 2 && 3 >= 3 || 0"), 1);
+        assert_eq!(evaluate("1 + 1 == 2"), 1);
+        assert_eq!(evaluate("0x10 != 16"), 0);
+        assert_eq!(evaluate("7 / 0 + 7 % 0"), 0);
+    }
+
+    #[test]
+    fn machine_state() {
+        assert_eq!(evaluate("[0x300]"), 1);
+        assert_eq!(evaluate("bcd(0x300)"), 123);
+        assert_eq!(evaluate("BCD(0x2FF + 1)"), 123);
+        assert_eq!(evaluate("v3 + VA"), 12);
+        assert_eq!(evaluate("I"), 0x2F0);
+        assert_eq!(evaluate("DT - st"), 9);
+        assert_eq!(evaluate("[-1] + [0x10000]"), 0);
+        // Short circuits, so the right side isn't evaluated
+        assert_eq!(evaluate("0 && 1 / 0"), 0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error("1 +"), "unexpected end in '1 +'");
+        assert_eq!(error("(1"), "expected ')' in '(1'");
+        assert_eq!(error("[0x300"), "expected ']' in '[0x300'");
+        assert_eq!(error("1 2"), "unexpected trailing input in '1 2'");
+        assert_eq!(error("V10"), "unknown name 'V10' in 'V10'");
+        assert_eq!(error("score"), "unknown name 'SCORE' in 'score'");
+        assert_eq!(error("0x"), "bad number '0x'");
+        assert_eq!(error("1 $ 2"), "unexpected '$' in '1 $ 2'");
+        assert_eq!(error("* 2"), "unexpected '*' in '* 2'");
+    }
+
+}
@@ -0,0 +1,294 @@
+//! Gym-style environments for training agents on CHIP-8 games: `reset()` starts an episode,
+//! `step()` holds a set of keys for a few frames and returns the screen, the reward and whether
+//! the episode is over. Rewards and endings are expressions over memory and registers, see
+//! `Expression`.
+//!
+//! ```no_run
+//! use chip_8::gym::{EnvOptions, VecEnv};
+//!
+//! let mut options = EnvOptions::new();
+//! options.reward = String::from("bcd(0x2F0)");
+//! options.done = Some(String::from("[0x2F5] == 0"));
+//!
+//! let rom = std::fs::read("game.ch8").unwrap();
+//! let mut envs = VecEnv::new(&rom, &options, 64).unwrap();
+//! envs.reset();
+//! loop {
+//!     let actions = vec![1 << 0x5; 64];
+//!     for (observation, reward, done) in envs.step(&actions).unwrap() {
+//!         // ...
+//!     }
+//! }
+//! ```
+
+mod expression;
+
+pub use self::expression::Expression;
+
+use crate::emulator::{Cpu, Options, Quirks, CYCLES_PER_FRAME};
+
+use rayon::prelude::*;
+
+pub struct EnvOptions {
+    /// Frames run per step with the same keys held.
+    pub frame_skip: usize,
+    /// The reward for a step is how much this grew over it, e.g. `bcd(0x2F0)` for a score
+    /// written with Fx33.
+    pub reward: String,
+    /// The episode ends once this is non-zero, e.g. `[0x2F5] == 0` for no lives left.
+    pub done: Option<String>,
+    /// Ends episodes after this many frames.
+    pub max_frames: Option<u64>,
+    pub quirks: Quirks,
+    pub cycles_per_frame: usize,
+    /// Makes CXNN's random numbers repeatable. Each episode (and each environment of a
+    /// `VecEnv`) gets a different seed derived from it.
+    pub seed: Option<u64>,
+}
+
+impl EnvOptions {
+
+    pub fn new() -> EnvOptions {
+        EnvOptions {
+            frame_skip: 4,
+            reward: String::from("0"),
+            done: None,
+            max_frames: None,
+            quirks: Quirks::new(),
+            cycles_per_frame: CYCLES_PER_FRAME,
+            seed: None,
+        }
+    }
+
+}
+
+impl Default for EnvOptions {
+    fn default() -> EnvOptions {
+        EnvOptions::new()
+    }
+}
+
+/// One machine running a ROM in episodes.
+pub struct Env {
+    cpu: Cpu,
+    rom: Vec<u8>,
+    frame_skip: usize,
+    reward: Expression,
+    done: Option<Expression>,
+    max_frames: Option<u64>,
+    seed: Option<u64>,
+    score: i64,
+    frames: u64,
+    episodes: u64,
+    finished: bool,
+}
+
+impl Env {
+
+    pub fn new(rom: &[u8], options: &EnvOptions) -> Result<Env, String> {
+
+        let mut emulator_options = Options::new();
+        emulator_options.quirks = options.quirks;
+        emulator_options.cycles_per_frame = options.cycles_per_frame;
+
+        let mut env = Env {
+            cpu: Cpu::new(&emulator_options),
+            rom: rom.to_vec(),
+            frame_skip: options.frame_skip.max(1),
+            reward: Expression::parse(&options.reward)?,
+            done: options.done.as_deref().map(Expression::parse).transpose()?,
+            max_frames: options.max_frames,
+            seed: options.seed,
+            score: 0,
+            frames: 0,
+            episodes: 0,
+            finished: false,
+        };
+
+        env.cpu.load_rom(rom)?;
+        Ok(env)
+
+    }
+
+    /// Starts a new episode from power on, returning the first observation.
+    pub fn reset(&mut self) -> Vec<u8> {
+        self.cpu.reset();
+        // Already validated by new()
+        let _ = self.cpu.load_rom(&self.rom);
+        if let Some(seed) = self.seed {
+            self.cpu.seed(seed.wrapping_add(self.episodes));
+        }
+        self.episodes += 1;
+        self.score = self.reward.evaluate(&self.cpu);
+        self.frames = 0;
+        self.finished = false;
+        self.observation()
+    }
+
+    /// Holds the keys set in `action` (bit n for key n) for `frame_skip` frames. Returns the
+    /// screen after them, the reward and whether the episode is over, which it also is when the
+    /// program hits an error. Once over, steps do nothing until `reset()`.
+    pub fn step(&mut self, action: u16) -> (Vec<u8>, f64, bool) {
+
+        if self.finished {
+            return (self.observation(), 0.0, true);
+        }
+
+        for key in 0..0x10 {
+            self.cpu.set_key(key, action & (1 << key) != 0);
+        }
+
+        for _ in 0..self.frame_skip {
+            self.frames += 1;
+            if self.cpu.run_frame().is_err()
+                || self.done.as_ref().is_some_and(|done| done.evaluate(&self.cpu) != 0)
+                || self.max_frames.is_some_and(|max_frames| self.frames >= max_frames) {
+                self.finished = true;
+                break;
+            }
+        }
+
+        let score = self.reward.evaluate(&self.cpu);
+        let reward = score.wrapping_sub(self.score) as f64;
+        self.score = score;
+
+        (self.observation(), reward, self.finished)
+
+    }
+
+    /// The screen, 64x32 bytes row by row, 0 or 255.
+    pub fn observation(&self) -> Vec<u8> {
+        self.cpu.display().to_vec()
+    }
+
+    /// The machine, e.g. to read memory the expressions don't cover.
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+}
+
+/// Many independent environments stepped together, spread over all cores.
+pub struct VecEnv {
+    envs: Vec<Env>,
+}
+
+impl VecEnv {
+
+    pub fn new(rom: &[u8], options: &EnvOptions, count: usize) -> Result<VecEnv, String> {
+        let envs = (0..count)
+            .map(|index| {
+                let mut env = Env::new(rom, options)?;
+                // Far enough apart that episode seeds don't overlap between environments
+                env.seed = options.seed.map(|seed| seed.wrapping_add((index as u64) << 32));
+                Ok(env)
+            })
+            .collect::<Result<Vec<Env>, String>>()?;
+        Ok(VecEnv { envs })
+    }
+
+    pub fn reset(&mut self) -> Vec<Vec<u8>> {
+        self.envs.par_iter_mut().map(Env::reset).collect()
+    }
+
+    /// One action per environment. Environments whose episode ended are reset straight away,
+    /// returning the first observation of the next one along with the final reward and `true`.
+    pub fn step(&mut self, actions: &[u16]) -> Result<Vec<(Vec<u8>, f64, bool)>, String> {
+        if actions.len() != self.envs.len() {
+            return Err(format!("Expected one action per environment, got {} for {}", actions.len(), self.envs.len()));
+        }
+        Ok(self.envs
+            .par_iter_mut()
+            .zip(actions.par_iter())
+            .map(|(env, &action)| {
+                let (observation, reward, done) = env.step(action);
+                if done {
+                    (env.reset(), reward, true)
+                } else {
+                    (observation, reward, false)
+                }
+            })
+            .collect())
+    }
+
+    pub fn envs(&self) -> &[Env] {
+        &self.envs
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Draws a 0 in the top left corner, then counts up in V1 forever
+    const COUNTER: [u8; 8] = [0xA0, 0x50, 0xD0, 0x05, 0x71, 0x01, 0x12, 0x04];
+
+    fn options() -> EnvOptions {
+        let mut options = EnvOptions::new();
+        options.frame_skip = 2;
+        options.cycles_per_frame = 4;
+        options.reward = String::from("V1");
+        options.max_frames = Some(3);
+        options
+    }
+
+    #[test]
+    fn vec_env_resets_finished_episodes() {
+        let mut envs = VecEnv::new(&COUNTER, &options(), 2).unwrap();
+        assert_eq!(envs.reset(), vec![vec![0; 64 * 32]; 2]);
+
+        // The first frame runs all four instructions, the next ones only the loop
+        let results = envs.step(&[0, 0]).unwrap();
+        for (observation, reward, done) in &results {
+            assert_eq!((observation[0], *reward, *done), (255, 3.0, false));
+        }
+
+        // Frame 3 ends the episode, the observation is already the next one's
+        let results = envs.step(&[0, 0]).unwrap();
+        for (observation, reward, done) in &results {
+            assert_eq!((observation[0], *reward, *done), (0, 2.0, true));
+        }
+        assert_eq!(envs.envs()[0].cpu().register(1), Some(0));
+
+        let results = envs.step(&[0, 0]).unwrap();
+        for (observation, reward, done) in &results {
+            assert_eq!((observation[0], *reward, *done), (255, 3.0, false));
+        }
+    }
+
+    #[test]
+    fn vec_env_checks_the_action_count() {
+        let mut envs = VecEnv::new(&COUNTER, &options(), 2).unwrap();
+        envs.reset();
+        assert_eq!(envs.step(&[0]).unwrap_err(), "Expected one action per environment, got 1 for 2");
+    }
+
+    #[test]
+    fn env_ends_on_done_and_errors() {
+        let mut options = options();
+        options.max_frames = None;
+        options.done = Some(String::from("V1 >= 5"));
+        let mut env = Env::new(&COUNTER, &options).unwrap();
+        env.reset();
+        assert!(!env.step(0).2);
+        assert!(env.step(0).2);
+        // Stays over until reset
+        assert_eq!(env.step(0).1, 0.0);
+        assert!(env.step(0).2);
+
+        let mut env = Env::new(&[0xFF, 0xFF], &options).unwrap();
+        env.reset();
+        assert!(env.step(0).2);
+    }
+
+}
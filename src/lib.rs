@@ -0,0 +1,25 @@
+//! CHIP-8 emulator core, plus the SDL frontend, debuggers and Octo compiler the `chip_8`
+//! binary is built from. The SDL parts are behind the default `sdl` feature.
+//!
+//! ```no_run
+//! use chip_8::emulator::{Cpu, Options, DISPLAY_WIDTH};
+//!
+//! let mut cpu = Cpu::new(&Options::new());
+//! cpu.load_rom(&std::fs::read("game.ch8").unwrap()).unwrap();
+//!
+//! loop {
+//!     cpu.set_key(0x5, true);
+//!     cpu.run_frame().unwrap();
+//!     let top_left_lit = cpu.display()[0] != 0;
+//!     let beeping = cpu.sound_active();
+//!     let v0 = cpu.register(0).unwrap_or(0);
+//!     cpu.memory_mut().set_u8(0x300, v0);
+//! }
+//! ```
+
+pub mod batch;
+pub mod emulator;
+pub mod ffi;
+pub mod gym;
+pub mod libretro;
+pub mod octo;
@@ -0,0 +1,620 @@
+//! libretro core, so frontends such as RetroArch can run the emulator. Built into the crate's
+//! `cdylib` (`libchip_8.so`), which RetroArch expects to be named `chip_8_libretro.so`.
+//!
+//! The 16 keys are mapped onto the RetroPad, directions on 2 / 4 / 6 / 8 and A on 5 as most
+//! games use them. Speed, palette and quirks are core options.
+
+use crate::emulator::{Cheat, Cpu, Options, Quirks, SquareWave, DISPLAY_HEIGHT, DISPLAY_WIDTH, STATE_SIZE};
+use crate::ffi::catch;
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_uint, c_void, CStr};
+use std::ptr;
+
+const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS: c_uint = 11;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+const SAMPLE_RATE: usize = 44100;
+const SAMPLES_PER_FRAME: usize = SAMPLE_RATE / 60;
+
+// RetroPad button id, CHIP-8 key and its description
+const BUTTONS: [(c_uint, usize, &CStr); 16] = [
+    (4, 0x2, c"Up (2)"),
+    (5, 0x8, c"Down (8)"),
+    (6, 0x4, c"Left (4)"),
+    (7, 0x6, c"Right (6)"),
+    (8, 0x5, c"A (5)"),
+    (0, 0x0, c"B (0)"),
+    (9, 0xE, c"X (E)"),
+    (1, 0xA, c"Y (A)"),
+    (2, 0xB, c"Select (B)"),
+    (3, 0xF, c"Start (F)"),
+    (10, 0x1, c"L (1)"),
+    (11, 0x3, c"R (3)"),
+    (12, 0x7, c"L2 (7)"),
+    (13, 0x9, c"R2 (9)"),
+    (14, 0xC, c"L3 (C)"),
+    (15, 0xD, c"R3 (D)"),
+];
+
+// Key, then "Description; default|other values"
+const VARIABLES: [(&CStr, &CStr); 8] = [
+    (c"chip_8_ips", c"Instructions per second; 1200|600|900|1800|2400|3000|6000|12000"),
+    (c"chip_8_palette", c"Palette; red|white|green|amber|inverted"),
+    (c"chip_8_quirk_shift_vy", c"Quirk: 8xy6 / 8xyE shift VY; enabled|disabled"),
+    (c"chip_8_quirk_memory_increment", c"Quirk: Fx55 / Fx65 increment I; enabled|disabled"),
+    (c"chip_8_quirk_vf_reset", c"Quirk: AND / OR / XOR reset VF; enabled|disabled"),
+    (c"chip_8_quirk_jump_vx", c"Quirk: Bnnn jumps to nnn + VX; disabled|enabled"),
+    (c"chip_8_quirk_clip", c"Quirk: Clip sprites at the edges; disabled|enabled"),
+    (c"chip_8_quirk_display_wait", c"Quirk: One sprite per frame; enabled|disabled"),
+];
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+#[repr(C)]
+pub struct RetroVariable {
+    pub key: *const c_char,
+    pub value: *const c_char,
+}
+
+#[repr(C)]
+pub struct RetroInputDescriptor {
+    pub port: c_uint,
+    pub device: c_uint,
+    pub index: c_uint,
+    pub id: c_uint,
+    pub description: *const c_char,
+}
+
+pub type RetroEnvironment = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type RetroVideoRefresh = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type RetroAudioSample = unsafe extern "C" fn(left: i16, right: i16);
+pub type RetroAudioSampleBatch = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type RetroInputPoll = unsafe extern "C" fn();
+pub type RetroInputState = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+struct Core {
+    environment: Option<RetroEnvironment>,
+    video_refresh: Option<RetroVideoRefresh>,
+    audio_sample_batch: Option<RetroAudioSampleBatch>,
+    input_poll: Option<RetroInputPoll>,
+    input_state: Option<RetroInputState>,
+    cpu: Option<Cpu>,
+    rom: Vec<u8>,
+    cheats: Vec<Option<Cheat>>,
+    // Off / on as XRGB8888
+    palette: [u32; 2],
+    frame: Vec<u32>,
+    wave: SquareWave,
+    samples: Vec<f32>,
+    audio: Vec<i16>,
+    // Set once the program hits an error, it stays frozen on its last frame until reset
+    halted: bool,
+}
+
+impl Core {
+
+    fn new() -> Core {
+        Core {
+            environment: None,
+            video_refresh: None,
+            audio_sample_batch: None,
+            input_poll: None,
+            input_state: None,
+            cpu: None,
+            rom: Vec::new(),
+            cheats: Vec::new(),
+            palette: [0x000000, 0xFF0000],
+            frame: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            wave: SquareWave::new(SAMPLE_RATE as i32),
+            samples: vec![0.0; SAMPLES_PER_FRAME],
+            audio: vec![0; SAMPLES_PER_FRAME * 2],
+            halted: false,
+        }
+    }
+
+    fn variable(&self, key: &CStr) -> Option<String> {
+        let environment = self.environment?;
+        let mut variable = RetroVariable { key: key.as_ptr(), value: ptr::null() };
+        unsafe {
+            if !environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable as *mut RetroVariable as *mut c_void) || variable.value.is_null() {
+                return None;
+            }
+            Some(CStr::from_ptr(variable.value).to_string_lossy().into_owned())
+        }
+    }
+
+    // Reads the core options into the machine, called on load and whenever they change
+    fn apply_variables(&mut self) {
+
+        let enabled = |core: &Core, key: &CStr, default: bool| match core.variable(key).as_deref() {
+            Some("enabled") => true,
+            Some("disabled") => false,
+            _ => default,
+        };
+
+        let defaults = Quirks::new();
+        let quirks = Quirks {
+            shift_vy: enabled(self, c"chip_8_quirk_shift_vy", defaults.shift_vy),
+            memory_increment: enabled(self, c"chip_8_quirk_memory_increment", defaults.memory_increment),
+            vf_reset: enabled(self, c"chip_8_quirk_vf_reset", defaults.vf_reset),
+            jump_vx: enabled(self, c"chip_8_quirk_jump_vx", defaults.jump_vx),
+            clip: enabled(self, c"chip_8_quirk_clip", defaults.clip),
+            display_wait: enabled(self, c"chip_8_quirk_display_wait", defaults.display_wait),
+            platform: defaults.platform,
+        };
+
+        let ips: Option<usize> = self.variable(c"chip_8_ips").and_then(|ips| ips.parse().ok());
+
+        self.palette = match self.variable(c"chip_8_palette").as_deref() {
+            Some("white") => [0x000000, 0xFFFFFF],
+            Some("green") => [0x0A1A0A, 0x33FF66],
+            Some("amber") => [0x1A0F00, 0xFFB000],
+            Some("inverted") => [0xFFFFFF, 0x000000],
+            _ => [0x000000, 0xFF0000],
+        };
+
+        if let Some(cpu) = &mut self.cpu {
+            cpu.quirks = quirks;
+            if let Some(ips) = ips {
+                cpu.cycles_per_frame = (ips / 60).max(1);
+            }
+        }
+
+    }
+
+    fn run(&mut self) {
+
+        if let Some(input_poll) = self.input_poll {
+            unsafe { input_poll() };
+        }
+
+        let mut keys = [false; 0x10];
+        if let Some(input_state) = self.input_state {
+            for (id, key, _) in BUTTONS {
+                keys[key] = unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, id) } != 0;
+            }
+        }
+
+        let cpu = match &mut self.cpu {
+            Some(cpu) => cpu,
+            None => return,
+        };
+
+        if !self.halted {
+            cpu.set_keys(&keys);
+            for cheat in self.cheats.iter().flatten() {
+                cheat.apply(cpu);
+            }
+            if let Err(error) = cpu.run_frame() {
+                eprintln!("chip_8: {}", error);
+                self.halted = true;
+            }
+        }
+
+        for (pixel, &value) in self.frame.iter_mut().zip(cpu.display()) {
+            *pixel = self.palette[(value > 0) as usize];
+        }
+
+        self.samples.fill(0.0);
+        if cpu.sound_active() && !self.halted {
+            self.wave.fill(&mut self.samples);
+        }
+        for (frame, sample) in self.audio.chunks_mut(2).zip(&self.samples) {
+            let sample = (sample * i16::MAX as f32) as i16;
+            frame.fill(sample);
+        }
+
+        unsafe {
+            if let Some(video_refresh) = self.video_refresh {
+                video_refresh(self.frame.as_ptr() as *const c_void, DISPLAY_WIDTH as c_uint, DISPLAY_HEIGHT as c_uint, DISPLAY_WIDTH * 4);
+            }
+            if let Some(audio_sample_batch) = self.audio_sample_batch {
+                audio_sample_batch(self.audio.as_ptr(), SAMPLES_PER_FRAME);
+            }
+        }
+
+    }
+
+}
+
+thread_local! {
+    // Frontends call every function from the same thread
+    static CORE: RefCell<Core> = RefCell::new(Core::new());
+}
+
+fn with_core<T>(f: impl FnOnce(&mut Core) -> T) -> T {
+    CORE.with(|core| f(&mut core.borrow_mut()))
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(environment: RetroEnvironment) {
+
+    let mut variables: Vec<RetroVariable> = VARIABLES
+        .iter()
+        .map(|(key, value)| RetroVariable { key: key.as_ptr(), value: value.as_ptr() })
+        .collect();
+    variables.push(RetroVariable { key: ptr::null(), value: ptr::null() });
+
+    unsafe {
+        environment(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void);
+    }
+
+    with_core(|core| core.environment = Some(environment));
+
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: RetroVideoRefresh) {
+    with_core(|core| core.video_refresh = Some(video_refresh));
+}
+
+// Samples are all sent in a batch, the single sample callback isn't used
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: RetroAudioSample) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: RetroAudioSampleBatch) {
+    with_core(|core| core.audio_sample_batch = Some(audio_sample_batch));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: RetroInputPoll) {
+    with_core(|core| core.input_poll = Some(input_poll));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: RetroInputState) {
+    with_core(|core| core.input_state = Some(input_state));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    with_core(|core| {
+        core.cpu = None;
+        core.cheats.clear();
+    });
+}
+
+/// # Safety
+/// `info` must point to a `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: c"chip_8".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"ch8|c8".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: DISPLAY_WIDTH as c_uint,
+            base_height: DISPLAY_HEIGHT as c_uint,
+            max_width: DISPLAY_WIDTH as c_uint,
+            max_height: DISPLAY_HEIGHT as c_uint,
+            aspect_ratio: 2.0,
+        },
+        timing: RetroSystemTiming {
+            fps: 60.0,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_core(|core| {
+        if let Some(cpu) = &mut core.cpu {
+            cpu.reset();
+            // Already validated when the game was loaded
+            let _ = cpu.load_rom(&core.rom);
+        }
+        core.halted = false;
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let ran = catch(false, || with_core(|core| {
+        let mut updated = false;
+        if let Some(environment) = core.environment {
+            unsafe {
+                environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut bool as *mut c_void);
+            }
+        }
+        if updated {
+            core.apply_variables();
+        }
+        core.run();
+        true
+    }));
+    // Same as an error in the program, the machine stays frozen until reset
+    if !ran {
+        with_core(|core| core.halted = true);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    STATE_SIZE
+}
+
+/// # Safety
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    with_core(|core| match &core.cpu {
+        Some(cpu) if size >= STATE_SIZE => {
+            ptr::copy_nonoverlapping(cpu.save_state().as_ptr(), data as *mut u8, STATE_SIZE);
+            true
+        }
+        _ => false,
+    })
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let state = std::slice::from_raw_parts(data as *const u8, size);
+    catch(false, || with_core(|core| match &mut core.cpu {
+        Some(cpu) => match cpu.load_state(state) {
+            Ok(()) => {
+                core.halted = false;
+                true
+            }
+            Err(error) => {
+                eprintln!("chip_8: {}", error);
+                false
+            }
+        },
+        None => false,
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    with_core(|core| core.cheats.clear());
+}
+
+/// Codes use the same format as `.cht` files, e.g. `2A0:05`.
+///
+/// # Safety
+/// `code` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(index: c_uint, enabled: bool, code: *const c_char) {
+    let code = CStr::from_ptr(code).to_string_lossy();
+    let cheat = match Cheat::parse(code.trim()) {
+        Ok(cheat) => Some(cheat).filter(|_| enabled),
+        Err(error) => {
+            eprintln!("chip_8: ignoring cheat: {}", error);
+            None
+        }
+    };
+    with_core(|core| {
+        let index = index as usize;
+        if core.cheats.len() <= index {
+            core.cheats.resize_with(index + 1, || None);
+        }
+        core.cheats[index] = cheat;
+    });
+}
+
+/// # Safety
+/// `game` must point to a `retro_game_info` whose data is `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let rom = std::slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+
+    catch(false, || load_game(rom))
+
+}
+
+fn load_game(rom: Vec<u8>) -> bool {
+
+    let mut cpu = Cpu::new(&Options::new());
+    if let Err(error) = cpu.load_rom(&rom) {
+        eprintln!("chip_8: {}", error);
+        return false;
+    }
+
+    with_core(|core| {
+
+        if let Some(environment) = core.environment {
+
+            let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+            if !unsafe { environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) } {
+                eprintln!("chip_8: XRGB8888 isn't supported by the frontend");
+                return false;
+            }
+
+            let mut descriptors: Vec<RetroInputDescriptor> = BUTTONS
+                .iter()
+                .map(|(id, _, description)| RetroInputDescriptor { port: 0, device: RETRO_DEVICE_JOYPAD, index: 0, id: *id, description: description.as_ptr() })
+                .collect();
+            descriptors.push(RetroInputDescriptor { port: 0, device: 0, index: 0, id: 0, description: ptr::null() });
+            unsafe { environment(RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS, descriptors.as_mut_ptr() as *mut c_void) };
+
+        }
+
+        core.cpu = Some(cpu);
+        core.rom = rom;
+        core.halted = false;
+        core.apply_variables();
+        true
+
+    })
+
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    with_core(|core| {
+        core.cpu = None;
+        core.rom.clear();
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+// All of memory, for achievements and RetroArch's own cheat search
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    with_core(|core| match &mut core.cpu {
+        Some(cpu) if id == RETRO_MEMORY_SYSTEM_RAM => cpu.memory_mut().data_mut().as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    with_core(|core| match &core.cpu {
+        Some(cpu) if id == RETRO_MEMORY_SYSTEM_RAM => cpu.memory().data().len(),
+        _ => 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn load(rom: &[u8]) -> bool {
+        let game = RetroGameInfo { path: ptr::null(), data: rom.as_ptr() as *const c_void, size: rom.len(), meta: ptr::null() };
+        unsafe { retro_load_game(&game) }
+    }
+
+    fn serialize() -> Vec<u8> {
+        let mut state = vec![0; retro_serialize_size()];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+        state
+    }
+
+    fn unserialize(state: &[u8]) -> bool {
+        unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) }
+    }
+
+    #[test]
+    fn save_states_round_trip() {
+        // V0 counts frames
+        assert!(load(&[0x70, 0x01, 0x12, 0x00]));
+        retro_run();
+        let state = serialize();
+        retro_run();
+        assert_ne!(serialize(), state);
+
+        assert!(unserialize(&state));
+        assert_eq!(serialize(), state);
+        retro_unload_game();
+    }
+
+    #[test]
+    fn save_states_are_checked() {
+        assert!(load(&[0x12, 0x00]));
+        let state = serialize();
+
+        let mut bad_magic = state.clone();
+        bad_magic[..4].copy_from_slice(b"NOPE");
+        assert!(!unserialize(&bad_magic));
+        assert!(!unserialize(&state[..state.len() - 1]));
+        let mut too_long = state.clone();
+        too_long.push(0);
+        assert!(!unserialize(&too_long));
+        assert!(!unsafe { retro_serialize(ptr::null_mut(), 0) });
+        assert!(!unsafe { retro_serialize(ptr::null_mut(), STATE_SIZE) });
+
+        assert_eq!(serialize(), state);
+        retro_unload_game();
+        assert!(!unserialize(&state));
+    }
+
+    #[test]
+    fn bad_games_are_refused() {
+        assert!(!load(&[]));
+        assert!(!load(&[0; 0x1000]));
+        assert!(!unsafe { retro_load_game(ptr::null()) });
+    }
+
+}
@@ -1,43 +1,530 @@
-mod emulator;
+mod args;
+mod watch;
+
+use chip_8::batch;
+use chip_8::emulator::{self, InputSource};
+use chip_8::octo;
+use sdl2::keyboard::Keycode;
 
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// ROM image, plus labels and source lines when it was compiled from a .8o
+fn load(path: &str) -> Result<octo::Program, String> {
+    if octo::is_source(path) {
+        return octo::compile_file(Path::new(path));
+    }
+    let rom = emulator::rom::read(path)?;
+    Ok(octo::Program { rom, labels: Vec::new(), lines: Vec::new() })
+}
+
+// Leaves the machine untouched if the new program can't be loaded
+fn reload(path: &str, old: &octo::Program, patch: bool, cpu: &mut emulator::Cpu) -> Result<octo::Program, String> {
+    let program = load(path)?;
+    if patch {
+        cpu.patch_rom(&old.rom, &program.rom)?;
+    } else {
+        emulator::rom::validate(&program.rom, cpu.quirks.platform)?;
+        cpu.reset();
+        cpu.load_rom(&program.rom)?;
+    }
+    Ok(program)
+}
+
+fn use_debug_info(program: &octo::Program, path: &str, cpu: &mut emulator::Cpu, debugger: &mut Option<Box<dyn emulator::Debugger>>) {
+    if !program.labels.is_empty() {
+        cpu.symbols = emulator::Symbols::from_labels(program.labels.clone());
+    }
+    if let Some(debugger) = debugger.as_mut().filter(|_| !program.lines.is_empty()) {
+        let lines = program.lines.iter().map(|&(address, line)| (address, PathBuf::from(path), line)).collect();
+        debugger.set_line_map(emulator::LineMap::from_entries(lines));
+    }
+}
+
+// Reports how a frontend's run ended, exiting like the SDL frontend does
+fn finish(cpu: &mut emulator::Cpu, result: Result<(), emulator::Error>) {
+    if let Some(profiler) = &cpu.profiler {
+        profiler.finish(cpu.memory(), &cpu.symbols);
+    }
+    if let Err(error) = result {
+        if let Some(tracer) = &mut cpu.tracer {
+            tracer.dump();
+        }
+        eprint!("Backtrace:\n{}", cpu.backtrace(error.pc()));
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+// The machine runs on its own thread unless something needs to get at it between instructions
+enum Machine {
+    Local(Box<emulator::Cpu>, emulator::Cheats),
+    Threaded(emulator::EmulationThread),
+}
+
+impl Machine {
+
+    fn with_cpu<T>(&mut self, f: impl FnOnce(&mut emulator::Cpu, &mut emulator::Cheats) -> T) -> T {
+        match self {
+            Machine::Local(cpu, cheats) => f(cpu, cheats),
+            Machine::Threaded(thread) => thread.with_cpu(f),
+        }
+    }
+
+    fn into_cpu(self) -> emulator::Cpu {
+        match self {
+            Machine::Local(cpu, _) => *cpu,
+            Machine::Threaded(thread) => thread.join(),
+        }
+    }
+
+}
+
+// From --cheats or the file next to the ROM, none for stdin
+fn load_cheats(path: &Option<String>, rom: &str) -> emulator::Cheats {
+    match path.clone().or_else(|| emulator::Cheats::path_for_rom(rom)) {
+        Some(path) => emulator::Cheats::load(&path),
+        None => emulator::Cheats::new(),
+    }
+}
+
+fn wav_audio(path: &str) -> emulator::WavAudio {
+    emulator::WavAudio::new(path, 44100).unwrap_or_else(|error| panic!("Failed to create WAV file {}: {}", path, error))
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| path.to_string())
+}
+
+// Every ROM/seed/quirk set combination on a thread pool, exiting with 1 if any of them failed
+fn run_batch(args: &args::Args) {
+
+    let path = Path::new(args.rom.as_deref().unwrap_or_default());
+    let paths: Vec<PathBuf> = if path.is_dir() {
+        let entries = fs::read_dir(path).unwrap_or_else(|error| panic!("Failed to read {}: {}", path.display(), error));
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| {
+                emulator::rom_browser::EXTENSIONS.contains(&extension.to_string_lossy().to_ascii_lowercase().as_str())
+            }))
+            .collect();
+        paths.sort();
+        paths
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let seeds: Vec<Option<u64>> = if args.seeds.is_empty() { vec![None] } else { args.seeds.iter().copied().map(Some).collect() };
+    let quirk_sets = if args.quirk_sets.is_empty() { vec![String::from("vip")] } else { args.quirk_sets.clone() };
+
+    let mut jobs = Vec::new();
+    for path in &paths {
+        // A ROM that doesn't load fails its runs without stopping the others
+        let rom = load(&path.to_string_lossy()).map(|program| program.rom);
+        for quirk_set in &quirk_sets {
+            for seed in &seeds {
+                jobs.push(batch::Job {
+                    name: file_name(&path.to_string_lossy()),
+                    rom: rom.clone(),
+                    seed: *seed,
+                    quirk_set: quirk_set.clone(),
+                    quirks: emulator::Quirks::preset(quirk_set).unwrap(),
+                    cycles_per_frame: args.options.cycles_per_frame,
+                    frames: args.frame_limit.unwrap_or(600),
+                });
+            }
+        }
+    }
+
+    let outcomes = batch::run(&jobs, args.threads).unwrap_or_else(|error| panic!("{}", error));
+    print!("{}", batch::summary(&jobs, &outcomes));
+
+    if let Some(report) = &args.report {
+        let json = serde_json::to_string_pretty(&batch::report(&jobs, &outcomes)).unwrap();
+        fs::write(report, json).unwrap_or_else(|error| panic!("Failed to write {}: {}", report, error));
+    }
+
+    if outcomes.iter().any(|outcome| outcome.error.is_some()) {
+        std::process::exit(1);
+    }
+
+}
 
 fn main() {
 
     let args: Vec<String> = env::args().collect();
-    if args.len() <= 1 {
-        panic!("Must provide rom as command line argument");
+    let args = args::parse(&args);
+
+    if args.batch {
+        run_batch(&args);
+        return;
+    }
+
+    if let Some(output) = &args.compile_output {
+        let source = args.rom.as_deref().unwrap_or_default();
+        let program = load(source).unwrap_or_else(|error| panic!("{}", error));
+        let output = Path::new(output);
+        fs::write(output, &program.rom).unwrap_or_else(|error| panic!("Failed to write {}: {}", output.display(), error));
+        program.write_symbols(&output.with_extension("sym")).unwrap_or_else(|error| panic!("Failed to write symbols: {}", error));
+        program.write_lines(&output.with_extension("lines"), Path::new(source)).unwrap_or_else(|error| panic!("Failed to write line map: {}", error));
+        return;
+    }
+
+    let mut dap = if args.dap { Some(emulator::DapServer::new()) } else { None };
+
+    let mut rom = match &mut dap {
+        Some(dap) => match dap.wait_for_launch() {
+            Some(rom) => rom,
+            None => return,
+        },
+        None => args.rom.unwrap(),
+    };
+
+    let mut debugger: Option<Box<dyn emulator::Debugger>> = match (dap, args.gdb_port) {
+        (Some(dap), _) => Some(Box::new(dap)),
+        (None, Some(port)) => match emulator::GdbServer::new(port) {
+            Ok(server) => Some(Box::new(server)),
+            Err(error) => panic!("Failed to listen for GDB on port {}: {}", port, error),
+        },
+        (None, None) => None,
+    };
+
+    let mut cpu = emulator::Cpu::new(&args.options);
+    if let Some(trace) = &args.trace {
+        match emulator::Tracer::new(trace) {
+            Ok(tracer) => cpu.tracer = Some(tracer),
+            Err(error) => panic!("Failed to create trace file {}: {}", trace.path, error),
+        }
     }
 
-    let mut cpu = emulator::Cpu::new();
-    
-    cpu.load_rom(args[1].as_str());
+    let mut program = load(&rom).unwrap_or_else(|error| panic!("{}", error));
+    if let Err(error) = cpu.load_rom(&program.rom) {
+        panic!("Failed to load ROM {}: {}", rom, error);
+    }
+    use_debug_info(&program, &rom, &mut cpu, &mut debugger);
+
+    let symbol_path = match &args.symbol_path {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(emulator::Symbols::path_for_rom(&rom)).filter(|path| cpu.symbols.is_empty() && path.exists()),
+    };
+    if let Some(path) = symbol_path {
+        match emulator::Symbols::load(&path) {
+            Ok(symbols) => cpu.symbols = symbols,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    let cheats = load_cheats(&args.cheat_path, &rom);
+
+    if matches!(args.frontend, args::Frontend::Image(_) | args::Frontend::Headless) {
+        let mut audio: Box<dyn emulator::AudioSink> = match &args.options.audio {
+            emulator::AudioBackend::Wav(path) => Box::new(wav_audio(path)),
+            _ => Box::new(emulator::NullAudio),
+        };
+        let mut display: Box<dyn emulator::Display> = match &args.frontend {
+            args::Frontend::Image(path) => Box::new(emulator::ImageSink::new(path)),
+            _ => Box::new(emulator::NoDisplay),
+        };
+        let result = emulator::run(&mut cpu, &mut *display, &mut *audio, &mut emulator::NoInput, args.frame_limit, false);
+        finish(&mut cpu, result);
+        return;
+    }
+
+    #[cfg(unix)]
+    if args.frontend == args::Frontend::Terminal || args.frontend == args::Frontend::Braille {
+        let glyphs = if args.frontend == args::Frontend::Braille { emulator::Glyphs::Braille } else { emulator::Glyphs::HalfBlock };
+        let mut audio: Box<dyn emulator::AudioSink> = match &args.options.audio {
+            emulator::AudioBackend::Sdl => Box::new(emulator::TerminalBell::new()),
+            emulator::AudioBackend::Null => Box::new(emulator::NullAudio),
+            emulator::AudioBackend::Wav(path) => Box::new(wav_audio(path)),
+        };
+        // Scoped so the terminal is restored before finish() prints anything
+        let result = {
+            let mut screen = emulator::TerminalScreen::new(glyphs);
+            let mut keys = emulator::TerminalKeys::new();
+            emulator::run(&mut cpu, &mut screen, &mut *audio, &mut keys, args.frame_limit, true)
+        };
+        finish(&mut cpu, result);
+        return;
+    }
+
+    let sdl_context = sdl2::init().unwrap();
+    let mut renderer = emulator::Renderer::new(&sdl_context, &args.options);
+    let mut audio = emulator::open_audio(&args.options.audio, &sdl_context, 44100, 512).unwrap_or_else(|error| panic!("{}", error));
+
+    let mut memory_viewer = if args.memory_viewer {
+        Some(emulator::MemoryViewer::new(&renderer.sdl_video_subsystem))
+    } else {
+        None
+    };
+
+    let mut watcher = if args.watch.is_some() { Some(watch::Watcher::new(&rom)) } else { None };
+
+    let rom_dir = match &args.rom_dir {
+        Some(directory) => PathBuf::from(directory),
+        None => Path::new(&rom).parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf(),
+    };
+    let mut rom_browser = emulator::RomBrowser::new(&rom_dir);
+    rom_browser.add_to_history(&rom);
+
+    // The debuggers and memory viewer work between instructions, so they keep it on this thread
+    let mut machine = if debugger.is_none() && memory_viewer.is_none() && !args.single_thread {
+        Machine::Threaded(emulator::EmulationThread::spawn(cpu, cheats))
+    } else {
+        Machine::Local(Box::new(cpu), cheats)
+    };
+
+    // Emulated frames for --frames, paused ones don't count
+    let mut frames: u64 = 0;
+    let mut paused = false;
+    let mut keys = [false; 0x10];
+    let mut instructions_seen: u64 = 0;
+    let mut errors_seen: u64 = 0;
 
     loop {
 
-        cpu.renderer.poll();
+        if !renderer.read_keys(&mut keys) || debugger.as_ref().is_some_and(|debugger| debugger.quit()) {
+            break;
+        }
+
+        // A ROM dropped onto the window or picked in the browser replaces the running one
+        let picked = rom_browser.update(&renderer.key_presses);
+        if let Some(path) = renderer.dropped_file.take().or(picked) {
+            let loaded = machine.with_cpu(|cpu, cheats| {
+                let loaded = reload(&path, &program, false, cpu)?;
+                cpu.symbols = emulator::Symbols::new();
+                use_debug_info(&loaded, &path, cpu, &mut debugger);
+                let symbol_path = emulator::Symbols::path_for_rom(&path);
+                if cpu.symbols.is_empty() && symbol_path.exists() {
+                    match emulator::Symbols::load(&symbol_path) {
+                        Ok(symbols) => cpu.symbols = symbols,
+                        Err(error) => eprintln!("{}", error),
+                    }
+                }
+                if args.cheat_path.is_none() {
+                    *cheats = load_cheats(&None, &path);
+                }
+                Ok::<_, String>(loaded)
+            });
+            match loaded {
+                Ok(loaded) => {
+                    if watcher.is_some() {
+                        watcher = Some(watch::Watcher::new(&path));
+                    }
+                    rom_browser.add_to_history(&path);
+                    program = loaded;
+                    rom = path;
+                    renderer.osd.error = None;
+                    renderer.osd.notify(&format!("Loaded {}", file_name(&rom)));
+                    eprintln!("Loaded {}", rom);
+                }
+                Err(error) => {
+                    eprintln!("Failed to load {}: {}", path, error);
+                    rom_browser.show_error(&error);
+                }
+            }
+        }
+
+        if watcher.as_mut().is_some_and(|watcher| watcher.changed()) {
+            let reloaded = machine.with_cpu(|cpu, _| {
+                let reloaded = reload(&rom, &program, args.watch == Some(args::Watch::Patch), cpu)?;
+                use_debug_info(&reloaded, &rom, cpu, &mut debugger);
+                Ok::<_, String>(reloaded)
+            });
+            match reloaded {
+                Ok(reloaded) => {
+                    program = reloaded;
+                    renderer.osd.error = None;
+                    renderer.osd.notify(&format!("Reloaded {}", file_name(&rom)));
+                    eprintln!("Reloaded {}", rom);
+                }
+                Err(error) => {
+                    renderer.osd.notify(&error);
+                    eprintln!("Failed to reload {}: {}", rom, error);
+                }
+            }
+        }
+
+        renderer.render();
+
+        if let Some(debugger) = &mut debugger {
+            machine.with_cpu(|cpu, _| debugger.poll(cpu));
+        }
+
+        let mut advance = false;
+
+        for key in renderer.key_presses.clone().into_iter().filter(|_| !rom_browser.open) {
+            match key {
+                Keycode::F1 => renderer.osd.show_stats = !renderer.osd.show_stats,
+                Keycode::P => paused = !paused,
+                // Runs a single frame and stays paused
+                Keycode::N => {
+                    paused = true;
+                    advance = true;
+                }
+                // Soft reset restarts the program already in memory, hard reset reads it from disk again
+                Keycode::F5 => match machine.with_cpu(|cpu, _| {
+                    cpu.reset();
+                    cpu.load_rom(&program.rom)
+                }) {
+                    Ok(()) => {
+                        renderer.osd.error = None;
+                        renderer.osd.notify("Reset");
+                    }
+                    Err(error) => renderer.osd.notify(&error),
+                },
+                Keycode::F6 => match machine.with_cpu(|cpu, cheats| {
+                    let reloaded = reload(&rom, &program, false, cpu)?;
+                    use_debug_info(&reloaded, &rom, cpu, &mut debugger);
+                    *cheats = load_cheats(&args.cheat_path, &rom);
+                    Ok::<_, String>(reloaded)
+                }) {
+                    Ok(reloaded) => {
+                        program = reloaded;
+                        renderer.osd.error = None;
+                        renderer.osd.notify("Hard reset");
+                    }
+                    Err(error) => renderer.osd.notify(&error),
+                },
+                Keycode::F7 => renderer.reload_shader(),
+                _ => (),
+            }
+        }
+
+        let halted = rom_browser.open
+            || (paused && !advance)
+            || (!renderer.focused && !args.options.background)
+            || debugger.as_ref().is_some_and(|debugger| debugger.halted());
+        renderer.osd.paused = halted;
+        let mut instructions = 0;
+
+        match &mut machine {
 
-        cpu.renderer.render();
+            Machine::Local(cpu, cheats) => {
+
+                cpu.set_keys(&keys);
+
+                if halted {
+                    audio.pause();
+                } else {
+
+                    frames += 1;
+
+                    if cpu.sound_active() {
+                        audio.resume();
+                    } else {
+                        audio.pause();
+                    }
+                    cpu.tick_timers();
+
+                    cheats.apply(cpu);
+
+                    for _ in 0..cpu.cycles_per_frame {
+
+                        if renderer.osd.error.is_some() {
+                            break;
+                        }
+
+                        if let Some(debugger) = &mut debugger {
+                            if debugger.check_stop(cpu) {
+                                break;
+                            }
+                        }
+
+                        if let Err(error) = cpu.step() {
+                            if debugger.as_mut().is_some_and(|debugger| debugger.report_error(cpu, &error)) {
+                                break;
+                            }
+                            if let Some(tracer) = &mut cpu.tracer {
+                                tracer.dump();
+                            }
+                            // Stop and leave the error on screen, exiting with it once the window is closed
+                            eprint!("Backtrace:\n{}", cpu.backtrace(error.pc()));
+                            eprintln!("{}", error);
+                            renderer.osd.error = Some(error.to_string());
+                            break;
+                        }
+
+                        instructions += 1;
+
+                    }
+
+                }
+
+                cpu.end_frame();
+
+                if !rom_browser.open {
+                    renderer.update_texture(cpu.display(), emulator::DISPLAY_WIDTH, emulator::DISPLAY_HEIGHT);
+                }
+
+                if let Some(viewer) = &mut memory_viewer {
+                    if !viewer.update(cpu, cheats, std::mem::take(&mut renderer.other_events)) {
+                        memory_viewer = None;
+                    }
+                }
+
+            }
+
+            // Runs at its own pace, this just shows whatever it finished last
+            Machine::Threaded(thread) => {
+
+                // The thread counts the frame out itself, unhalting it for one render frame could run none or two
+                if advance && !halted {
+                    thread.advance(1);
+                }
+                thread.send_input(keys, halted || advance);
+                let latest = thread.frame();
+                frames = latest.frames;
+
+                if latest.sound_active {
+                    audio.resume();
+                } else {
+                    audio.pause();
+                }
+
+                instructions = latest.instructions.saturating_sub(instructions_seen) as u32;
+                instructions_seen = latest.instructions;
+
+                if latest.errors > errors_seen {
+                    errors_seen = latest.errors;
+                    renderer.osd.error = latest.error.clone();
+                }
+
+                if !rom_browser.open {
+                    renderer.update_texture(&latest.display, emulator::DISPLAY_WIDTH, emulator::DISPLAY_HEIGHT);
+                }
+
+            }
 
-        if cpu.dt > 0 { cpu.dt -= 1; }
-        if cpu.st > 0 { 
-            cpu.renderer.audio.device.resume(); 
-            cpu.st -= 1; 
-        } else {
-            cpu.renderer.audio.device.pause();
         }
 
-        for _ in 0..20 {
-            cpu.decode_next_instruction();
+        if rom_browser.open {
+            let pixels = rom_browser.draw();
+            renderer.update_texture(pixels, emulator::rom_browser::WIDTH, emulator::rom_browser::HEIGHT);
         }
 
-        cpu.drawn_this_frame = false;
+        if let Err(error) = audio.end_frame() {
+            eprintln!("Failed to write audio, continuing without sound: {}", error);
+            audio = Box::new(emulator::NullAudio);
+        }
+        renderer.osd.end_frame(instructions);
 
-        cpu.renderer.update_texture();
+        if args.frame_limit.is_some_and(|limit| frames >= limit) {
+            break;
+        }
 
         ::std::thread::sleep(std::time::Duration::from_secs_f64(1.0/60.0))
 
     }
 
+    let cpu = machine.into_cpu();
+    if let Some(profiler) = &cpu.profiler {
+        profiler.finish(cpu.memory(), &cpu.symbols);
+    }
+
+    if renderer.osd.error.is_some() {
+        std::process::exit(1);
+    }
 
 }
@@ -0,0 +1,1020 @@
+use super::lexer::{self, Token};
+use super::Program;
+
+use std::collections::{HashMap, VecDeque};
+
+const START: u16 = 0x200;
+const END: u16 = 0x1000;
+
+// Guards against macros that expand into themselves forever
+const MAX_EXPANSIONS: usize = 100_000;
+
+const UNARY: [&str; 13] = ["-", "~", "!", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor"];
+const BINARY: [&str; 19] = ["+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", "<=", ">", ">=", "==", "!="];
+
+// How a forward reference is patched once the label is known
+enum Patch {
+    // Low 12 bits of an instruction
+    Address,
+    // A full 16 bit word, for 'i := long' and ':pointer'
+    Long,
+    // Immediate of 'v0 := NN' from ':unpack', high byte of the address or'd with a prefix
+    UnpackHigh(u16),
+    // Immediate of 'v1 := NN' from ':unpack', low byte of the address
+    UnpackLow,
+}
+
+struct Reference {
+    address: u16,
+    name: String,
+    patch: Patch,
+    line: u32,
+}
+
+enum Block {
+    If(u16),
+    Else(u16),
+    Loop { start: u16, breaks: Vec<u16> },
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>,
+    calls: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Condition {
+    x: u8,
+    operator: String,
+    operand: Option<Operand>,
+}
+
+pub struct Compiler {
+    name: String,
+    tokens: VecDeque<Token>,
+    line: u32,
+    statement_line: u32,
+    rom: Vec<u8>,
+    here: u16,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    references: Vec<Reference>,
+    blocks: Vec<(Block, u32)>,
+    lines: Vec<(u16, u32)>,
+    expansions: usize,
+}
+
+impl Compiler {
+
+    pub fn new(tokens: Vec<Token>, name: &str) -> Compiler {
+        Compiler {
+            name: name.to_string(),
+            tokens: tokens.into(),
+            line: 1,
+            statement_line: 1,
+            // The first instruction is a jump to main, filled in at the end
+            rom: vec![0, 0],
+            here: START + 2,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            references: Vec::new(),
+            blocks: Vec::new(),
+            lines: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    pub fn compile(mut self) -> Result<Program, String> {
+
+        while let Some(token) = self.next() {
+            self.statement_line = token.line;
+            self.statement(token)?;
+        }
+
+        if let Some((block, line)) = self.blocks.last() {
+            let kind = match block {
+                Block::If(_) | Block::Else(_) => "if ... begin",
+                Block::Loop { .. } => "loop",
+            };
+            return Err(format!("{}:{}: '{}' is never closed", self.name, line, kind));
+        }
+
+        for reference in std::mem::take(&mut self.references) {
+            let value = match self.resolve(&reference.name) {
+                Some(value) => value as i64,
+                None => return Err(format!("{}:{}: undefined name '{}'", self.name, reference.line, reference.name)),
+            };
+            self.patch(&reference, value)?;
+        }
+
+        let main = match self.labels.get("main") {
+            Some(&main) => main,
+            None => return Err(format!("{}: program is missing a 'main' label", self.name)),
+        };
+        self.rom[0] = 0x10 | (main >> 8) as u8;
+        self.rom[1] = main as u8;
+
+        let mut labels: Vec<(String, u16)> = self.labels.into_iter().collect();
+        labels.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        self.lines.sort_by_key(|entry| entry.0);
+
+        Ok(Program {
+            rom: self.rom,
+            labels,
+            lines: self.lines,
+        })
+
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), String> {
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect("a label name")?.text;
+                self.define_label(name, self.here)?;
+            }
+            ":next" => {
+                // Labels the immediate byte of the following instruction, for self-modifying code
+                let name = self.expect("a label name")?.text;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":const" | ":calc" => {
+                let name = self.expect("a constant name")?.text;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+            }
+            ":alias" => {
+                let name = self.expect("an alias name")?.text;
+                let register = self.expect_register()?;
+                self.aliases.insert(name, register);
+            }
+            ":unpack" => {
+                let prefix = match self.expect("a nibble or 'long'")? {
+                    token if token.text == "long" => 0,
+                    token => (self.known(&token)? as u16 & 0xF) << 4,
+                };
+                let target = self.expect("an address")?;
+                match self.lookup(&target)? {
+                    Some(value) => {
+                        self.instruction(0x6000 | (prefix | (value as u16 >> 8)))?;
+                        self.instruction(0x6100 | (value as u16 & 0xFF))?;
+                    }
+                    None => {
+                        self.reference(&target, Patch::UnpackHigh(prefix));
+                        self.instruction(0x6000)?;
+                        self.reference(&target, Patch::UnpackLow);
+                        self.instruction(0x6100)?;
+                    }
+                }
+            }
+            ":org" => {
+                let address = self.value()? as i64;
+                if !(START as i64..END as i64).contains(&address) {
+                    return Err(self.error(&format!(":org address {:X} is outside of program memory", address)));
+                }
+                self.here = address as u16;
+            }
+            ":macro" => self.define_macro()?,
+            ":byte" => {
+                let token = self.expect("a byte")?;
+                let byte = self.byte(&token)?;
+                self.write(byte)?;
+            }
+            ":pointer" => {
+                let token = self.expect("an address")?;
+                self.word(&token)?;
+            }
+            ":call" => {
+                let token = self.expect("an address")?;
+                self.address_instruction(0x2000, &token)?;
+            }
+            // Octo debugger directives, there's nothing for them to do here
+            ":breakpoint" | ":proto" => {
+                self.expect("a name")?;
+            }
+            ":monitor" => {
+                self.expect("an address")?;
+                self.expect("a length or format")?;
+            }
+            "return" | ";" => self.instruction(0x00EE)?,
+            "clear" => self.instruction(0x00E0)?,
+            "hires" => self.instruction(0x00FF)?,
+            "lores" => self.instruction(0x00FE)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(0x00C0 | n)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(0x00D0 | n)?;
+            }
+            "scroll-right" => self.instruction(0x00FB)?,
+            "scroll-left" => self.instruction(0x00FC)?,
+            "exit" => self.instruction(0x00FD)?,
+            "bcd" => {
+                let x = self.expect_register()?;
+                self.instruction(0xF033 | (x as u16) << 8)?;
+            }
+            "save" | "load" => {
+                let x = self.expect_register()?;
+                if self.tokens.front().is_some_and(|token| token.text == "-") {
+                    self.next();
+                    let y = self.expect_register()?;
+                    let base = if token.text == "save" { 0x5002 } else { 0x5003 };
+                    self.instruction(base | (x as u16) << 8 | (y as u16) << 4)?;
+                } else {
+                    let base = if token.text == "save" { 0xF055 } else { 0xF065 };
+                    self.instruction(base | (x as u16) << 8)?;
+                }
+            }
+            "saveflags" => {
+                let x = self.expect_register()?;
+                self.instruction(0xF075 | (x as u16) << 8)?;
+            }
+            "loadflags" => {
+                let x = self.expect_register()?;
+                self.instruction(0xF085 | (x as u16) << 8)?;
+            }
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let n = self.nibble()?;
+                self.instruction(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let base = match token.text.as_str() {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                let target = self.expect("an address")?;
+                self.address_instruction(base, &target)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect_text(":=")?;
+                let x = self.expect_register()?;
+                let base = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.instruction(base | (x as u16) << 8)?;
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.instruction(0xF001 | n << 8)?;
+            }
+            "audio" => self.instruction(0xF002)?,
+            "i" => self.index_statement()?,
+            "if" => {
+                let condition = self.condition()?;
+                match self.expect("'then' or 'begin'")?.text.as_str() {
+                    "then" => self.emit_condition(&condition, false)?,
+                    "begin" => {
+                        self.emit_condition(&condition, true)?;
+                        let jump = self.here;
+                        self.instruction(0x1000)?;
+                        self.blocks.push((Block::If(jump), token.line));
+                    }
+                    other => return Err(self.error(&format!("expected 'then' or 'begin', got '{}'", other))),
+                }
+            }
+            "else" => {
+                match self.blocks.pop() {
+                    Some((Block::If(jump), line)) => {
+                        let end_jump = self.here;
+                        self.instruction(0x1000)?;
+                        self.patch_jump(jump, self.here);
+                        self.blocks.push((Block::Else(end_jump), line));
+                    }
+                    _ => return Err(self.error("'else' without 'if ... begin'")),
+                }
+            }
+            "end" => {
+                match self.blocks.pop() {
+                    Some((Block::If(jump), _)) | Some((Block::Else(jump), _)) => self.patch_jump(jump, self.here),
+                    _ => return Err(self.error("'end' without 'if ... begin'")),
+                }
+            }
+            "loop" => self.blocks.push((Block::Loop { start: self.here, breaks: Vec::new() }, token.line)),
+            "while" => {
+                let condition = self.condition()?;
+                self.emit_condition(&condition, true)?;
+                let jump = self.here;
+                self.instruction(0x1000)?;
+                let innermost = self.blocks.iter_mut().rev().find_map(|(block, _)| match block {
+                    Block::Loop { breaks, .. } => Some(breaks),
+                    _ => None,
+                });
+                match innermost {
+                    Some(breaks) => breaks.push(jump),
+                    None => return Err(self.error("'while' outside of a loop")),
+                }
+            }
+            "again" => {
+                match self.blocks.pop() {
+                    Some((Block::Loop { start, breaks }, _)) => {
+                        self.instruction(0x1000 | start)?;
+                        for jump in breaks {
+                            self.patch_jump(jump, self.here);
+                        }
+                    }
+                    _ => return Err(self.error("'again' without 'loop'")),
+                }
+            }
+            text => {
+                if let Some(x) = self.register(text) {
+                    self.assignment(x)?;
+                } else if self.macros.contains_key(text) {
+                    self.expand(&token)?;
+                } else if let Some(value) = lexer::parse_number(text) {
+                    let byte = self.check_byte(value)?;
+                    self.write(byte)?;
+                } else if text.starts_with(':') {
+                    return Err(self.error(&format!("unknown directive '{}'", text)));
+                } else {
+                    // Any other name is a subroutine call, possibly to a label defined later
+                    self.address_instruction(0x2000, &token)?;
+                }
+            }
+        }
+
+        Ok(())
+
+    }
+
+    fn assignment(&mut self, x: u8) -> Result<(), String> {
+
+        let operator = self.expect("an operator")?.text;
+        let operand = self.expect("a register or value")?;
+        let x = (x as u16) << 8;
+
+        let op = match (operator.as_str(), self.register(&operand.text)) {
+            (":=", Some(y)) => 0x8000 | x | (y as u16) << 4,
+            ("|=", Some(y)) => 0x8001 | x | (y as u16) << 4,
+            ("&=", Some(y)) => 0x8002 | x | (y as u16) << 4,
+            ("^=", Some(y)) => 0x8003 | x | (y as u16) << 4,
+            ("+=", Some(y)) => 0x8004 | x | (y as u16) << 4,
+            ("-=", Some(y)) => 0x8005 | x | (y as u16) << 4,
+            (">>=", Some(y)) => 0x8006 | x | (y as u16) << 4,
+            ("=-", Some(y)) => 0x8007 | x | (y as u16) << 4,
+            ("<<=", Some(y)) => 0x800E | x | (y as u16) << 4,
+            (":=", None) => match operand.text.as_str() {
+                "random" => {
+                    let mask = self.expect("a mask")?;
+                    0xC000 | x | self.byte(&mask)? as u16
+                }
+                "key" => 0xF00A | x,
+                "delay" => 0xF007 | x,
+                _ => 0x6000 | x | self.byte(&operand)? as u16,
+            },
+            ("+=", None) => 0x7000 | x | self.byte(&operand)? as u16,
+            ("-=", None) => 0x7000 | x | 0u8.wrapping_sub(self.byte(&operand)?) as u16,
+            _ => return Err(self.error(&format!("can't use '{}' with '{}'", operator, operand.text))),
+        };
+
+        self.instruction(op)
+
+    }
+
+    fn index_statement(&mut self) -> Result<(), String> {
+
+        let operator = self.expect("':=' or '+='")?.text;
+        let operand = self.expect("a value")?;
+
+        match (operator.as_str(), operand.text.as_str()) {
+            (":=", "hex") => {
+                let x = self.expect_register()?;
+                self.instruction(0xF029 | (x as u16) << 8)
+            }
+            (":=", "bighex") => {
+                let x = self.expect_register()?;
+                self.instruction(0xF030 | (x as u16) << 8)
+            }
+            (":=", "long") => {
+                let target = self.expect("an address")?;
+                self.instruction(0xF000)?;
+                self.word(&target)
+            }
+            (":=", _) => self.address_instruction(0xA000, &operand),
+            ("+=", _) => match self.register(&operand.text) {
+                Some(x) => self.instruction(0xF01E | (x as u16) << 8),
+                None => Err(self.error(&format!("expected a register, got '{}'", operand.text))),
+            },
+            _ => Err(self.error(&format!("can't use '{}' with i", operator))),
+        }
+
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+
+        let x = self.expect_register()?;
+        let operator = self.expect("a comparison")?.text;
+
+        let operand = match operator.as_str() {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let token = self.expect("a register or value")?;
+                Some(match self.register(&token.text) {
+                    Some(y) => Operand::Register(y),
+                    None => Operand::Byte(self.byte(&token)?),
+                })
+            }
+            other => return Err(self.error(&format!("unknown comparison '{}'", other))),
+        };
+
+        Ok(Condition {
+            x,
+            operator,
+            operand,
+        })
+
+    }
+
+    // Emits the skip so the next instruction only runs when the condition holds, or when it
+    // doesn't if negated. The ordered comparisons go through vf like Octo does.
+    fn emit_condition(&mut self, condition: &Condition, negate: bool) -> Result<(), String> {
+
+        let operator = match (condition.operator.as_str(), negate) {
+            (operator, false) => operator,
+            ("==", true) => "!=",
+            ("!=", true) => "==",
+            ("key", true) => "-key",
+            ("-key", true) => "key",
+            ("<", true) => ">=",
+            (">=", true) => "<",
+            (">", true) => "<=",
+            ("<=", true) => ">",
+            (operator, true) => operator,
+        };
+
+        let x = condition.x;
+        let left = Operand::Register(x);
+        let x = (x as u16) << 8;
+
+        match (operator, condition.operand) {
+            ("key", _) => self.instruction(0xE0A1 | x),
+            ("-key", _) => self.instruction(0xE09E | x),
+            ("==", Some(Operand::Register(y))) => self.instruction(0x9000 | x | (y as u16) << 4),
+            ("==", Some(Operand::Byte(kk))) => self.instruction(0x4000 | x | kk as u16),
+            ("!=", Some(Operand::Register(y))) => self.instruction(0x5000 | x | (y as u16) << 4),
+            ("!=", Some(Operand::Byte(kk))) => self.instruction(0x3000 | x | kk as u16),
+            ("<", Some(right)) => {
+                self.greater_or_equal(left, right)?;
+                self.instruction(0x4F00)
+            }
+            (">=", Some(right)) => {
+                self.greater_or_equal(left, right)?;
+                self.instruction(0x3F00)
+            }
+            (">", Some(right)) => {
+                self.greater_or_equal(right, left)?;
+                self.instruction(0x4F00)
+            }
+            ("<=", Some(right)) => {
+                self.greater_or_equal(right, left)?;
+                self.instruction(0x3F00)
+            }
+            _ => Err(self.error("invalid condition")),
+        }
+
+    }
+
+    // Leaves vf = 1 when a >= b, 0 otherwise
+    fn greater_or_equal(&mut self, a: Operand, b: Operand) -> Result<(), String> {
+        match (a, b) {
+            (Operand::Register(a), Operand::Register(b)) => {
+                self.instruction(0x8F00 | (a as u16) << 4)?;
+                self.instruction(0x8F05 | (b as u16) << 4)
+            }
+            (Operand::Register(a), Operand::Byte(b)) => {
+                self.instruction(0x6F00 | b as u16)?;
+                self.instruction(0x8F07 | (a as u16) << 4)
+            }
+            (Operand::Byte(a), Operand::Register(b)) => {
+                self.instruction(0x6F00 | a as u16)?;
+                self.instruction(0x8F05 | (b as u16) << 4)
+            }
+            (Operand::Byte(_), Operand::Byte(_)) => Err(self.error("can't compare two constants")),
+        }
+    }
+
+    fn define_label(&mut self, name: String, address: u16) -> Result<(), String> {
+        if self.register(&name).is_some() || lexer::parse_number(&name).is_some() {
+            return Err(self.error(&format!("'{}' can't be used as a label name", name)));
+        }
+        if self.labels.contains_key(&name) {
+            return Err(self.error(&format!("label '{}' is already defined", name)));
+        }
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+
+        let name = self.expect("a macro name")?.text;
+
+        let mut arguments: Vec<String> = Vec::new();
+        loop {
+            let token = self.expect("'{'")?;
+            if token.text == "{" {
+                break;
+            }
+            arguments.push(token.text);
+        }
+
+        let mut body: Vec<Token> = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.expect("'}'")?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name, Macro {
+            arguments,
+            body,
+            calls: 0,
+        });
+
+        Ok(())
+
+    }
+
+    fn expand(&mut self, token: &Token) -> Result<(), String> {
+
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(&format!("too many macro expansions while expanding '{}'", token.text)));
+        }
+
+        let arguments = self.macros[&token.text].arguments.clone();
+        let mut values: HashMap<String, String> = HashMap::new();
+        for argument in arguments {
+            let value = self.expect("a macro argument")?.text;
+            values.insert(argument, value);
+        }
+
+        let definition = self.macros.get_mut(&token.text).unwrap();
+        let calls = definition.calls;
+        definition.calls += 1;
+
+        // Expanded code is attributed to the line that used the macro
+        for body_token in definition.body.iter().rev() {
+            let text = match values.get(&body_token.text) {
+                Some(value) => value.clone(),
+                None if body_token.text == "CALLS" => calls.to_string(),
+                None => body_token.text.clone(),
+            };
+            self.tokens.push_front(Token {
+                text,
+                line: token.line,
+            });
+        }
+
+        Ok(())
+
+    }
+
+    // Value of a ':calc { ... }' style expression
+    fn calc(&mut self) -> Result<f64, String> {
+
+        let mut tokens: Vec<String> = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.expect("'}'")?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token.text);
+        }
+
+        let mut position = 0;
+        let value = self.expression(&tokens, &mut position)?;
+        if position < tokens.len() {
+            return Err(self.error(&format!("unexpected '{}' in expression", tokens[position])));
+        }
+        Ok(value)
+
+    }
+
+    // Octo evaluates expressions right to left without precedence, use parentheses to group
+    fn expression(&self, tokens: &[String], position: &mut usize) -> Result<f64, String> {
+
+        let left = self.term(tokens, position)?;
+
+        match tokens.get(*position) {
+            Some(operator) if BINARY.contains(&operator.as_str()) => {
+                *position += 1;
+                let right = self.expression(tokens, position)?;
+                Ok(binary(operator, left, right))
+            }
+            _ => Ok(left),
+        }
+
+    }
+
+    fn term(&self, tokens: &[String], position: &mut usize) -> Result<f64, String> {
+
+        let token = match tokens.get(*position) {
+            Some(token) => token.as_str(),
+            None => return Err(self.error("unexpected end of expression")),
+        };
+        *position += 1;
+
+        match token {
+            "(" => {
+                let value = self.expression(tokens, position)?;
+                match tokens.get(*position) {
+                    Some(token) if token == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error("expected ')' in expression")),
+                }
+            }
+            "@" => {
+                let address = self.term(tokens, position)? as i64;
+                let index = address - START as i64;
+                if index < 0 || index as usize >= self.rom.len() {
+                    return Ok(0.0);
+                }
+                Ok(self.rom[index as usize] as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ if UNARY.contains(&token) && lexer::parse_number(token).is_none() => {
+                let value = self.term(tokens, position)?;
+                Ok(unary(token, value))
+            }
+            _ => match self.resolve(token) {
+                Some(value) => Ok(value),
+                None => Err(self.error(&format!("undefined name '{}' in expression", token))),
+            },
+        }
+
+    }
+
+    // Number literal, constant or already defined label
+    fn resolve(&self, text: &str) -> Option<f64> {
+        if let Some(value) = lexer::parse_number(text) {
+            return Some(value as f64);
+        }
+        if let Some(value) = self.constants.get(text) {
+            return Some(*value);
+        }
+        self.labels.get(text).map(|&address| address as f64)
+    }
+
+    // Like resolve, but also evaluates '{ ... }' expressions. None means a name that isn't
+    // defined yet, which is fine for addresses.
+    fn lookup(&mut self, token: &Token) -> Result<Option<i64>, String> {
+        if token.text == "{" {
+            return Ok(Some(self.calc()?.floor() as i64));
+        }
+        if self.register(&token.text).is_some() {
+            return Err(self.error(&format!("expected a value, got register '{}'", token.text)));
+        }
+        Ok(self.resolve(&token.text).map(|value| value.floor() as i64))
+    }
+
+    fn known(&mut self, token: &Token) -> Result<i64, String> {
+        match self.lookup(token)? {
+            Some(value) => Ok(value),
+            None => Err(self.error(&format!("undefined name '{}'", token.text))),
+        }
+    }
+
+    fn value(&mut self) -> Result<f64, String> {
+        let token = self.expect("a value")?;
+        if token.text == "{" {
+            return self.calc();
+        }
+        match self.resolve(&token.text) {
+            Some(value) => Ok(value),
+            None => Err(self.error(&format!("undefined name '{}'", token.text))),
+        }
+    }
+
+    fn byte(&mut self, token: &Token) -> Result<u8, String> {
+        let value = self.known(token)?;
+        self.check_byte(value)
+    }
+
+    fn check_byte(&self, value: i64) -> Result<u8, String> {
+        if !(-128..=255).contains(&value) {
+            return Err(self.error(&format!("{} doesn't fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u16, String> {
+        let token = self.expect("a value from 0 to 15")?;
+        let value = self.known(&token)?;
+        if !(0..=15).contains(&value) {
+            return Err(self.error(&format!("{} doesn't fit in a nibble", value)));
+        }
+        Ok(value as u16)
+    }
+
+    fn register(&self, text: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(text) {
+            return Some(register);
+        }
+        let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn expect_register(&mut self) -> Result<u8, String> {
+        let token = self.expect("a register")?;
+        match self.register(&token.text) {
+            Some(register) => Ok(register),
+            None => Err(self.error(&format!("expected a register, got '{}'", token.text))),
+        }
+    }
+
+    fn address_instruction(&mut self, base: u16, token: &Token) -> Result<(), String> {
+        match self.lookup(token)? {
+            Some(address) if (0..=0xFFF).contains(&address) => self.instruction(base | address as u16),
+            Some(address) => Err(self.error(&format!("address {:X} doesn't fit in 12 bits", address))),
+            None => {
+                self.reference(token, Patch::Address);
+                self.instruction(base)
+            }
+        }
+    }
+
+    fn word(&mut self, token: &Token) -> Result<(), String> {
+        let value = match self.lookup(token)? {
+            Some(value) if (0..=0xFFFF).contains(&value) => value as u16,
+            Some(value) => return Err(self.error(&format!("{:X} doesn't fit in 16 bits", value))),
+            None => {
+                self.reference(token, Patch::Long);
+                0
+            }
+        };
+        self.write((value >> 8) as u8)?;
+        self.write(value as u8)
+    }
+
+    fn reference(&mut self, token: &Token, patch: Patch) {
+        self.references.push(Reference {
+            address: self.here,
+            name: token.text.clone(),
+            patch,
+            line: token.line,
+        });
+    }
+
+    fn patch(&mut self, reference: &Reference, value: i64) -> Result<(), String> {
+
+        let index = (reference.address - START) as usize;
+        let value = value as u16;
+
+        match reference.patch {
+            Patch::Address => {
+                if value > 0xFFF {
+                    return Err(format!("{}:{}: address {:X} doesn't fit in 12 bits", self.name, reference.line, value));
+                }
+                self.rom[index] |= (value >> 8) as u8;
+                self.rom[index + 1] = value as u8;
+            }
+            Patch::Long => {
+                self.rom[index] = (value >> 8) as u8;
+                self.rom[index + 1] = value as u8;
+            }
+            Patch::UnpackHigh(prefix) => self.rom[index + 1] = (prefix | (value >> 8)) as u8,
+            Patch::UnpackLow => self.rom[index + 1] = value as u8,
+        }
+
+        Ok(())
+
+    }
+
+    fn patch_jump(&mut self, jump: u16, target: u16) {
+        let index = (jump - START) as usize;
+        self.rom[index] = 0x10 | (target >> 8) as u8;
+        self.rom[index + 1] = target as u8;
+    }
+
+    fn instruction(&mut self, op: u16) -> Result<(), String> {
+        self.lines.push((self.here, self.statement_line));
+        self.write((op >> 8) as u8)?;
+        self.write(op as u8)
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), String> {
+        if self.here >= END {
+            return Err(self.error("program doesn't fit in memory"));
+        }
+        let index = (self.here - START) as usize;
+        if index >= self.rom.len() {
+            self.rom.resize(index + 1, 0);
+        }
+        self.rom[index] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.pop_front()?;
+        self.line = token.line;
+        Some(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<Token, String> {
+        match self.next() {
+            Some(token) => Ok(token),
+            None => Err(self.error(&format!("expected {} but reached the end of the file", expected))),
+        }
+    }
+
+    fn expect_text(&mut self, text: &str) -> Result<(), String> {
+        let token = self.expect(&format!("'{}'", text))?;
+        if token.text != text {
+            return Err(self.error(&format!("expected '{}', got '{}'", text, token.text)));
+        }
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{}:{}: {}", self.name, self.line, message)
+    }
+
+}
+
+fn unary(operator: &str, value: f64) -> f64 {
+    match operator {
+        "-" => -value,
+        "~" => !(value as i64) as f64,
+        "!" => (value == 0.0) as i64 as f64,
+        "sin" => value.sin(),
+        "cos" => value.cos(),
+        "tan" => value.tan(),
+        "exp" => value.exp(),
+        "log" => value.ln(),
+        "abs" => value.abs(),
+        "sqrt" => value.sqrt(),
+        "sign" => if value == 0.0 { 0.0 } else { value.signum() },
+        "ceil" => value.ceil(),
+        _ => value.floor(),
+    }
+}
+
+fn binary(operator: &str, left: f64, right: f64) -> f64 {
+    let (a, b) = (left as i64, right as i64);
+    match operator {
+        "+" => left + right,
+        "-" => left - right,
+        "*" => left * right,
+        "/" => left / right,
+        "%" => left % right,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.wrapping_shl(b as u32) as f64,
+        ">>" => a.wrapping_shr(b as u32) as f64,
+        "pow" => left.powf(right),
+        "min" => left.min(right),
+        "max" => left.max(right),
+        "<" => (left < right) as i64 as f64,
+        "<=" => (left <= right) as i64 as f64,
+        ">" => (left > right) as i64 as f64,
+        ">=" => (left >= right) as i64 as f64,
+        "==" => (left == right) as i64 as f64,
+        _ => (left != right) as i64 as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    // Instructions after the jump to main that every program starts with
+    fn compile(source: &str) -> Vec<u16> {
+        let program = crate::octo::compile(source, "test.8o").unwrap();
+        program.rom[2..].chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+    }
+
+    fn error(source: &str) -> String {
+        crate::octo::compile(source, "test.8o").err().unwrap()
+    }
+
+    #[test]
+    fn labels_and_forward_references() {
+        let source = "
+            : main
+                jump later
+                sub
+            : later
+                v0 := 1
+            : sub
+                return
+        ";
+        let program = crate::octo::compile(source, "test.8o").unwrap();
+        assert_eq!(&program.rom[..2], &[0x12, 0x02]);
+        assert_eq!(compile(source), [0x1206, 0x2208, 0x6001, 0x00EE]);
+        assert_eq!(program.labels, [(String::from("main"), 0x202), (String::from("later"), 0x206), (String::from("sub"), 0x208)]);
+    }
+
+    #[test]
+    fn undefined_and_duplicate_labels() {
+        assert_eq!(error(": main jump nowhere"), "test.8o:1: undefined name 'nowhere'");
+        assert_eq!(error(": main\n: main"), "test.8o:2: label 'main' is already defined");
+        assert_eq!(error("v0 := 1"), "test.8o: program is missing a 'main' label");
+    }
+
+    #[test]
+    fn calc_evaluates_right_to_left() {
+        let source = "
+            :calc a { 2 * 3 + 1 }
+            :calc b { ( 2 * 3 ) + 1 }
+            :calc c { 10 - 4 - 1 }
+            : main
+                v0 := a
+                v1 := b
+                v2 := c
+        ";
+        assert_eq!(compile(source), [0x6008, 0x6107, 0x6207]);
+    }
+
+    #[test]
+    fn if_then() {
+        assert_eq!(compile(": main if v1 == 5 then v2 := 1"), [0x4105, 0x6201]);
+        assert_eq!(compile(": main if v1 != v3 then v2 := 1"), [0x5130, 0x6201]);
+    }
+
+    #[test]
+    fn if_begin_else_end() {
+        let source = "
+            : main
+                if v0 == 1 begin
+                    v1 := 2
+                else
+                    v1 := 3
+                end
+                v2 := 4
+        ";
+        assert_eq!(compile(source), [0x3001, 0x120A, 0x6102, 0x120C, 0x6103, 0x6204]);
+        assert_eq!(compile(": main if v0 == 1 begin v1 := 2 end"), [0x3001, 0x1208, 0x6102]);
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = "
+            : main
+                loop
+                    v0 += 1
+                    while v0 != 10
+                    v1 += 1
+                again
+                v2 := 0
+        ";
+        assert_eq!(compile(source), [0x7001, 0x400A, 0x120C, 0x7101, 0x1202, 0x6200]);
+    }
+
+    #[test]
+    fn unbalanced_blocks() {
+        assert_eq!(error(": main end"), "test.8o:1: 'end' without 'if ... begin'");
+        assert_eq!(error(": main while v0 == 1"), "test.8o:1: 'while' outside of a loop");
+        assert_eq!(error(": main\nloop\nv0 += 1"), "test.8o:2: 'loop' is never closed");
+    }
+
+    #[test]
+    fn ordered_comparisons_go_through_vf() {
+        assert_eq!(compile(": main if v1 < v2 then v3 := 1"), [0x8F10, 0x8F25, 0x4F00, 0x6301]);
+        assert_eq!(compile(": main if v1 >= v2 then v3 := 1"), [0x8F10, 0x8F25, 0x3F00, 0x6301]);
+        assert_eq!(compile(": main if v1 > 5 then v3 := 1"), [0x6F05, 0x8F15, 0x4F00, 0x6301]);
+        assert_eq!(compile(": main if v1 <= 5 then v3 := 1"), [0x6F05, 0x8F15, 0x3F00, 0x6301]);
+    }
+
+    #[test]
+    fn key_conditions() {
+        assert_eq!(compile(": main if v4 key then v3 := 1"), [0xE4A1, 0x6301]);
+        assert_eq!(compile(": main if v4 -key then v3 := 1"), [0xE49E, 0x6301]);
+        // Negated by begin, the jump past the block runs while the key is up
+        assert_eq!(compile(": main if v4 key begin v3 := 1 end"), [0xE49E, 0x1208, 0x6301]);
+    }
+
+}
@@ -0,0 +1,49 @@
+#[derive(Clone)]
+pub struct Token {
+    pub text: String,
+    pub line: u32,
+}
+
+// Octo tokens are separated by whitespace, '#' starts a comment
+pub fn tokenize(source: &str) -> Vec<Token> {
+
+    let mut tokens: Vec<Token> = Vec::new();
+
+    for (number, line) in source.lines().enumerate() {
+
+        let line = match line.split_once('#') {
+            Some((line, _)) => line,
+            None => line,
+        };
+
+        for text in line.split_whitespace() {
+            tokens.push(Token {
+                text: text.to_string(),
+                line: number as u32 + 1,
+            });
+        }
+
+    }
+
+    tokens
+
+}
+
+pub fn parse_number(text: &str) -> Option<i64> {
+
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+
+}
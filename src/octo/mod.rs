@@ -0,0 +1,51 @@
+mod lexer;
+mod compiler;
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+pub struct Program {
+    pub rom: Vec<u8>,
+    // Every label with its address, sorted by address
+    pub labels: Vec<(String, u16)>,
+    // Address of each instruction and the source line it was compiled from
+    pub lines: Vec<(u16, u32)>,
+}
+
+impl Program {
+
+    // "ADDR name" per label
+    pub fn write_symbols(&self, path: &Path) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        for (name, address) in &self.labels {
+            writeln!(out, "{:03X} {}", address, name)?;
+        }
+        out.flush()
+    }
+
+    // "ADDR FILE:LINE" per instruction, the format read back by the debugger
+    pub fn write_lines(&self, path: &Path, source: &Path) -> std::io::Result<()> {
+        let source = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+        let mut out = BufWriter::new(File::create(path)?);
+        for (address, line) in &self.lines {
+            writeln!(out, "{:03X} {}:{}", address, source.display(), line)?;
+        }
+        out.flush()
+    }
+
+}
+
+pub fn is_source(path: &str) -> bool {
+    path.ends_with(".8o")
+}
+
+pub fn compile_file(path: &Path) -> Result<Program, String> {
+    let source = fs::read_to_string(path).map_err(|error| format!("Failed to read {}: {}", path.display(), error))?;
+    compile(&source, &path.display().to_string())
+}
+
+pub fn compile(source: &str, name: &str) -> Result<Program, String> {
+    compiler::Compiler::new(lexer::tokenize(source), name).compile()
+}
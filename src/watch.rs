@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// Frames between checks of the modification time, a quarter of a second
+const INTERVAL: u32 = 15;
+
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    countdown: u32,
+}
+
+impl Watcher {
+
+    pub fn new(path: &str) -> Watcher {
+        Watcher {
+            path: PathBuf::from(path),
+            modified: modified(Path::new(path)),
+            countdown: INTERVAL,
+        }
+    }
+
+    // Called once per frame, true once for every change to the file
+    pub fn changed(&mut self) -> bool {
+
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return false;
+        }
+        self.countdown = INTERVAL;
+
+        // Editors often delete and recreate the file on save, wait until it's back
+        let modified = modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+
+        self.modified = modified;
+        true
+
+    }
+
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}